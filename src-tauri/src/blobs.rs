@@ -0,0 +1,57 @@
+use sha2::{Digest, Sha256};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// Content-addressed files for attachments and raw messages.
+///
+/// Blobs are named by the SHA-256 of their content, so identical content is
+/// stored once. Reference counting is left to the caller: the `email` table
+/// knows which blobs are still in use.
+pub struct BlobStore {
+    root: PathBuf,
+}
+
+impl BlobStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self { root: root.as_ref().to_path_buf() }
+    }
+
+    pub fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn path(&self, hash: &str) -> std::io::Result<PathBuf> {
+        if hash.len() < 3 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(std::io::Error::new(ErrorKind::InvalidInput, format!("invalid blob hash {:?}", hash)));
+        }
+        Ok(self.root.join(&hash[..2]).join(&hash[2..]))
+    }
+
+    /// Store `data`, returning its hash. Storing existing content is a no-op.
+    pub async fn put(&self, data: &[u8]) -> std::io::Result<String> {
+        let hash = Self::hash(data);
+        let path = self.path(&hash)?;
+        if tokio::fs::try_exists(&path).await? {
+            return Ok(hash);
+        }
+
+        let dir = path.parent().expect("blob paths have a parent");
+        tokio::fs::create_dir_all(dir).await?;
+        // Write aside then rename, so a crash never leaves a truncated blob.
+        let partial = dir.join(format!("{}.{}.tmp", &hash[2..], uuid::Uuid::new_v4()));
+        tokio::fs::write(&partial, data).await?;
+        tokio::fs::rename(&partial, &path).await?;
+        Ok(hash)
+    }
+
+    pub async fn get(&self, hash: &str) -> std::io::Result<Vec<u8>> {
+        tokio::fs::read(self.path(hash)?).await
+    }
+
+    pub async fn remove(&self, hash: &str) -> std::io::Result<()> {
+        match tokio::fs::remove_file(self.path(hash)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
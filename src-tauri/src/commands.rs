@@ -1,7 +1,15 @@
-use crate::models::{Email, EmailAccount, NewEmail, ConnectionTestResult, EmailAddress};
-use crate::email::EmailClient;
+use crate::models::{Attachment, AuthMethod, Email, EmailAccount, EmailListQuery, EmailPage, Identity, NewEmail, ConnectionTestResult, MailFolder, PendingAction, PendingOperation, Thread};
+use crate::compose::{self, ReplyMode};
+use crate::database::Database;
+use crate::email::{self, EmailClient, EmailError};
+use crate::oauth;
+use crate::search::{SearchHit, SearchQuery};
+use crate::sync;
+use crate::threading;
 use crate::AppState;
-use tauri::State;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager, State};
+use tokio::sync::Mutex;
 
 type CommandResult<T> = Result<T, String>;
 
@@ -10,36 +18,188 @@ fn map_err<E: std::fmt::Display>(e: E) -> String {
 }
 
 
-async fn resolve_user_ids(db: &crate::database::Database, addrs: &[EmailAddress]) -> CommandResult<Option<Vec<String>>> {
-    let mut ids = Vec::new();
-    for addr in addrs {
-        if let Some(id) = db.get_or_create_user(&addr.email, Some(&addr.name)).await.map_err(map_err)? {
-            ids.push(id);
+/// One page of a folder for the message list, without bodies.
+#[tauri::command]
+pub async fn list_emails(
+    state: State<'_, AppState>,
+    query: EmailListQuery,
+) -> CommandResult<EmailPage> {
+    let db = state.db.lock().await;
+    db.list_emails(&query).await.map_err(map_err)
+}
+
+#[tauri::command]
+pub async fn get_threads(
+    state: State<'_, AppState>,
+    folder: String,
+) -> CommandResult<Vec<Thread>> {
+    let db = state.db.lock().await;
+    let emails = db.get_thread_emails(&folder).await.map_err(map_err)?;
+    Ok(threading::summarize(emails))
+}
+
+/// Full-text search with Gmail-style operators (`from:`, `is:unread`, `in:sent`...).
+///
+/// With `server`, the query also runs as IMAP `SEARCH` on every account and
+/// hits missing from the cache are appended after the ranked local ones.
+#[tauri::command]
+pub async fn search_emails(
+    state: State<'_, AppState>,
+    query: String,
+    limit: Option<usize>,
+    server: Option<bool>,
+) -> CommandResult<Vec<SearchHit>> {
+    let query = SearchQuery::parse(&query);
+    let limit = limit.unwrap_or(100);
+    let mut hits = {
+        let db = state.db.lock().await;
+        db.search_emails(&query, limit).await.map_err(map_err)?
+    };
+    if !server.unwrap_or(false) {
+        return Ok(hits);
+    }
+
+    // Also ask each server, for mail older than the local cache.
+    let accounts = state.db.lock().await.get_all_accounts().await.map_err(map_err)?;
+    let mut remote = Vec::new();
+    for account in accounts {
+        match sync::search_server(&state.db, &account.id, &query, limit).await {
+            Ok(emails) => remote.extend(emails),
+            Err(e) => log::warn!("Server search failed for {}: {}", account.email, e),
         }
     }
-    if ids.is_empty() {
-        Ok(None)
-    } else {
-        Ok(Some(ids))
+    remote.retain(|email| !hits.iter().any(|hit| hit.email.id == email.id));
+    remote.sort_by(|a, b| b.date.cmp(&a.date));
+    hits.extend(remote.into_iter().map(|email| SearchHit { email, score: 0.0, snippet: None }));
+    hits.truncate(limit);
+    Ok(hits)
+}
+
+/// Download the body of an email synced without it, and its attachments if asked.
+#[tauri::command]
+pub async fn load_email_body(
+    state: State<'_, AppState>,
+    id: String,
+    attachments: Option<bool>,
+) -> CommandResult<Email> {
+    sync::load_body(&state.db, &id, attachments.unwrap_or(false))
+        .await
+        .map_err(map_err)
+}
+
+/// Return an attachment with its content.
+#[tauri::command]
+pub async fn get_attachment(
+    state: State<'_, AppState>,
+    email_id: String,
+    attachment_id: String,
+) -> CommandResult<Attachment> {
+    sync::load_attachment(&state.db, &email_id, &attachment_id)
+        .await
+        .map_err(map_err)?
+        .ok_or_else(|| format!("Attachment {} not found", attachment_id))
+}
+
+/// Save an attachment to the downloads directory and return its path.
+///
+/// The webview never picks the path: the file name comes from the
+/// attachment, stripped of any directory, and never replaces a file.
+#[tauri::command]
+pub async fn save_attachment(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    email_id: String,
+    attachment_id: String,
+) -> CommandResult<String> {
+    let attachment = get_attachment(state, email_id, attachment_id.clone()).await?;
+    let data = attachment
+        .data
+        .ok_or_else(|| format!("Content of attachment {} is not available", attachment_id))?;
+
+    let dir = app.path().download_dir().map_err(map_err)?;
+    tokio::fs::create_dir_all(&dir).await.map_err(map_err)?;
+    let mut n = 0;
+    loop {
+        let path = download_path(&dir, &attachment.filename, n);
+        match tokio::fs::OpenOptions::new().write(true).create_new(true).open(&path).await {
+            Ok(mut file) => {
+                use tokio::io::AsyncWriteExt;
+                file.write_all(&data).await.map_err(map_err)?;
+                return Ok(path.to_string_lossy().into_owned());
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && n < 100 => n += 1,
+            Err(e) => return Err(map_err(e)),
+        }
+    }
+}
+
+/// `dir/filename`, as `name (n).ext` for `n > 0`, with `filename` reduced
+/// to a plain file name.
+fn download_path(dir: &Path, filename: &str, n: u32) -> PathBuf {
+    let name: String = filename
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    let name = match name.trim_matches(|c: char| c == '.' || c.is_whitespace()) {
+        "" => "attachment",
+        name => name,
+    };
+    if n == 0 {
+        return dir.join(name);
+    }
+    match name.rsplit_once('.') {
+        Some((stem, ext)) => dir.join(format!("{} ({}).{}", stem, n, ext)),
+        None => dir.join(format!("{} ({})", name, n)),
     }
 }
 
+/// A reply to `email_id`, prefilled with recipients, subject and quoted body.
 #[tauri::command]
-pub async fn fetch_emails(
+pub async fn compose_reply(
     state: State<'_, AppState>,
-    folder: String,
-) -> CommandResult<Vec<Email>> {
+    email_id: String,
+    mode: Option<ReplyMode>,
+) -> CommandResult<NewEmail> {
+    let original = sync::load_body(&state.db, &email_id, false).await.map_err(map_err)?;
     let db = state.db.lock().await;
-    
-    // First try to get from database
-    let emails = db.get_emails_by_folder(&folder).await.map_err(map_err)?;
-    
-    if !emails.is_empty() {
-        return Ok(emails);
+    let accounts = db.get_all_accounts().await.map_err(map_err)?;
+    let account = reply_account(&accounts, &original)?;
+    let own = compose::own_addresses(&accounts);
+    Ok(compose::reply(&original, account, &own, mode.unwrap_or(ReplyMode::Reply)))
+}
+
+/// `email_id` forwarded inline, with the content of its attachments.
+#[tauri::command]
+pub async fn compose_forward(
+    state: State<'_, AppState>,
+    email_id: String,
+) -> CommandResult<NewEmail> {
+    let original = sync::load_body(&state.db, &email_id, true).await.map_err(map_err)?;
+    let db = state.db.lock().await;
+    let accounts = db.get_all_accounts().await.map_err(map_err)?;
+    let account = reply_account(&accounts, &original)?;
+
+    let mut email = compose::forward(&original, account);
+    for attachment in email.attachments.iter_mut().flatten() {
+        attachment.data = db
+            .get_attachment(&original.id, &attachment.id)
+            .await
+            .map_err(map_err)?
+            .and_then(|stored| stored.data);
     }
-    
-    // If no emails in database, return empty (sync will populate)
-    Ok(vec![])
+    Ok(email)
+}
+
+/// The account that received `email`, or the first one for local messages.
+fn reply_account<'a>(accounts: &'a [EmailAccount], email: &Email) -> CommandResult<&'a EmailAccount> {
+    accounts
+        .iter()
+        .find(|account| email.account_id.as_ref() == Some(&account.id))
+        .or_else(|| accounts.first())
+        .ok_or_else(|| "No email account configured".to_string())
 }
 
 #[tauri::command]
@@ -48,43 +208,74 @@ pub async fn sync_emails(
     account_id: String,
     folder: String,
 ) -> CommandResult<Vec<Email>> {
-    let db = state.db.lock().await;
-    
-    // Get account
-    let account = db.get_account(&account_id).await.map_err(map_err)?
-        .ok_or_else(|| "Account not found".to_string())?;
-    
-    // Create email client
-    let client = EmailClient::new(account);
-    
-    // Fetch emails from server
-    let emails = client.fetch_emails(&folder, 50).await.map_err(map_err)?;
-    
-    // Store in database with user links
-    let mut enriched = Vec::with_capacity(emails.len());
-    for mut email in emails {
-        email.from_user_id = db
-            .get_or_create_user(&email.from.email, Some(&email.from.name))
-            .await
-            .map_err(map_err)?;
-        email.to_user_ids = resolve_user_ids(&db, &email.to).await?;
-        email.cc_user_ids = match &email.cc {
-            Some(cc) => resolve_user_ids(&db, cc).await?,
-            None => None,
-        };
-        email.bcc_user_ids = match &email.bcc {
-            Some(bcc) => resolve_user_ids(&db, bcc).await?,
-            None => None,
-        };
-
-        if db.get_email(&email.id).await.map_err(map_err)?.is_none() {
-            db.create_email(&email).await.map_err(map_err)?;
-        }
+    sync::sync_folder(&state.db, &account_id, &folder)
+        .await
+        .map_err(map_err)
+}
 
-        enriched.push(email);
+#[tauri::command]
+pub async fn list_folders(
+    state: State<'_, AppState>,
+    account_id: String,
+    refresh: Option<bool>,
+) -> CommandResult<Vec<MailFolder>> {
+    if !refresh.unwrap_or(false) {
+        let folders = state.db.lock().await.get_folders(&account_id).await.map_err(map_err)?;
+        if !folders.is_empty() {
+            return Ok(folders);
+        }
     }
-    
-    Ok(enriched)
+
+    sync::refresh_folders(&state.db, &account_id)
+        .await
+        .map_err(map_err)
+}
+
+#[tauri::command]
+pub async fn create_folder(
+    state: State<'_, AppState>,
+    account_id: String,
+    name: String,
+    parent: Option<String>,
+) -> CommandResult<Vec<MailFolder>> {
+    sync::create_folder(&state.db, &account_id, parent.as_deref(), &name)
+        .await
+        .map_err(map_err)
+}
+
+#[tauri::command]
+pub async fn rename_folder(
+    state: State<'_, AppState>,
+    account_id: String,
+    path: String,
+    new_name: String,
+) -> CommandResult<Vec<MailFolder>> {
+    sync::rename_folder(&state.db, &account_id, &path, &new_name)
+        .await
+        .map_err(map_err)
+}
+
+#[tauri::command]
+pub async fn delete_folder(
+    state: State<'_, AppState>,
+    account_id: String,
+    path: String,
+) -> CommandResult<Vec<MailFolder>> {
+    sync::delete_folder(&state.db, &account_id, &path)
+        .await
+        .map_err(map_err)
+}
+
+#[tauri::command]
+pub async fn subscribe_folder(
+    state: State<'_, AppState>,
+    account_id: String,
+    path: String,
+    subscribed: bool,
+) -> CommandResult<Vec<MailFolder>> {
+    sync::set_subscribed(&state.db, &account_id, &path, subscribed)
+        .await
+        .map_err(map_err)
 }
 
 #[tauri::command]
@@ -92,69 +283,258 @@ pub async fn send_email(
     state: State<'_, AppState>,
     email: NewEmail,
 ) -> CommandResult<Email> {
-    let db = state.db.lock().await;
-    
-    // Get first account (or could be specified)
-    let accounts = db.get_all_accounts().await.map_err(map_err)?;
-    let account = accounts.first()
-        .ok_or_else(|| "No email account configured".to_string())?;
-    
-    // Create email client
-    let client = EmailClient::new(account.clone());
+    let sent = deliver(&state.db, email).await?;
+    state.outbox.notify_one();
+    Ok(sent)
+}
+
+/// Send `email`, or queue it when the server is unreachable, and record it.
+///
+/// The database is not locked while talking to the server, which can take
+/// up to the SMTP timeout when offline.
+async fn deliver(db: &Mutex<Database>, mut email: NewEmail) -> CommandResult<Email> {
+    let (account, client) = {
+        let db = db.lock().await;
+        let accounts = db.get_all_accounts().await.map_err(map_err)?;
+        let (account, identity) = sender_identity(&accounts, &email)?;
+        compose::sign(&mut email, &identity);
+        if email.from.name.trim().is_empty() {
+            email.from.name = identity.name;
+        }
+        (account.clone(), EmailClient::new(account.clone(), db.secrets()))
+    };
+    email.account_id = Some(account.id.clone());
     
-    // Send email
-    client.send_email(&email).await.map_err(map_err)?;
+    // The Message-ID is fixed now so that a queued send and the local copy agree.
+    email.message_id.get_or_insert_with(|| email::new_message_id(&email.from.email));
+
+    // Send email, or queue it when the server is unreachable
+    let sent = match client.send_email(&email).await {
+        Ok(message) => Some(message),
+        Err(EmailError::ConnectionError(e)) => {
+            log::info!("SMTP server unreachable, queueing message: {}", e);
+            None
+        }
+        Err(e) => return Err(map_err(e)),
+    };
+    let outgoing = sent.is_none().then(|| email.clone());
     
     // Create sent email record
-    let mut sent_email = Email {
-        id: uuid::Uuid::new_v4().to_string(),
-        from: email.from,
-        to: email.to,
-        cc: email.cc,
-        bcc: email.bcc,
-        subject: email.subject,
-        body: email.body,
-        html_body: None,
-        date: chrono::Utc::now().to_rfc3339(),
-        read: true,
-        starred: false,
-        folder: "sent".to_string(),
-        attachments: email.attachments,
-        account_id: Some(account.id.clone()),
-        message_id: None,
-        from_user_id: None,
-        to_user_ids: None,
-        cc_user_ids: None,
-        bcc_user_ids: None,
-    };
+    let mut sent_email = Email::from_new(email, if sent.is_some() { "sent" } else { "outbox" });
+    if sent_email.body.trim().is_empty() {
+        if let Some(html) = &sent_email.html_body {
+            sent_email.body = compose::html_to_text(html);
+        }
+    }
+    sent_email.raw = sent.as_ref().map(|message| message.formatted());
 
-    sent_email.from_user_id = db
-        .get_or_create_user(&sent_email.from.email, Some(&sent_email.from.name))
-        .await
-        .map_err(map_err)?;
-    sent_email.to_user_ids = resolve_user_ids(&db, &sent_email.to).await?;
-    sent_email.cc_user_ids = match &sent_email.cc {
-        Some(cc) => resolve_user_ids(&db, cc).await?,
-        None => None,
-    };
-    sent_email.bcc_user_ids = match &sent_email.bcc {
-        Some(bcc) => resolve_user_ids(&db, bcc).await?,
-        None => None,
-    };
+    let db = db.lock().await;
+    db.link_users(&mut sent_email).await.map_err(map_err)?;
     
     // Store in database
-    db.create_email(&sent_email).await.map_err(map_err)?;
+    let stored = db.create_email(&sent_email).await.map_err(map_err)?;
+    threading::update_threads(&db, std::slice::from_ref(&stored)).await.map_err(map_err)?;
+
+    if let Some(outgoing) = outgoing {
+        let mut op = PendingOperation::new(account.id.clone(), PendingAction::Send { email: Box::new(outgoing) });
+        op.email_id = Some(sent_email.id.clone());
+        db.enqueue_operation(&op).await.map_err(map_err)?;
+    } else {
+        sync::queue_sent_copy(&db, &client, &sent_email).await.map_err(map_err)?;
+    }
     
     Ok(sent_email)
 }
 
+/// Save a new draft and mirror it to the Drafts folder of its account.
+#[tauri::command]
+pub async fn save_draft(
+    state: State<'_, AppState>,
+    draft: NewEmail,
+) -> CommandResult<Email> {
+    let id = {
+        let db = state.db.lock().await;
+        store_draft(&db, draft).await?
+    };
+    upload_draft(&state, &id).await
+}
+
+/// Replace draft `id` with a new revision.
+///
+/// When the draft changes account it is stored anew, so the returned draft
+/// may have a different id.
+#[tauri::command]
+pub async fn update_draft(
+    state: State<'_, AppState>,
+    id: String,
+    draft: NewEmail,
+) -> CommandResult<Email> {
+    let id = {
+        let db = state.db.lock().await;
+        let previous = get_draft(&db, &id).await?;
+        let draft = draft_from(&db, draft).await?;
+
+        if draft.account_id != previous.account_id {
+            remove_draft(&db, &id).await?;
+            store_draft(&db, draft).await?
+        } else {
+            let mut revision = Email::from_new(draft, "drafts");
+            revision.id = id;
+            db.link_users(&mut revision).await.map_err(map_err)?;
+            db.replace_email(&revision).await.map_err(map_err)?.id
+        }
+    };
+    upload_draft(&state, &id).await
+}
+
+#[tauri::command]
+pub async fn discard_draft(
+    state: State<'_, AppState>,
+    id: String,
+) -> CommandResult<()> {
+    let db = state.db.lock().await;
+    get_draft(&db, &id).await?;
+    remove_draft(&db, &id).await?;
+    state.outbox.notify_one();
+    Ok(())
+}
+
+/// Send draft `id` and discard it.
+#[tauri::command]
+pub async fn send_draft(
+    state: State<'_, AppState>,
+    id: String,
+) -> CommandResult<Email> {
+    let email = {
+        let db = state.db.lock().await;
+        let mut email = get_draft(&db, &id).await?.to_new_email();
+        email.message_id = None;
+        for attachment in email.attachments.iter_mut().flatten() {
+            attachment.data = db
+                .get_attachment(&id, &attachment.id)
+                .await
+                .map_err(map_err)?
+                .and_then(|a| a.data);
+        }
+        email
+    };
+
+    let sent = deliver(&state.db, email).await?;
+    let db = state.db.lock().await;
+    remove_draft(&db, &id).await?;
+    state.outbox.notify_one();
+    Ok(sent)
+}
+
+async fn get_draft(db: &Database, id: &str) -> CommandResult<Email> {
+    match db.get_email(id).await.map_err(map_err)? {
+        Some(email) if email.folder == "drafts" => Ok(email),
+        _ => Err(format!("Draft {} not found", id)),
+    }
+}
+
+/// `draft` with its sending account resolved from the From address.
+async fn draft_from(db: &Database, mut draft: NewEmail) -> CommandResult<NewEmail> {
+    let accounts = db.get_all_accounts().await.map_err(map_err)?;
+    let (account, identity) = sender_identity(&accounts, &draft)?;
+    if draft.from.name.trim().is_empty() {
+        draft.from.name = identity.name;
+    }
+    draft.account_id = Some(account.id.clone());
+    Ok(draft)
+}
+
+async fn store_draft(db: &Database, draft: NewEmail) -> CommandResult<String> {
+    let mut email = Email::from_new(draft_from(db, draft).await?, "drafts");
+    db.link_users(&mut email).await.map_err(map_err)?;
+    db.create_email(&email).await.map_err(map_err)?;
+    Ok(email.id)
+}
+
+/// Delete draft `id` locally, and its server copy if it has one.
+async fn remove_draft(db: &Database, id: &str) -> CommandResult<()> {
+    db.delete_operations_for_email(id).await.map_err(map_err)?;
+    queue_for_email(db, id, PendingAction::Delete).await?;
+    db.delete_email(id).await.map_err(map_err)
+}
+
+/// Mirror draft `id` to the server, queueing the upload if it is unreachable.
+///
+/// The local draft is kept whatever happens, so it is returned even when the
+/// upload fails.
+async fn upload_draft(state: &State<'_, AppState>, id: &str) -> CommandResult<Email> {
+    match sync::upload_draft(&state.db, id).await {
+        Ok(draft) => return Ok(draft),
+        Err(sync::SyncError::Email(EmailError::ConnectionError(e))) => {
+            log::info!("IMAP server unreachable, queueing draft {}: {}", id, e);
+            let db = state.db.lock().await;
+            let draft = get_draft(&db, id).await?;
+            let pending = db.get_pending_operations().await.map_err(map_err)?;
+            let queued = pending.iter().any(|op| {
+                op.email_id.as_deref() == Some(id) && matches!(op.action, PendingAction::UploadDraft)
+            });
+            if !queued {
+                if let Some(account_id) = draft.account_id.clone() {
+                    let mut op = PendingOperation::new(account_id, PendingAction::UploadDraft);
+                    op.email_id = Some(id.to_string());
+                    db.enqueue_operation(&op).await.map_err(map_err)?;
+                    state.outbox.notify_one();
+                }
+            }
+        }
+        Err(e) => log::warn!("Could not upload draft {}: {}", id, e),
+    }
+
+    let db = state.db.lock().await;
+    get_draft(&db, id).await
+}
+
+/// The account `email` goes out through, and the identity its From matches.
+///
+/// With an `account_id` the From must belong to that account; otherwise the
+/// first account owning the address is used.
+fn sender_identity<'a>(accounts: &'a [EmailAccount], email: &NewEmail) -> CommandResult<(&'a EmailAccount, Identity)> {
+    if accounts.is_empty() {
+        return Err("No email account configured".to_string());
+    }
+    if let Some(id) = &email.account_id {
+        if !accounts.iter().any(|account| &account.id == id) {
+            return Err(format!("Account {} not found", id));
+        }
+    }
+
+    accounts
+        .iter()
+        .filter(|account| email.account_id.as_ref().is_none_or(|id| &account.id == id))
+        .find_map(|account| account.identity_for(&email.from.email).map(|identity| (account, identity)))
+        .ok_or_else(|| format!("{} is not a configured sender identity", email.from.email))
+}
+
+/// Queue `action` for the server copy of an email, if it was synced from IMAP.
+///
+/// Must run before the local change so the operation records where the
+/// message currently lives on the server.
+async fn queue_for_email(db: &Database, id: &str, action: PendingAction) -> CommandResult<()> {
+    let email = match db.get_email(id).await.map_err(map_err)? {
+        Some(email) => email,
+        None => return Ok(()),
+    };
+    let op = match email.imap_uid().and_then(|uid| PendingOperation::for_message(&email, uid, action)) {
+        Some(op) => op,
+        None => return Ok(()),
+    };
+    db.enqueue_operation(&op).await.map_err(map_err)
+}
+
 #[tauri::command]
 pub async fn mark_as_read(
     state: State<'_, AppState>,
     id: String,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+    queue_for_email(&db, &id, PendingAction::SetRead { read: true }).await?;
     db.mark_as_read(&id, true).await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
@@ -164,7 +544,9 @@ pub async fn mark_as_unread(
     id: String,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+    queue_for_email(&db, &id, PendingAction::SetRead { read: false }).await?;
     db.mark_as_read(&id, false).await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
@@ -175,7 +557,9 @@ pub async fn toggle_star(
     starred: bool,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+    queue_for_email(&db, &id, PendingAction::SetStarred { starred }).await?;
     db.toggle_star(&id, starred).await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
@@ -185,7 +569,9 @@ pub async fn delete_email(
     id: String,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+    queue_for_email(&db, &id, PendingAction::Delete).await?;
     db.delete_email(&id).await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
@@ -195,7 +581,9 @@ pub async fn move_to_trash(
     id: String,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+    queue_for_email(&db, &id, PendingAction::Move { target: "trash".to_string() }).await?;
     db.move_to_folder(&id, "trash").await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
@@ -206,12 +594,24 @@ pub async fn move_to_folder(
     folder: String,
 ) -> CommandResult<()> {
     let db = state.db.lock().await;
+
+    // Only folders that exist on the server can be moved to.
+    if let Some(account_id) = db.get_email(&id).await.map_err(map_err)?.and_then(|email| email.account_id) {
+        let folders = db.get_folders(&account_id).await.map_err(map_err)?;
+        if !folders.is_empty() && !folders.iter().any(|f| f.logical_name() == folder) {
+            return Err(format!("Folder not found: {}", folder));
+        }
+    }
+
+    queue_for_email(&db, &id, PendingAction::Move { target: folder.clone() }).await?;
     db.move_to_folder(&id, &folder).await.map_err(map_err)?;
+    state.outbox.notify_one();
     Ok(())
 }
 
 #[tauri::command]
 pub async fn save_account(
+    app: AppHandle,
     state: State<'_, AppState>,
     account: EmailAccount,
 ) -> CommandResult<EmailAccount> {
@@ -224,17 +624,20 @@ pub async fn save_account(
         .map_err(map_err)?;
 
     // Prefer id if it exists, otherwise upsert by email to avoid duplicates
-    if let Some(_) = db.get_account(&account.id).await.map_err(map_err)? {
-        return db.update_account(&account).await.map_err(map_err);
-    }
-
-    if let Some(existing) = db.get_account_by_email(&account.email).await.map_err(map_err)? {
+    let saved = if db.get_account(&account.id).await.map_err(map_err)?.is_some() {
+        db.update_account(&account).await.map_err(map_err)?
+    } else if let Some(existing) = db.get_account_by_email(&account.email).await.map_err(map_err)? {
         let mut updated = account.clone();
         updated.id = existing.id;
-        return db.update_account(&updated).await.map_err(map_err);
-    }
+        db.update_account(&updated).await.map_err(map_err)?
+    } else {
+        db.create_account(&account).await.map_err(map_err)?
+    };
 
-    db.create_account(&account).await.map_err(map_err)
+    // Pooled sessions may have been opened with the previous settings.
+    state.sessions.evict(&saved.id);
+    state.idle.start(app, state.db.clone(), saved.clone());
+    Ok(saved)
 }
 
 #[tauri::command]
@@ -242,11 +645,44 @@ pub async fn remove_account(
     state: State<'_, AppState>,
     id: String,
 ) -> CommandResult<()> {
+    state.idle.stop(&id);
+    state.sessions.evict(&id);
     let db = state.db.lock().await;
     db.delete_account(&id).await.map_err(map_err)?;
     Ok(())
 }
 
+/// Sign account `account_id` in with its OAuth2 provider in the browser,
+/// and keep the tokens it grants.
+#[tauri::command]
+pub async fn oauth2_authorize(
+    app: AppHandle,
+    state: State<'_, AppState>,
+    account_id: String,
+) -> CommandResult<()> {
+    let (account, secrets) = {
+        let db = state.db.lock().await;
+        let account = db
+            .get_account(&account_id)
+            .await
+            .map_err(map_err)?
+            .ok_or_else(|| format!("Account {} not found", account_id))?;
+        (account, db.secrets())
+    };
+    let AuthMethod::OAuth2(config) = &account.auth else {
+        return Err(format!("Account {} does not use OAuth2", account.email));
+    };
+
+    let tokens = oauth::authorize(config, &account.email, |url| open::that(url))
+        .await
+        .map_err(map_err)?;
+    oauth::store_tokens(secrets.as_ref(), &account.id, &tokens).map_err(map_err)?;
+
+    // The watcher may have given up without tokens.
+    state.idle.start(app, state.db.clone(), account);
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn get_accounts(
     state: State<'_, AppState>,
@@ -257,55 +693,79 @@ pub async fn get_accounts(
 
 #[tauri::command]
 pub async fn test_connection(
+    state: State<'_, AppState>,
     account: EmailAccount,
 ) -> CommandResult<ConnectionTestResult> {
-    let client = EmailClient::new(account);
+    let secrets = state.db.lock().await.secrets();
+    let client = EmailClient::new(account, secrets);
     
-    match client.test_connection().await {
-        Ok(_) => Ok(ConnectionTestResult {
-            success: true,
-            message: "Connexion réussie !".to_string(),
-        }),
-        Err(e) => Ok(ConnectionTestResult {
-            success: false,
-            message: format!("Échec de la connexion: {}", e),
-        }),
-    }
+    let (mut imap, mut smtp) = (None, None);
+    let outcome = match keep_pin(client.test_imap().await, &mut imap) {
+        Ok(()) => keep_pin(client.test_smtp().await, &mut smtp),
+        Err(e) => Err(e),
+    };
+    Ok(test_report(outcome, imap, smtp, "Connexion réussie !", "Échec de la connexion"))
 }
 
 #[tauri::command]
 pub async fn test_imap_connection(
+    state: State<'_, AppState>,
     account: EmailAccount,
 ) -> CommandResult<ConnectionTestResult> {
-    let client = EmailClient::new(account);
-
-    match client.test_imap().await {
-        Ok(_) => Ok(ConnectionTestResult {
-            success: true,
-            message: "Connexion IMAP réussie !".to_string(),
-        }),
-        Err(e) => Ok(ConnectionTestResult {
-            success: false,
-            message: format!("Échec IMAP: {}", e),
-        }),
-    }
+    let secrets = state.db.lock().await.secrets();
+    let client = EmailClient::new(account, secrets);
+
+    let mut imap = None;
+    let outcome = keep_pin(client.test_imap().await, &mut imap);
+    Ok(test_report(outcome, imap, None, "Connexion IMAP réussie !", "Échec IMAP"))
 }
 
 #[tauri::command]
 pub async fn test_smtp_connection(
+    state: State<'_, AppState>,
     account: EmailAccount,
 ) -> CommandResult<ConnectionTestResult> {
-    let client = EmailClient::new(account);
-
-    match client.test_smtp().await {
-        Ok(_) => Ok(ConnectionTestResult {
-            success: true,
-            message: "Connexion SMTP réussie !".to_string(),
-        }),
-        Err(e) => Ok(ConnectionTestResult {
-            success: false,
-            message: format!("Échec SMTP: {}", e),
-        }),
+    let secrets = state.db.lock().await.secrets();
+    let client = EmailClient::new(account, secrets);
+
+    let mut smtp = None;
+    let outcome = keep_pin(client.test_smtp().await, &mut smtp);
+    Ok(test_report(outcome, None, smtp, "Connexion SMTP réussie !", "Échec SMTP"))
+}
+
+/// Keep in `pin` the certificate a test saw: the one trusted on first use,
+/// or the new one when the pinned certificate changed.
+fn keep_pin(outcome: email::Result<Option<String>>, pin: &mut Option<String>) -> email::Result<()> {
+    match outcome {
+        Ok(fingerprint) => {
+            *pin = fingerprint;
+            Ok(())
+        }
+        Err(e) => {
+            if let EmailError::CertificateChanged { found, .. } = &e {
+                *pin = Some(found.clone());
+            }
+            Err(e)
+        }
+    }
+}
+
+fn test_report(
+    outcome: email::Result<()>,
+    imap_fingerprint: Option<String>,
+    smtp_fingerprint: Option<String>,
+    success: &str,
+    failure: &str,
+) -> ConnectionTestResult {
+    ConnectionTestResult {
+        success: outcome.is_ok(),
+        message: match &outcome {
+            Ok(()) => success.to_string(),
+            Err(e) => format!("{}: {}", failure, e),
+        },
+        imap_fingerprint,
+        smtp_fingerprint,
+        certificate_changed: matches!(outcome, Err(EmailError::CertificateChanged { .. })),
     }
 }
 
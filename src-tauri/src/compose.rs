@@ -0,0 +1,425 @@
+use crate::models::{Email, EmailAccount, EmailAddress, Identity, NewEmail};
+use crate::search;
+use crate::threading;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReplyMode {
+    Reply,
+    ReplyAll,
+}
+
+/// Every address of every account, lowercased, to keep ourselves out of replies.
+pub fn own_addresses(accounts: &[EmailAccount]) -> HashSet<String> {
+    accounts
+        .iter()
+        .flat_map(EmailAccount::all_identities)
+        .map(|identity| identity.email.trim().to_lowercase())
+        .collect()
+}
+
+/// A reply to `original`, sent from `account`, quoting its text body.
+pub fn reply(original: &Email, account: &EmailAccount, own: &HashSet<String>, mode: ReplyMode) -> NewEmail {
+    let identity = receiving_identity(original, account);
+    let is_own = |address: &EmailAddress| own.contains(&address.email.trim().to_lowercase());
+
+    // Replying to a message we sent goes back to its recipients.
+    let mut to = if is_own(&original.from) {
+        original.to.clone()
+    } else {
+        vec![original.from.clone()]
+    };
+    let mut cc = Vec::new();
+    if mode == ReplyMode::ReplyAll {
+        to.extend(original.to.iter().cloned());
+        cc.extend(original.cc.iter().flatten().cloned());
+    }
+    let mut seen = HashSet::new();
+    to.retain(|address| !is_own(address) && seen.insert(address.email.to_lowercase()));
+    cc.retain(|address| !is_own(address) && seen.insert(address.email.to_lowercase()));
+    if to.is_empty() && !is_own(&original.from) {
+        to.push(original.from.clone());
+    }
+
+    let mut references = original.references.clone().unwrap_or_default();
+    if let Some(id) = &original.message_id {
+        references.push(id.clone());
+    }
+
+    let attribution = format!(
+        "Le {}, {} a écrit :",
+        local_date(&original.date),
+        display_address(&original.from)
+    );
+    let quoted: Vec<String> = original
+        .body
+        .lines()
+        .map(|line| if line.is_empty() { ">".to_string() } else { format!("> {}", line) })
+        .collect();
+
+    NewEmail {
+        account_id: Some(account.id.clone()),
+        from: EmailAddress { name: identity.name.clone(), email: identity.email.clone() },
+        to,
+        cc: (!cc.is_empty()).then_some(cc),
+        bcc: None,
+        subject: format!("Re: {}", threading::strip_reply_prefixes(&original.subject)),
+        body: format!("\n\n{}\n{}", attribution, quoted.join("\n")),
+        html_body: None,
+        attachments: None,
+        in_reply_to: original.message_id.clone(),
+        references: (!references.is_empty()).then_some(references),
+        message_id: None,
+    }
+}
+
+/// `original` forwarded inline from `account`, with its attachments.
+///
+/// The attachments keep whatever content they were loaded with; the caller
+/// fills in `data` before sending.
+pub fn forward(original: &Email, account: &EmailAccount) -> NewEmail {
+    let identity = receiving_identity(original, account);
+
+    let mut header = vec![
+        format!("De: {}", display_address(&original.from)),
+        format!("Date: {}", local_date(&original.date)),
+        format!("Objet: {}", original.subject),
+        format!("À: {}", display_addresses(&original.to)),
+    ];
+    if let Some(cc) = original.cc.as_ref().filter(|cc| !cc.is_empty()) {
+        header.push(format!("Cc: {}", display_addresses(cc)));
+    }
+
+    let attachments: Vec<_> = original.attachments.iter().flatten().filter(|a| !a.inline).cloned().collect();
+    NewEmail {
+        account_id: Some(account.id.clone()),
+        from: EmailAddress { name: identity.name.clone(), email: identity.email.clone() },
+        to: vec![],
+        cc: None,
+        bcc: None,
+        subject: format!("Fwd: {}", threading::strip_reply_prefixes(&original.subject)),
+        body: format!(
+            "\n\n---------- Message transféré ----------\n{}\n\n{}",
+            header.join("\n"),
+            original.body
+        ),
+        html_body: None,
+        attachments: (!attachments.is_empty()).then_some(attachments),
+        in_reply_to: None,
+        references: None,
+        message_id: None,
+    }
+}
+
+/// A plain text rendering of an HTML body, for the text part of HTML mail.
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::new();
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => {
+                rest = "";
+                break;
+            }
+        };
+        let tag = rest[start + 1..end].trim().to_lowercase();
+        let name: String = tag
+            .trim_start_matches('/')
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect();
+        rest = &rest[end + 1..];
+
+        match name.as_str() {
+            // Their content is not text.
+            "script" | "style" | "head" | "title" if !tag.starts_with('/') => {
+                let close = format!("</{}", name);
+                rest = match find_ignore_ascii_case(rest, &close) {
+                    Some(at) => &rest[at..],
+                    None => "",
+                };
+            }
+            "br" => text.push('\n'),
+            "li" if !tag.starts_with('/') => text.push_str("\n- "),
+            "p" | "div" | "tr" | "table" | "ul" | "ol" | "blockquote" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" | "hr" => {
+                text.push('\n')
+            }
+            "td" | "th" if tag.starts_with('/') => text.push(' '),
+            _ => {}
+        }
+    }
+    text.push_str(&decode_entities(rest));
+
+    // Collapse the whitespace of the source and keep at most one blank line.
+    let mut lines: Vec<String> = Vec::new();
+    for line in text.lines() {
+        let line = line.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !line.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(line);
+        }
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// Byte offset of the first occurrence of the ASCII `needle` in `haystack`,
+/// ignoring ASCII case. Unlike searching a lowercased copy, the offset is
+/// always valid in `haystack`.
+fn find_ignore_ascii_case(haystack: &str, needle: &str) -> Option<usize> {
+    haystack
+        .as_bytes()
+        .windows(needle.len())
+        .position(|window| window.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+/// Names of the Latin-1 entities, for U+00A0 to U+00FF.
+const LATIN1_ENTITIES: [&str; 96] = [
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf", "laquo", "not", "shy",
+    "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro", "para", "middot", "cedil", "sup1", "ordm", "raquo",
+    "frac14", "frac12", "frac34", "iquest", "Agrave", "Aacute", "Acirc", "Atilde", "Auml", "Aring", "AElig", "Ccedil",
+    "Egrave", "Eacute", "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml", "ETH", "Ntilde", "Ograve", "Oacute",
+    "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute", "Ucirc", "Uuml", "Yacute", "THORN", "szlig",
+    "agrave", "aacute", "acirc", "atilde", "auml", "aring", "aelig", "ccedil", "egrave", "eacute", "ecirc", "euml",
+    "igrave", "iacute", "icirc", "iuml", "eth", "ntilde", "ograve", "oacute", "ocirc", "otilde", "ouml", "divide",
+    "oslash", "ugrave", "uacute", "ucirc", "uuml", "yacute", "thorn", "yuml",
+];
+
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest[1..].find(';').filter(|&end| end <= 10).map(|end| &rest[1..end + 1]);
+        let character = entity.and_then(|entity| match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            "ndash" => Some('–'),
+            "mdash" => Some('—'),
+            "lsquo" => Some('‘'),
+            "rsquo" => Some('’'),
+            "ldquo" => Some('“'),
+            "rdquo" => Some('”'),
+            "bull" => Some('•'),
+            "hellip" => Some('…'),
+            "euro" => Some('€'),
+            "trade" => Some('™'),
+            _ if !entity.starts_with('#') => {
+                let index = LATIN1_ENTITIES.iter().position(|name| *name == entity)?;
+                char::from_u32(0xA0 + index as u32)
+            }
+            _ => {
+                let number = entity.strip_prefix('#')?;
+                let code = match number.strip_prefix(['x', 'X']) {
+                    Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                    None => number.parse().ok()?,
+                };
+                char::from_u32(code)
+            }
+        });
+        match (entity, character) {
+            (Some(entity), Some(character)) => {
+                decoded.push(character);
+                rest = &rest[entity.len() + 2..];
+            }
+            _ => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+/// The identity of `account` the original was addressed to, or the account itself.
+fn receiving_identity(original: &Email, account: &EmailAccount) -> Identity {
+    original
+        .to
+        .iter()
+        .chain(original.cc.iter().flatten())
+        .chain(original.bcc.iter().flatten())
+        .chain(std::iter::once(&original.from))
+        .find_map(|address| account.identity_for(&address.email))
+        .unwrap_or_else(|| account.all_identities().remove(0))
+}
+
+/// Append the signature of `identity` to the bodies of `email`.
+///
+/// A body that already ends with it, such as a resent draft, is left alone.
+pub fn sign(email: &mut NewEmail, identity: &Identity) {
+    let signature = match identity.signature.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+        Some(signature) => signature,
+        None => return,
+    };
+
+    let block = format!("\n\n-- \n{}", signature);
+    if !email.body.trim_end().ends_with(block.trim_start()) {
+        email.body = format!("{}{}", email.body.trim_end(), block);
+    }
+    if let Some(html) = email.html_body.as_mut().filter(|html| !html.trim().is_empty()) {
+        let block = format!("<p>-- <br>{}</p>", search::escape_html(signature).replace('\n', "<br>"));
+        if !html.trim_end().ends_with(&block) {
+            html.push_str(&block);
+        }
+    }
+}
+
+fn local_date(date: &str) -> String {
+    DateTime::parse_from_rfc3339(date)
+        .map(|date| date.with_timezone(&Local).format("%d/%m/%Y à %H:%M").to_string())
+        .unwrap_or_else(|_| date.to_string())
+}
+
+fn display_address(address: &EmailAddress) -> String {
+    if address.name.trim().is_empty() {
+        format!("<{}>", address.email)
+    } else {
+        format!("{} <{}>", address.name, address.email)
+    }
+}
+
+fn display_addresses(addresses: &[EmailAddress]) -> String {
+    addresses.iter().map(display_address).collect::<Vec<_>>().join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(name: &str, email: &str) -> EmailAddress {
+        EmailAddress { name: name.to_string(), email: email.to_string() }
+    }
+
+    fn account() -> EmailAccount {
+        let mut account = EmailAccount::new(
+            "me@example.com".to_string(),
+            "Me".to_string(),
+            "imap.example.com".to_string(),
+            993,
+            "smtp.example.com".to_string(),
+            587,
+            "me".to_string(),
+            "secret".to_string(),
+            false,
+            false,
+        );
+        account.identities.push(Identity {
+            email: "team@example.com".to_string(),
+            name: "Team".to_string(),
+            signature: Some("The team".to_string()),
+        });
+        account
+    }
+
+    fn original() -> Email {
+        let mut email = Email::new(
+            address("Alice", "alice@example.com"),
+            vec![address("Team", "TEAM@example.com"), address("Bob", "bob@example.com")],
+            "RE: Fwd: Lunch".to_string(),
+            "See you\n\nat noon".to_string(),
+            "inbox".to_string(),
+        );
+        email.cc = Some(vec![address("Carol", "carol@example.com"), address("Me", "me@example.com")]);
+        email.message_id = Some("<3@x>".to_string());
+        email.references = Some(vec!["<1@x>".to_string(), "<2@x>".to_string()]);
+        email.date = "2024-01-05T10:00:00+00:00".to_string();
+        email
+    }
+
+    #[test]
+    fn test_reply_all() {
+        let account = account();
+        let own = own_addresses(std::slice::from_ref(&account));
+        let reply = reply(&original(), &account, &own, ReplyMode::ReplyAll);
+
+        assert_eq!(reply.subject, "Re: Lunch");
+        assert_eq!(reply.from.email, "team@example.com");
+        let to: Vec<&str> = reply.to.iter().map(|a| a.email.as_str()).collect();
+        assert_eq!(to, vec!["alice@example.com", "bob@example.com"]);
+        let cc: Vec<&str> = reply.cc.iter().flatten().map(|a| a.email.as_str()).collect();
+        assert_eq!(cc, vec!["carol@example.com"]);
+        assert_eq!(reply.in_reply_to.as_deref(), Some("<3@x>"));
+        assert_eq!(reply.references.unwrap(), vec!["<1@x>", "<2@x>", "<3@x>"]);
+        assert!(reply.body.starts_with("\n\nLe "));
+        assert!(reply.body.ends_with("Alice <alice@example.com> a écrit :\n> See you\n>\n> at noon"));
+    }
+
+    #[test]
+    fn test_sign() {
+        let identity = account().identity_for("team@example.com").unwrap();
+        let mut email = reply(&original(), &account(), &HashSet::new(), ReplyMode::Reply);
+        email.body = format!("Yes!{}", email.body);
+        email.html_body = Some("<p>Yes!</p>".to_string());
+
+        sign(&mut email, &identity);
+        assert!(email.body.starts_with("Yes!\n\nLe "));
+        assert!(email.body.ends_with("> at noon\n\n-- \nThe team"));
+        assert_eq!(email.html_body.as_deref(), Some("<p>Yes!</p><p>-- <br>The team</p>"));
+
+        // A draft signed before is not signed twice.
+        let signed = email.clone();
+        sign(&mut email, &identity);
+        assert_eq!(email.body, signed.body);
+        assert_eq!(email.html_body, signed.html_body);
+    }
+
+    #[test]
+    fn test_reply_to_own_message() {
+        let account = account();
+        let own = own_addresses(std::slice::from_ref(&account));
+        let mut sent = original();
+        sent.from = address("Me", "me@example.com");
+
+        let reply = reply(&sent, &account, &own, ReplyMode::Reply);
+        let to: Vec<&str> = reply.to.iter().map(|a| a.email.as_str()).collect();
+        assert_eq!(to, vec!["bob@example.com"]);
+        assert!(reply.cc.is_none());
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        let html = "<html><head><style>p { color: red; }</style></head><body>\n<p>Bonjour&nbsp;Alice,</p>\n<p>Voici   la <b>liste</b>&#160;:<br>\n<ul><li>pain</li><li>caf&eacute; &amp; th&#xE9;</li></ul></p><div>&lt;fin&gt; &Ccedil;a co&ucirc;te 5&euro; &copy; &bogus;</div></body></html>";
+        assert_eq!(
+            html_to_text(html),
+            "Bonjour Alice,\n\nVoici la liste :\n\n- pain\n- café & thé\n\n<fin> Ça coûte 5€ © &bogus;"
+        );
+        // Lowercasing "İ" changes its length, which must not shift the search for `</style>`.
+        assert_eq!(html_to_text("<STYLE>İİ</STYLE>ok"), "ok");
+        assert_eq!(LATIN1_ENTITIES[0xFF - 0xA0], "yuml");
+    }
+
+    #[test]
+    fn test_forward() {
+        let mut original = original();
+        original.attachments = Some(vec![crate::models::Attachment {
+            id: "1".to_string(),
+            filename: "menu.pdf".to_string(),
+            size: 3,
+            mime_type: "application/pdf".to_string(),
+            content_id: None,
+            inline: false,
+            blob: None,
+            data: None,
+        }]);
+
+        let forward = forward(&original, &account());
+        assert_eq!(forward.subject, "Fwd: Lunch");
+        assert!(forward.to.is_empty());
+        assert!(forward.in_reply_to.is_none());
+        assert!(forward.body.contains("---------- Message transféré ----------\nDe: Alice <alice@example.com>\n"));
+        assert!(forward.body.ends_with("Cc: Carol <carol@example.com>, Me <me@example.com>\n\nSee you\n\nat noon"));
+        assert_eq!(forward.attachments.unwrap()[0].filename, "menu.pdf");
+    }
+}
@@ -1,9 +1,16 @@
 use surrealdb::engine::local::{Db, SurrealKV};
 use surrealdb::Surreal;
-use crate::models::{Email, EmailAccount};
+use crate::models::{self, Attachment, Email, EmailAccount, EmailAddress, EmailListItem, EmailListQuery, EmailPage, EmailSort, FolderSyncState, MailFolder, PendingOperation, Security, ThreadHeaders};
 use thiserror::Error;
 use serde_json;
+use crate::blobs::BlobStore;
+use crate::pool::SessionPool;
+use crate::secrets::{self, FileSecretStore, SecretError, SecretStore};
+use crate::search::{self, SearchHit, SearchQuery};
+use crate::threading;
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::Arc;
 
 #[derive(Error, Debug)]
 pub enum DatabaseError {
@@ -13,15 +20,33 @@ pub enum DatabaseError {
     SerdeJsonError(#[from] serde_json::Error),
     #[error("Not found: {0}")]
     NotFound(String),
+    #[error("Blob store error: {0}")]
+    BlobError(#[from] std::io::Error),
+    #[error(transparent)]
+    SecretError(#[from] SecretError),
 }
 
 pub type Result<T> = std::result::Result<T, DatabaseError>;
 
 
-const EMAIL_SELECT_FIELDS: &str = "emailId AS id, from, to, cc, bcc, subject, body, htmlBody, date, read, starred, folder, attachments, account_id, message_id, fromUserId, toUserIds, ccUserIds, bccUserIds";
+const EMAIL_SELECT_FIELDS: &str = "emailId AS id, from, to, cc, bcc, subject, body, htmlBody, date, read, starred, folder, attachments, account_id, message_id, inReplyTo, references, threadId, rawBlob, bodyLoaded, size, bodyParts, fromUserId, toUserIds, ccUserIds, bccUserIds";
+
+const ACCOUNT_SELECT_FIELDS: &str = "accountId AS id, email, name, imapServer, imapPort, smtpServer, smtpPort, username, imapSecurity, smtpSecurity, allowInvalidCerts, allowInvalidSmtpCerts, imapFingerprint, smtpFingerprint, userId, signature, identities, auth";
+
+/// The headers threading needs.
+const THREAD_SELECT_FIELDS: &str = "emailId AS id, account_id, message_id, inReplyTo, references, subject, date, threadId";
+
+/// The fields of an `EmailListItem`, with the start of the body as preview.
+const LIST_SELECT_FIELDS: &str = "emailId AS id, from, to, subject, string::slice(body, 0, 400) AS preview, date, read, starred, folder, array::len((attachments ?? [])[WHERE !inline]) > 0 AS hasAttachments, account_id, threadId, size";
+
+const PREVIEW_LENGTH: usize = 160;
+const DEFAULT_PAGE_SIZE: usize = 50;
 
 pub struct Database {
     db: Surreal<Db>,
+    blobs: BlobStore,
+    secrets: Arc<dyn SecretStore>,
+    sessions: Arc<SessionPool>,
 }
 
 impl Database {
@@ -56,6 +81,37 @@ impl Database {
 
         Ok(Some(format!("user:{}", key)))
     }
+
+    async fn resolve_user_ids(&self, addrs: &[EmailAddress]) -> Result<Option<Vec<String>>> {
+        let mut ids = Vec::new();
+        for addr in addrs {
+            if let Some(id) = self.get_or_create_user(&addr.email, Some(&addr.name)).await? {
+                ids.push(id);
+            }
+        }
+        if ids.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ids))
+        }
+    }
+
+    /// Fill the `*_user_ids` fields of an email from its addresses.
+    pub async fn link_users(&self, email: &mut Email) -> Result<()> {
+        email.from_user_id = self
+            .get_or_create_user(&email.from.email, Some(&email.from.name))
+            .await?;
+        email.to_user_ids = self.resolve_user_ids(&email.to).await?;
+        email.cc_user_ids = match &email.cc {
+            Some(cc) => self.resolve_user_ids(cc).await?,
+            None => None,
+        };
+        email.bcc_user_ids = match &email.bcc {
+            Some(bcc) => self.resolve_user_ids(bcc).await?,
+            None => None,
+        };
+        Ok(())
+    }
     pub async fn new(path: impl AsRef<Path>) -> Result<Self> {
         let db = Surreal::new::<SurrealKV>(path.as_ref()).await?;
         
@@ -88,11 +144,37 @@ impl Database {
             DEFINE FIELD starred ON email TYPE bool DEFAULT false;
             DEFINE FIELD folder ON email TYPE string DEFAULT 'inbox';
             DEFINE FIELD attachments ON email TYPE option<array>;
+            DEFINE FIELD attachments.*.id ON email TYPE string;
+            DEFINE FIELD attachments.*.filename ON email TYPE string;
+            DEFINE FIELD attachments.*.size ON email TYPE int;
+            DEFINE FIELD attachments.*.mimeType ON email TYPE string;
+            DEFINE FIELD attachments.*.contentId ON email TYPE option<string>;
+            DEFINE FIELD attachments.*.inline ON email TYPE bool DEFAULT false;
+            DEFINE FIELD attachments.*.blob ON email TYPE option<string>;
             DEFINE FIELD account_id ON email TYPE option<string>;
             DEFINE FIELD message_id ON email TYPE option<string>;
+            DEFINE FIELD inReplyTo ON email TYPE option<string>;
+            DEFINE FIELD references ON email TYPE option<array<string>>;
+            DEFINE FIELD threadId ON email TYPE option<string>;
+            DEFINE FIELD baseSubject ON email TYPE option<string>;
+            DEFINE FIELD timestamp ON email TYPE option<int>;
+            DEFINE FIELD rawBlob ON email TYPE option<string>;
+            DEFINE FIELD bodyLoaded ON email TYPE bool DEFAULT true;
+            DEFINE FIELD size ON email TYPE option<int>;
+            DEFINE FIELD bodyParts ON email TYPE option<array>;
+            DEFINE FIELD bodyParts.*.section ON email TYPE string;
+            DEFINE FIELD bodyParts.*.mimeType ON email TYPE string;
             DEFINE INDEX email_id ON email FIELDS emailId UNIQUE;
+            DEFINE INDEX email_thread ON email FIELDS threadId;
+            DEFINE INDEX email_message_id ON email FIELDS account_id, message_id;
+            DEFINE INDEX email_base_subject ON email FIELDS account_id, baseSubject;
+            -- No stemmer: mailboxes mix languages, and one language's rules mangle the others.
+            DEFINE ANALYZER email_text TOKENIZERS blank, class, punct FILTERS lowercase, ascii;
+            DEFINE INDEX email_subject_search ON email FIELDS subject SEARCH ANALYZER email_text BM25 HIGHLIGHTS;
+            DEFINE INDEX email_body_search ON email FIELDS body SEARCH ANALYZER email_text BM25 HIGHLIGHTS;
             DEFINE INDEX email_folder ON email FIELDS folder;
             DEFINE INDEX email_date ON email FIELDS date;
+            DEFINE INDEX email_timestamp ON email FIELDS timestamp;
         "#).await?;
 
         db.query(r#"
@@ -113,35 +195,447 @@ impl Database {
             DEFINE FIELD smtpPort ON account TYPE option<int>;
             DEFINE FIELD username ON account TYPE option<string>;
             DEFINE FIELD password ON account TYPE option<string>;
-            DEFINE FIELD useSsl ON account TYPE bool DEFAULT true;
+            DEFINE FIELD useSsl ON account TYPE option<bool>;
+            DEFINE FIELD imapSecurity ON account TYPE option<string>;
+            DEFINE FIELD smtpSecurity ON account TYPE option<string>;
             DEFINE FIELD allowInvalidCerts ON account TYPE bool DEFAULT false;
             DEFINE FIELD allowInvalidSmtpCerts ON account TYPE bool DEFAULT false;
+            DEFINE FIELD imapFingerprint ON account TYPE option<string>;
+            DEFINE FIELD smtpFingerprint ON account TYPE option<string>;
             DEFINE FIELD userId ON account TYPE option<string>;
+            DEFINE FIELD signature ON account TYPE option<string>;
+            DEFINE FIELD identities ON account TYPE array DEFAULT [];
+            DEFINE FIELD identities.*.email ON account TYPE string;
+            DEFINE FIELD identities.*.name ON account TYPE string;
+            DEFINE FIELD identities.*.signature ON account TYPE option<string>;
+            DEFINE FIELD auth ON account FLEXIBLE TYPE object DEFAULT { type: 'password' };
             DEFINE INDEX account_email ON account FIELDS email UNIQUE;
             DEFINE INDEX account_id ON account FIELDS accountId UNIQUE;
         "#).await?;
+
+        db.query(r#"
+            DEFINE TABLE blob SCHEMAFULL;
+            DEFINE FIELD hash ON blob TYPE string;
+            DEFINE FIELD refs ON blob TYPE int;
+            DEFINE INDEX blob_hash ON blob FIELDS hash UNIQUE;
+        "#).await?;
+
+        db.query(r#"
+            DEFINE TABLE sync_state SCHEMAFULL;
+            DEFINE FIELD accountId ON sync_state TYPE string;
+            DEFINE FIELD folder ON sync_state TYPE string;
+            DEFINE FIELD uidValidity ON sync_state TYPE int;
+            DEFINE FIELD lastUid ON sync_state TYPE int;
+            DEFINE FIELD oldestUid ON sync_state TYPE int;
+            DEFINE FIELD highestModSeq ON sync_state TYPE option<int>;
+            DEFINE INDEX sync_state_folder ON sync_state FIELDS accountId, folder UNIQUE;
+        "#).await?;
+
+        db.query(r#"
+            DEFINE TABLE folder SCHEMAFULL;
+            DEFINE FIELD accountId ON folder TYPE string;
+            DEFINE FIELD path ON folder TYPE string;
+            DEFINE FIELD name ON folder TYPE string;
+            DEFINE FIELD delimiter ON folder TYPE option<string>;
+            DEFINE FIELD parent ON folder TYPE option<string>;
+            DEFINE FIELD specialUse ON folder TYPE option<string>;
+            DEFINE FIELD selectable ON folder TYPE bool DEFAULT true;
+            DEFINE FIELD subscribed ON folder TYPE bool DEFAULT true;
+            DEFINE INDEX folder_path ON folder FIELDS accountId, path UNIQUE;
+        "#).await?;
+
+        db.query(r#"
+            DEFINE TABLE outbox SCHEMAFULL;
+            DEFINE FIELD opId ON outbox TYPE string;
+            DEFINE FIELD accountId ON outbox TYPE string;
+            DEFINE FIELD emailId ON outbox TYPE option<string>;
+            DEFINE FIELD folder ON outbox TYPE option<string>;
+            DEFINE FIELD uid ON outbox TYPE option<int>;
+            DEFINE FIELD action ON outbox FLEXIBLE TYPE object;
+            DEFINE FIELD createdAt ON outbox TYPE string;
+            DEFINE FIELD attempts ON outbox TYPE int DEFAULT 0;
+            DEFINE FIELD lastError ON outbox TYPE option<string>;
+            DEFINE INDEX outbox_id ON outbox FIELDS opId UNIQUE;
+            DEFINE INDEX outbox_email ON outbox FIELDS emailId;
+            DEFINE INDEX outbox_created ON outbox FIELDS createdAt;
+        "#).await?;
         
-        Ok(Self { db })
+        // Blobs live next to the database, in the app data dir.
+        let dir = path.as_ref().parent().unwrap_or(Path::new("."));
+        let blobs = BlobStore::new(dir.join("blobs"));
+        let secrets = Arc::new(FileSecretStore::open(dir)?);
+
+        let sessions = Arc::new(SessionPool::new());
+
+        let database = Self { db, blobs, secrets, sessions };
+        database.migrate_passwords().await?;
+        database.migrate_security().await?;
+        database.migrate_blob_refs().await?;
+        database.migrate_email_ids().await?;
+        database.migrate_threads().await?;
+        database.migrate_timestamps().await?;
+        Ok(database)
     }
-    
-    pub async fn create_email(&self, email: &Email) -> Result<Email> {
-        let mut content = serde_json::to_value(email)?;
-        if let Some(obj) = content.as_object_mut() {
-            if let Some(id) = obj.remove("id") {
-                obj.insert("emailId".to_string(), id);
+
+    /// The store holding account passwords and OAuth2 tokens.
+    pub fn secrets(&self) -> Arc<dyn SecretStore> {
+        self.secrets.clone()
+    }
+
+    /// The IMAP sessions kept open for the accounts of this database.
+    pub fn sessions(&self) -> Arc<SessionPool> {
+        self.sessions.clone()
+    }
+
+    /// Move passwords stored in the `account` table by earlier versions to
+    /// the secret store.
+    async fn migrate_passwords(&self) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct Legacy {
+            id: String,
+            password: String,
+        }
+
+        let mut result = self.db
+            .query("SELECT accountId AS id, password FROM account WHERE password != NONE")
+            .await?;
+        let legacy: Vec<Legacy> = result.take(0)?;
+        for account in legacy {
+            if !account.password.is_empty() {
+                self.secrets.set(&secrets::password_key(&account.id), &account.password)?;
+            }
+            self.db
+                .query("UPDATE account SET password = NONE WHERE accountId = $id")
+                .bind(("id", &account.id))
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+
+    /// Replace the single `useSsl` flag of accounts saved by earlier versions
+    /// with the security of each protocol, guessed from its port.
+    async fn migrate_security(&self) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct Legacy {
+            id: String,
+            #[serde(rename = "useSsl")]
+            use_ssl: Option<bool>,
+            #[serde(rename = "imapPort")]
+            imap_port: Option<u16>,
+            #[serde(rename = "smtpPort")]
+            smtp_port: Option<u16>,
+        }
+
+        let mut result = self.db
+            .query("SELECT accountId AS id, useSsl, imapPort, smtpPort FROM account WHERE imapSecurity = NONE OR smtpSecurity = NONE")
+            .await?;
+        let legacy: Vec<Legacy> = result.take(0)?;
+        for account in legacy {
+            // `useSsl` only ever applied to SMTP; IMAP picked TLS by port.
+            let smtp = match Security::default_for_smtp_port(account.smtp_port.unwrap_or(587)) {
+                // Turning SSL off only ever worked on ports that start in plaintext.
+                Security::StartTls if !account.use_ssl.unwrap_or(true) => Security::None,
+                security => security,
+            };
+            self.db
+                .query("UPDATE account SET imapSecurity = $imap, smtpSecurity = $smtp, useSsl = NONE WHERE accountId = $id")
+                .bind(("id", &account.id))
+                .bind(("imap", Security::default_for_imap_port(account.imap_port.unwrap_or(993))))
+                .bind(("smtp", smtp))
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+
+    /// Re-key emails synced by earlier versions as `{account}:{uid}`, which
+    /// clashed between folders, by their folder and UIDVALIDITY.
+    ///
+    /// Sync states are then forgotten, so that messages skipped because of a
+    /// clash are brought in by the next sync.
+    async fn migrate_email_ids(&self) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct Legacy {
+            id: String,
+            account_id: String,
+            folder: String,
+        }
+
+        let mut result = self.db
+            .query("SELECT emailId AS id, account_id, folder FROM email WHERE account_id != NONE AND array::len(string::split(emailId, ':')) = 2")
+            .await?;
+        let legacy: Vec<Legacy> = result.take(0)?;
+        let mut validities = HashMap::new();
+        let mut accounts = HashSet::new();
+        for email in legacy {
+            let uid = match email.id.strip_prefix(&format!("{}:", email.account_id)).and_then(|uid| uid.parse().ok()) {
+                Some(uid) => uid,
+                None => continue,
+            };
+            let key = (email.account_id.clone(), email.folder.clone());
+            if !validities.contains_key(&key) {
+                let state = self.get_sync_state(&email.account_id, &email.folder).await?;
+                validities.insert(key.clone(), state.map(|s| s.uid_validity));
             }
+
+            match validities[&key] {
+                Some(uid_validity) => {
+                    let new_id = models::imap_email_id(&email.account_id, &email.folder, uid_validity, uid);
+                    self.rekey_email(&email.id, &new_id).await?;
+                    self.db
+                        .query("UPDATE outbox SET emailId = $new_id WHERE emailId = $id")
+                        .bind(("id", &email.id))
+                        .bind(("new_id", new_id))
+                        .await?
+                        .check()?;
+                }
+                // Not from a synced folder: the next sync or search brings it back.
+                None => {
+                    self.delete_operations_for_email(&email.id).await?;
+                    self.delete_email(&email.id).await?;
+                }
+            }
+            accounts.insert(email.account_id);
+        }
+
+        for account_id in accounts {
+            self.db
+                .query("DELETE sync_state WHERE accountId = $account")
+                .bind(("account", account_id))
+                .await?
+                .check()?;
+        }
+        Ok(())
+    }
+
+    /// Index the base subject of emails stored before threads were updated
+    /// incrementally, and re-thread them with thread ids scoped by account.
+    async fn migrate_threads(&self) -> Result<()> {
+        let mut result = self.db
+            .query(format!("SELECT {} FROM email WHERE baseSubject = NONE", THREAD_SELECT_FIELDS))
+            .await?;
+        let legacy: Vec<ThreadHeaders> = result.take(0)?;
+        if legacy.is_empty() {
+            return Ok(());
+        }
+
+        let mut by_account: HashMap<Option<String>, Vec<ThreadHeaders>> = HashMap::new();
+        for headers in legacy {
+            self.db
+                .query("UPDATE email SET baseSubject = $subject WHERE emailId = $id")
+                .bind(("id", &headers.id))
+                .bind(("subject", threading::base_subject(&headers.subject).0))
+                .await?
+                .check()?;
+            by_account.entry(headers.account_id.clone()).or_default().push(headers);
+        }
+        for (account_id, headers) in by_account {
+            threading::save_threads(self, account_id.as_deref(), &headers).await?;
+        }
+        Ok(())
+    }
+
+    /// Fill in the UTC timestamp of emails stored before it was kept.
+    async fn migrate_timestamps(&self) -> Result<()> {
+        #[derive(serde::Deserialize)]
+        struct Legacy {
+            id: String,
+            date: String,
+        }
+
+        let mut result = self.db
+            .query("SELECT emailId AS id, date FROM email WHERE timestamp = NONE")
+            .await?;
+        let legacy: Vec<Legacy> = result.take(0)?;
+        for email in legacy {
+            if let Some(timestamp) = utc_timestamp(&email.date) {
+                self.db
+                    .query("UPDATE email SET timestamp = $timestamp WHERE emailId = $id")
+                    .bind(("id", &email.id))
+                    .bind(("timestamp", timestamp))
+                    .await?
+                    .check()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Count the blob references of emails stored before blobs were counted.
+    async fn migrate_blob_refs(&self) -> Result<()> {
+        let mut result = self.db.query("SELECT VALUE hash FROM blob LIMIT 1").await?;
+        let counted: Vec<String> = result.take(0)?;
+        if !counted.is_empty() {
+            return Ok(());
+        }
+
+        let mut result = self.db
+            .query("SELECT rawBlob, attachments.*.blob AS blobs FROM email WHERE rawBlob != NONE OR attachments != NONE")
+            .await?;
+        let refs: Vec<serde_json::Value> = result.take(0)?;
+        let mut hashes = Vec::new();
+        for value in &refs {
+            collect_strings(value, &mut hashes);
+        }
+        self.retain_blobs(hashes).await
+    }
+
+    /// Keep the password of `account`, if it came with one.
+    fn store_password(&self, account: &EmailAccount) -> secrets::Result<()> {
+        if !account.password.is_empty() {
+            self.secrets.set(&secrets::password_key(&account.id), &account.password)?;
         }
+        Ok(())
+    }
+    
+    pub async fn create_email(&self, email: &Email) -> Result<Email> {
+        let mut email = email.clone();
+        self.store_blobs(&mut email).await?;
 
         let _: Vec<serde_json::Value> = self.db
             .create("email")
-            .content(content)
+            .content(Self::email_content(&email)?)
             .await?;
+        self.retain_blobs(blob_refs(&email)).await?;
 
         self.get_email(&email.id)
             .await?
             .ok_or_else(|| DatabaseError::NotFound("Failed to create email".to_string()))
     }
+
+    /// Overwrite a stored email with new content, keeping its id.
+    pub async fn replace_email(&self, email: &Email) -> Result<Email> {
+        let previous = self
+            .get_email(&email.id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", email.id)))?;
+        let mut email = email.clone();
+        self.store_blobs(&mut email).await?;
+
+        self.db
+            .query("UPDATE email CONTENT $content WHERE emailId = $id")
+            .bind(("id", &email.id))
+            .bind(("content", Self::email_content(&email)?))
+            .await?
+            .check()?;
+
+        self.retain_blobs(blob_refs(&email)).await?;
+        self.release_blobs(blob_refs(&previous)).await?;
+
+        self.get_email(&email.id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", email.id)))
+    }
+
+    /// Move attachment contents and the raw message of `email` to the blob store.
+    async fn store_blobs(&self, email: &mut Email) -> Result<()> {
+        for attachment in email.attachments.iter_mut().flatten() {
+            if let Some(data) = attachment.data.take() {
+                attachment.blob = Some(self.blobs.put(&data).await?);
+            }
+        }
+        if let Some(raw) = email.raw.take() {
+            email.raw_blob = Some(self.blobs.put(&raw).await?);
+        }
+        Ok(())
+    }
+
+    fn email_content(email: &Email) -> serde_json::Result<serde_json::Value> {
+        let mut content = serde_json::to_value(email)?;
+        if let Some(obj) = content.as_object_mut() {
+            if let Some(id) = obj.remove("id") {
+                obj.insert("emailId".to_string(), id);
+            }
+            // Indexed to find the conversations a new message may join.
+            obj.insert("baseSubject".to_string(), threading::base_subject(&email.subject).0.into());
+            // Sorted on instead of `date`, whose offsets vary.
+            obj.insert("timestamp".to_string(), utc_timestamp(&email.date).into());
+        }
+        Ok(content)
+    }
     
+    /// An attachment of an email, with its content.
+    pub async fn get_attachment(&self, email_id: &str, attachment_id: &str) -> Result<Option<Attachment>> {
+        let email = match self.get_email(email_id).await? {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+        let mut attachment = match email.attachments.into_iter().flatten().find(|a| a.id == attachment_id) {
+            Some(attachment) => attachment,
+            None => return Ok(None),
+        };
+
+        attachment.data = match &attachment.blob {
+            Some(hash) => Some(self.blobs.get(hash).await?),
+            None => None,
+        };
+
+        Ok(Some(attachment))
+    }
+
+    /// Store the body of an email synced without it.
+    pub async fn set_email_body(&self, id: &str, body: &str, html_body: Option<&str>) -> Result<()> {
+        self.db
+            .query("UPDATE email SET body = $body, htmlBody = $html, bodyLoaded = true WHERE emailId = $id")
+            .bind(("id", id))
+            .bind(("body", body))
+            .bind(("html", html_body))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Store the content of one attachment downloaded on demand.
+    pub async fn set_attachment_data(&self, email_id: &str, attachment_id: &str, data: &[u8]) -> Result<()> {
+        let email = self
+            .get_email(email_id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", email_id)))?;
+        let hash = self.blobs.put(data).await?;
+
+        let mut attachments = email.attachments.unwrap_or_default();
+        let mut added = Vec::new();
+        let mut replaced = Vec::new();
+        for attachment in attachments.iter_mut().filter(|a| a.id == attachment_id) {
+            replaced.extend(attachment.blob.replace(hash.clone()));
+            added.push(hash.clone());
+        }
+        self.db
+            .query("UPDATE email SET attachments = $attachments WHERE emailId = $id")
+            .bind(("id", email_id))
+            .bind(("attachments", &attachments))
+            .await?
+            .check()?;
+
+        self.retain_blobs(added).await?;
+        self.release_blobs(replaced).await
+    }
+
+    /// Replace the body, attachments and raw message of an email with a full download.
+    pub async fn set_email_content(&self, id: &str, full: &Email) -> Result<()> {
+        let previous = self
+            .get_email(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", id)))?;
+
+        let mut full = full.clone();
+        self.store_blobs(&mut full).await?;
+
+        self.db
+            .query("UPDATE email SET body = $body, htmlBody = $html, attachments = $attachments, rawBlob = $raw, bodyLoaded = true WHERE emailId = $id")
+            .bind(("id", id))
+            .bind(("body", &full.body))
+            .bind(("html", &full.html_body))
+            .bind(("attachments", &full.attachments))
+            .bind(("raw", &full.raw_blob))
+            .await?
+            .check()?;
+
+        self.retain_blobs(blob_refs(&full)).await?;
+        self.release_blobs(blob_refs(&previous)).await
+    }
+
     pub async fn get_email(&self, id: &str) -> Result<Option<Email>> {
         let mut result = self.db
             .query(&format!("SELECT {} FROM email WHERE emailId = $id LIMIT 1", EMAIL_SELECT_FIELDS))
@@ -152,16 +646,175 @@ impl Database {
         Ok(emails.into_iter().next())
     }
     
-    pub async fn get_emails_by_folder(&self, folder: &str) -> Result<Vec<Email>> {
+    /// The raw RFC 5322 message of an email, when it was kept.
+    pub async fn get_raw_message(&self, id: &str) -> Result<Option<Vec<u8>>> {
+        match self.get_email(id).await?.and_then(|email| email.raw_blob) {
+            Some(hash) => Ok(Some(self.blobs.get(&hash).await?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record the message an email was sent as, replacing any previous one.
+    pub async fn set_sent_message(&self, id: &str, message_id: Option<&str>, raw: &[u8]) -> Result<()> {
+        let previous = self
+            .get_email(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", id)))?;
+        let hash = self.blobs.put(raw).await?;
+
+        self.db
+            .query("UPDATE email SET message_id = $message_id, rawBlob = $raw WHERE emailId = $id")
+            .bind(("id", id))
+            .bind(("message_id", message_id))
+            .bind(("raw", &hash))
+            .await?
+            .check()?;
+
+        self.retain_blobs(vec![hash]).await?;
+        self.release_blobs(previous.raw_blob.into_iter().collect()).await
+    }
+
+    /// Ids of the emails of `account_id` in `folder` with Message-ID `message_id`.
+    pub async fn find_by_message_id(&self, account_id: &str, folder: &str, message_id: &str) -> Result<Vec<String>> {
         let mut result = self.db
-            .query(&format!("SELECT {} FROM email WHERE folder = $folder ORDER BY date DESC", EMAIL_SELECT_FIELDS))
+            .query("SELECT VALUE emailId FROM email WHERE account_id = $account AND folder = $folder AND message_id = $message_id")
+            .bind(("account", account_id))
             .bind(("folder", folder))
+            .bind(("message_id", message_id))
             .await?;
-        
+
+        let ids: Vec<String> = result.take(0)?;
+        Ok(ids)
+    }
+
+    /// One page of a folder, sorted and filtered, without bodies.
+    ///
+    /// The cursor holds the sort key and id of the last item of the previous
+    /// page, so pages stay stable while new mail arrives.
+    pub async fn list_emails(&self, query: &EmailListQuery) -> Result<EmailPage> {
+        let key = match query.sort {
+            EmailSort::Date => "(timestamp ?? 0)",
+            EmailSort::Sender => "string::lowercase(from.name ?? from.email)",
+            EmailSort::Subject => "string::lowercase(subject)",
+            EmailSort::Size => "(size ?? 0)",
+        };
+        let (direction, comparison) = if query.ascending { ("ASC", ">") } else { ("DESC", "<") };
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).max(1);
+
+        let mut conditions = vec!["folder = $folder".to_string()];
+        let mut vars = serde_json::Map::new();
+        vars.insert("folder".into(), query.folder.clone().into());
+        if query.unread {
+            conditions.push("read = false".to_string());
+        }
+        if query.starred {
+            conditions.push("starred = true".to_string());
+        }
+        if query.has_attachments {
+            conditions.push("array::len((attachments ?? [])[WHERE !inline]) > 0".to_string());
+        }
+        if let Some(cursor) = &query.cursor {
+            let (after_key, after_id): (serde_json::Value, String) = serde_json::from_str(cursor)?;
+            conditions.push(format!(
+                "({0} {1} $afterKey OR ({0} = $afterKey AND emailId {1} $afterId))",
+                key, comparison
+            ));
+            vars.insert("afterKey".into(), after_key);
+            vars.insert("afterId".into(), after_id.into());
+        }
+        // One extra row tells whether there is a next page.
+        vars.insert("limit".into(), (limit + 1).into());
+
+        // Pin the folder index: the planner fails on the cursor's OR over `date`.
+        let mut result = self.db
+            .query(format!(
+                "SELECT {0}, {1} AS sortKey, emailId AS sortId FROM email WITH INDEX email_folder WHERE {2} ORDER BY sortKey {3}, sortId {3} LIMIT $limit",
+                LIST_SELECT_FIELDS, key, conditions.join(" AND "), direction
+            ))
+            .bind(serde_json::Value::Object(vars))
+            .await?;
+
+        let mut rows: Vec<ListRow> = result.take(0)?;
+        let next_cursor = if rows.len() > limit {
+            rows.truncate(limit);
+            let last = rows.last().expect("page is not empty");
+            Some(serde_json::to_string(&(&last.sort_key, &last.item.id))?)
+        } else {
+            None
+        };
+
+        let items = rows
+            .into_iter()
+            .map(|row| {
+                let mut item = row.item;
+                let words: Vec<&str> = item.preview.split_whitespace().collect();
+                item.preview = words.join(" ").chars().take(PREVIEW_LENGTH).collect();
+                item
+            })
+            .collect();
+        Ok(EmailPage { items, next_cursor })
+    }
+
+    /// Every email of the threads that have a message in `folder`.
+    ///
+    /// Trash and junk only count when they are the folder being listed.
+    pub async fn get_thread_emails(&self, folder: &str) -> Result<Vec<Email>> {
+        let mut result = self.db
+            .query(format!(
+                "SELECT {} FROM email WHERE (threadId IN (SELECT VALUE threadId FROM email WHERE folder = $folder AND threadId != NONE) OR (folder = $folder AND threadId = NONE)) AND (folder = $folder OR folder NOT IN ['trash', 'junk'])",
+                EMAIL_SELECT_FIELDS
+            ))
+            .bind(("folder", folder))
+            .await?;
+
         let emails: Vec<Email> = result.take(0)?;
         Ok(emails)
     }
-    
+
+    /// Threading headers of the emails `ids` of `account_id`, and of the
+    /// conversations they may join: those with one of `message_ids` as
+    /// Message-ID or reference, or with one of the base `subjects`.
+    pub async fn get_related_thread_headers(
+        &self,
+        account_id: Option<&str>,
+        ids: Vec<String>,
+        message_ids: Vec<String>,
+        subjects: Vec<String>,
+    ) -> Result<Vec<ThreadHeaders>> {
+        let scope = match account_id {
+            Some(_) => "account_id = $account",
+            None => "account_id = NONE",
+        };
+        let related = "emailId IN $ids OR message_id IN $message_ids OR inReplyTo IN $message_ids OR references CONTAINSANY $message_ids OR baseSubject IN $subjects";
+        let mut result = self.db
+            .query(format!(
+                "LET $threads = (SELECT VALUE threadId FROM email WHERE {0} AND threadId != NONE AND ({1}))",
+                scope, related
+            ))
+            .query(format!(
+                "SELECT {} FROM email WHERE {} AND (threadId IN $threads OR {})",
+                THREAD_SELECT_FIELDS, scope, related
+            ))
+            .bind(("account", account_id))
+            .bind(("ids", ids))
+            .bind(("message_ids", message_ids))
+            .bind(("subjects", subjects))
+            .await?;
+
+        let headers: Vec<ThreadHeaders> = result.take(1)?;
+        Ok(headers)
+    }
+
+    pub async fn set_thread_id(&self, id: &str, thread_id: &str) -> Result<()> {
+        self.db
+            .query("UPDATE email SET threadId = $thread WHERE emailId = $id")
+            .bind(("id", id))
+            .bind(("thread", thread_id))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get_all_emails(&self) -> Result<Vec<Email>> {
         let mut result = self.db
             .query(&format!("SELECT {} FROM email", EMAIL_SELECT_FIELDS))
@@ -193,14 +846,145 @@ impl Database {
     }
     
     pub async fn delete_email(&self, id: &str) -> Result<()> {
+        self.delete_emails_where("emailId = $id", serde_json::json!({ "id": id })).await
+    }
+
+    /// Delete the emails matching `condition`, then the blobs no email uses anymore.
+    async fn delete_emails_where(&self, condition: &str, vars: serde_json::Value) -> Result<()> {
+        let mut result = self.db
+            .query(format!("SELECT rawBlob, attachments.*.blob AS blobs FROM email WHERE {}", condition))
+            .query(format!("DELETE email WHERE {}", condition))
+            .bind(vars)
+            .await?;
+
+        let refs: Vec<serde_json::Value> = result.take(0)?;
+        let mut hashes = Vec::new();
+        for value in &refs {
+            collect_strings(value, &mut hashes);
+        }
+        self.release_blobs(hashes).await
+    }
+
+    /// Count one more reference to each of `hashes`, once per occurrence.
+    async fn retain_blobs(&self, hashes: Vec<String>) -> Result<()> {
+        for hash in hashes {
+            let mut result = self.db
+                .query("UPDATE blob SET refs += 1 WHERE hash = $hash RETURN VALUE refs")
+                .bind(("hash", &hash))
+                .await?;
+            let updated: Vec<i64> = result.take(0)?;
+            if updated.is_empty() {
+                self.db
+                    .query("CREATE blob SET hash = $hash, refs = 1")
+                    .bind(("hash", &hash))
+                    .await?
+                    .check()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop one reference to each of `hashes`, once per occurrence, and remove
+    /// the blobs no email references anymore.
+    async fn release_blobs(&self, hashes: Vec<String>) -> Result<()> {
+        for hash in hashes {
+            let mut result = self.db
+                .query("UPDATE blob SET refs -= 1 WHERE hash = $hash RETURN VALUE refs")
+                .bind(("hash", &hash))
+                .await?;
+            let refs: Vec<i64> = result.take(0)?;
+            // A blob nothing counted is left alone rather than risked.
+            if refs.first().is_some_and(|&refs| refs <= 0) {
+                self.db
+                    .query("DELETE blob WHERE hash = $hash")
+                    .bind(("hash", &hash))
+                    .await?
+                    .check()?;
+                self.blobs.remove(&hash).await?;
+            }
+        }
+        Ok(())
+    }
+    
+    /// Change the id of a stored email, e.g. when the server assigned it a new UID.
+    pub async fn rekey_email(&self, id: &str, new_id: &str) -> Result<()> {
         self.db
-            .query("DELETE email WHERE emailId = $id")
+            .query("UPDATE email SET emailId = $new_id WHERE emailId = $id")
             .bind(("id", id))
+            .bind(("new_id", new_id))
             .await?;
 
         Ok(())
     }
-    
+
+    pub async fn get_folder_email_ids(&self, account_id: &str, folder: &str) -> Result<Vec<String>> {
+        let mut result = self.db
+            .query("SELECT VALUE emailId FROM email WHERE account_id = $account AND folder = $folder")
+            .bind(("account", account_id))
+            .bind(("folder", folder))
+            .await?;
+
+        let ids: Vec<String> = result.take(0)?;
+        Ok(ids)
+    }
+
+    pub async fn delete_folder_emails(&self, account_id: &str, folder: &str) -> Result<()> {
+        self.delete_emails_where(
+            "account_id = $account AND folder = $folder",
+            serde_json::json!({ "account": account_id, "folder": folder }),
+        )
+        .await
+    }
+
+    /// Point everything stored under folder `from`, and its subfolders, at `to`.
+    pub async fn rename_folder(&self, account_id: &str, from: &str, to: &str, delimiter: Option<&str>) -> Result<()> {
+        let prefix = delimiter.map(|d| format!("{}{}", from, d)).unwrap_or_else(|| from.to_string());
+        let matches = if delimiter.is_some() {
+            "($from = folder OR string::startsWith(folder, $prefix))"
+        } else {
+            "$from = folder"
+        };
+        // Ids of synced emails embed their folder, see `imap_email_id`.
+        let id_from = format!("{}:{}", account_id, from);
+        let id_prefix = delimiter.map(|d| format!("{}{}", id_from, d)).unwrap_or_else(|| format!("{}:", id_from));
+        let id_matches = "(string::startsWith(emailId, $id_exact) OR string::startsWith(emailId, $id_prefix))";
+
+        self.db
+            .query(format!("UPDATE email SET folder = string::concat($to, string::slice(folder, $len)) WHERE account_id = $account AND {}", matches))
+            .query(format!("UPDATE sync_state SET folder = string::concat($to, string::slice(folder, $len)) WHERE accountId = $account AND {}", matches))
+            .query(format!("UPDATE outbox SET folder = string::concat($to, string::slice(folder, $len)) WHERE accountId = $account AND {}", matches))
+            .query(format!("UPDATE email SET emailId = string::concat($id_to, string::slice(emailId, $id_len)) WHERE account_id = $account AND {}", id_matches))
+            .query(format!("UPDATE outbox SET emailId = string::concat($id_to, string::slice(emailId, $id_len)) WHERE accountId = $account AND {}", id_matches))
+            .bind(("account", account_id))
+            .bind(("from", from))
+            .bind(("to", to))
+            .bind(("prefix", prefix))
+            .bind(("len", from.chars().count()))
+            .bind(("id_to", format!("{}:{}", account_id, to)))
+            .bind(("id_len", id_from.chars().count()))
+            .bind(("id_exact", format!("{}:", id_from)))
+            .bind(("id_prefix", id_prefix))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
+    /// Forget a folder that was deleted on the server.
+    pub async fn delete_folder(&self, account_id: &str, folder: &str) -> Result<()> {
+        self.delete_folder_emails(account_id, folder).await?;
+        self.db
+            .query("DELETE sync_state WHERE accountId = $account AND folder = $folder")
+            .query("DELETE outbox WHERE accountId = $account AND folder = $folder")
+            .query("DELETE folder WHERE accountId = $account AND path = $folder")
+            .bind(("account", account_id))
+            .bind(("folder", folder))
+            .await?
+            .check()?;
+
+        Ok(())
+    }
+
     pub async fn mark_as_read(&self, id: &str, read: bool) -> Result<()> {
         self.db
             .query("UPDATE email SET read = $read WHERE emailId = $id")
@@ -221,7 +1005,20 @@ impl Database {
         Ok(())
     }
     
-    pub async fn move_to_folder(&self, id: &str, folder: &str) -> Result<()> {
+    /// Apply flags read from the server.
+    pub async fn set_flags(&self, id: &str, folder: &str, read: bool, starred: bool) -> Result<()> {
+        self.db
+            .query("UPDATE email SET read = $read, starred = $starred WHERE emailId = $id AND folder = $folder")
+            .bind(("id", id))
+            .bind(("folder", folder))
+            .bind(("read", read))
+            .bind(("starred", starred))
+            .await?;
+
+        Ok(())
+    }
+    
+    pub async fn move_to_folder(&self, id: &str, folder: &str) -> Result<()> {
         self.db
             .query("UPDATE email SET folder = $folder WHERE emailId = $id")
             .bind(("id", id))
@@ -231,17 +1028,95 @@ impl Database {
         Ok(())
     }
     
-    pub async fn search_emails(&self, query: &str) -> Result<Vec<Email>> {
+    /// Search emails, ranked by BM25 when the query has free text and by date otherwise.
+    pub async fn search_emails(&self, query: &SearchQuery, limit: usize) -> Result<Vec<SearchHit>> {
+        let mut conditions = Vec::new();
+        let mut vars = serde_json::Map::new();
+
+        let has_text = !query.text.trim().is_empty();
+        if has_text {
+            conditions.push("(subject @0@ $text OR body @1@ $text)".to_string());
+            vars.insert("text".into(), query.text.clone().into());
+        }
+        for (i, from) in query.from.iter().enumerate() {
+            conditions.push(format!("(string::lowercase(from.email) CONTAINS $from{0} OR string::lowercase(from.name) CONTAINS $from{0})", i));
+            vars.insert(format!("from{}", i), from.clone().into());
+        }
+        for (i, to) in query.to.iter().enumerate() {
+            conditions.push(format!(
+                "array::len(array::concat(to, cc ?? [])[WHERE string::lowercase(email) CONTAINS $to{0} OR string::lowercase(name) CONTAINS $to{0}]) > 0",
+                i
+            ));
+            vars.insert(format!("to{}", i), to.clone().into());
+        }
+        for (i, subject) in query.subject.iter().enumerate() {
+            conditions.push(format!("string::lowercase(subject) CONTAINS $subject{}", i));
+            vars.insert(format!("subject{}", i), subject.clone().into());
+        }
+        if query.has_attachment {
+            conditions.push("array::len(attachments ?? []) > 0".to_string());
+        }
+        if let Some(read) = query.read {
+            conditions.push("read = $read".to_string());
+            vars.insert("read".into(), read.into());
+        }
+        if let Some(starred) = query.starred {
+            conditions.push("starred = $starred".to_string());
+            vars.insert("starred".into(), starred.into());
+        }
+        if let Some(before) = query.before.as_deref().and_then(utc_timestamp) {
+            conditions.push("timestamp < $before".to_string());
+            vars.insert("before".into(), before.into());
+        }
+        if let Some(after) = query.after.as_deref().and_then(utc_timestamp) {
+            conditions.push("timestamp >= $after".to_string());
+            vars.insert("after".into(), after.into());
+        }
+        if let Some(folder) = &query.folder {
+            conditions.push("string::lowercase(folder) = $folder".to_string());
+            vars.insert("folder".into(), folder.clone().into());
+        }
+        vars.insert("limit".into(), limit.into());
+
+        let (ranking, order) = if has_text {
+            (
+                format!(
+                    "search::score(0) * 2 + search::score(1) AS score, search::highlight('{0}', '{1}', 0) AS subjectHighlight, search::highlight('{0}', '{1}', 1) AS bodyHighlight",
+                    search::HIGHLIGHT_START,
+                    search::HIGHLIGHT_END
+                ),
+                "score DESC",
+            )
+        } else {
+            ("0 AS score, timestamp".to_string(), "timestamp DESC")
+        };
+        let filter = if conditions.is_empty() { "true".to_string() } else { conditions.join(" AND ") };
+
         let mut result = self.db
-            .query(&format!("SELECT {} FROM email WHERE string::lowercase(subject) CONTAINS $query OR string::lowercase(body) CONTAINS $query OR string::lowercase(from.email) CONTAINS $query OR string::lowercase(from.name) CONTAINS $query ORDER BY date DESC", EMAIL_SELECT_FIELDS))
-            .bind(("query", query.to_lowercase()))
+            .query(format!(
+                "SELECT {}, {} FROM email WHERE {} ORDER BY {} LIMIT $limit",
+                EMAIL_SELECT_FIELDS, ranking, filter, order
+            ))
+            .bind(serde_json::Value::Object(vars))
             .await?;
-        
-        let emails: Vec<Email> = result.take(0)?;
-        Ok(emails)
+
+        let rows: Vec<SearchRow> = result.take(0)?;
+        Ok(rows
+            .into_iter()
+            .map(|row| SearchHit {
+                snippet: row
+                    .body_highlight
+                    .as_deref()
+                    .and_then(search::snippet)
+                    .or_else(|| row.subject_highlight.as_deref().and_then(search::snippet)),
+                score: row.score.unwrap_or_default(),
+                email: row.email,
+            })
+            .collect())
     }
-    
+
     pub async fn create_account(&self, account: &EmailAccount) -> Result<EmailAccount> {
+        self.store_password(account)?;
         let mut content = serde_json::to_value(account)?;
         if let Some(obj) = content.as_object_mut() {
             if let Some(id) = obj.remove("id") {
@@ -261,7 +1136,7 @@ impl Database {
     
     pub async fn get_account(&self, id: &str) -> Result<Option<EmailAccount>> {
         let mut result = self.db
-            .query("SELECT accountId AS id, email, name, imapServer, imapPort, smtpServer, smtpPort, username, password, useSsl, allowInvalidCerts, allowInvalidSmtpCerts, userId FROM account WHERE accountId = $id LIMIT 1")
+            .query(format!("SELECT {} FROM account WHERE accountId = $id LIMIT 1", ACCOUNT_SELECT_FIELDS))
             .bind(("id", id))
             .await?;
 
@@ -271,7 +1146,7 @@ impl Database {
 
     pub async fn get_account_by_email(&self, email: &str) -> Result<Option<EmailAccount>> {
         let mut result = self.db
-            .query("SELECT accountId AS id, email, name, imapServer, imapPort, smtpServer, smtpPort, username, password, useSsl, allowInvalidCerts, allowInvalidSmtpCerts, userId FROM account WHERE email = $email LIMIT 1")
+            .query(format!("SELECT {} FROM account WHERE email = $email LIMIT 1", ACCOUNT_SELECT_FIELDS))
             .bind(("email", email))
             .await?;
 
@@ -281,14 +1156,36 @@ impl Database {
     
     pub async fn get_all_accounts(&self) -> Result<Vec<EmailAccount>> {
         let mut result = self.db
-            .query("SELECT accountId AS id, email, name, imapServer, imapPort, smtpServer, smtpPort, username, password, useSsl, allowInvalidCerts, allowInvalidSmtpCerts, userId FROM account")
+            .query(format!("SELECT {} FROM account", ACCOUNT_SELECT_FIELDS))
             .await?;
 
         let accounts: Vec<EmailAccount> = result.take(0)?;
         Ok(accounts)
     }
     
+    /// Store the pinned certificate fingerprints of `account`, leaving the
+    /// rest of it alone.
+    pub async fn set_fingerprints(&self, account: &EmailAccount) -> Result<()> {
+        let mut pins = serde_json::Map::new();
+        if let Some(fingerprint) = &account.imap_fingerprint {
+            pins.insert("imapFingerprint".to_string(), fingerprint.clone().into());
+        }
+        if let Some(fingerprint) = &account.smtp_fingerprint {
+            pins.insert("smtpFingerprint".to_string(), fingerprint.clone().into());
+        }
+
+        self.db
+            .query("UPDATE account MERGE $pins WHERE accountId = $id")
+            .bind(("id", &account.id))
+            .bind(("pins", pins))
+            .await?
+            .check()?;
+        Ok(())
+    }
+
+    /// Update an account; its password is kept unless `account` carries a new one.
     pub async fn update_account(&self, account: &EmailAccount) -> Result<EmailAccount> {
+        self.store_password(account)?;
         let mut content = serde_json::to_value(account)?;
         if let Some(obj) = content.as_object_mut() {
             if let Some(id) = obj.remove("id") {
@@ -314,14 +1211,151 @@ impl Database {
             .bind(("id", id))
             .await?;
 
+        self.delete_emails_where("account_id = $id", serde_json::json!({ "id": id })).await?;
+
         self.db
-            .query("DELETE FROM email WHERE account_id = $id")
+            .query("DELETE sync_state WHERE accountId = $id")
             .bind(("id", id))
             .await?;
 
+        self.db
+            .query("DELETE outbox WHERE accountId = $id")
+            .bind(("id", id))
+            .await?;
+
+        self.db
+            .query("DELETE folder WHERE accountId = $id")
+            .bind(("id", id))
+            .await?;
+
+        self.secrets.delete(&secrets::password_key(id))?;
+        self.secrets.delete(&secrets::tokens_key(id))?;
+
+        Ok(())
+    }
+
+    pub async fn get_folders(&self, account_id: &str) -> Result<Vec<MailFolder>> {
+        let mut result = self.db
+            .query("SELECT accountId, path, name, delimiter, parent, specialUse, selectable, subscribed FROM folder WHERE accountId = $account ORDER BY path ASC")
+            .bind(("account", account_id))
+            .await?;
+
+        let folders: Vec<MailFolder> = result.take(0)?;
+        Ok(folders)
+    }
+
+    /// Replace the stored folder tree of an account with a fresh `LIST`.
+    pub async fn replace_folders(&self, account_id: &str, folders: &[MailFolder]) -> Result<()> {
+        self.db
+            .query("DELETE folder WHERE accountId = $account")
+            .bind(("account", account_id))
+            .await?;
+
+        for folder in folders {
+            let _: Vec<serde_json::Value> = self.db
+                .create("folder")
+                .content(folder)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_sync_state(&self, account_id: &str, folder: &str) -> Result<Option<FolderSyncState>> {
+        let mut result = self.db
+            .query("SELECT accountId, folder, uidValidity, lastUid, oldestUid, highestModSeq FROM sync_state WHERE accountId = $account AND folder = $folder LIMIT 1")
+            .bind(("account", account_id))
+            .bind(("folder", folder))
+            .await?;
+
+        let states: Vec<FolderSyncState> = result.take(0)?;
+        Ok(states.into_iter().next())
+    }
+
+    pub async fn save_sync_state(&self, state: &FolderSyncState) -> Result<()> {
+        let mut result = self.db
+            .query("UPDATE sync_state SET uidValidity = $data.uidValidity, lastUid = $data.lastUid, oldestUid = $data.oldestUid, highestModSeq = $data.highestModSeq WHERE accountId = $data.accountId AND folder = $data.folder")
+            .bind(("data", serde_json::to_value(state)?))
+            .await?;
+
+        let updated: Vec<serde_json::Value> = result.take(0)?;
+        if updated.is_empty() {
+            let _: Vec<serde_json::Value> = self.db
+                .create("sync_state")
+                .content(state)
+                .await?;
+        }
+
         Ok(())
     }
     
+    pub async fn enqueue_operation(&self, op: &PendingOperation) -> Result<()> {
+        let mut content = serde_json::to_value(op)?;
+        if let Some(obj) = content.as_object_mut() {
+            if let Some(id) = obj.remove("id") {
+                obj.insert("opId".to_string(), id);
+            }
+        }
+
+        let _: Vec<serde_json::Value> = self.db
+            .create("outbox")
+            .content(content)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Pending operations in the order they were queued.
+    pub async fn get_pending_operations(&self) -> Result<Vec<PendingOperation>> {
+        let mut result = self.db
+            .query("SELECT opId AS id, accountId, emailId, folder, uid, action, createdAt, attempts, lastError FROM outbox ORDER BY createdAt ASC")
+            .await?;
+
+        let ops: Vec<PendingOperation> = result.take(0)?;
+        Ok(ops)
+    }
+
+    pub async fn record_operation_failure(&self, id: &str, error: &str) -> Result<()> {
+        self.db
+            .query("UPDATE outbox SET attempts += 1, lastError = $error WHERE opId = $id")
+            .bind(("id", id))
+            .bind(("error", error))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_operation(&self, id: &str) -> Result<()> {
+        self.db
+            .query("DELETE outbox WHERE opId = $id")
+            .bind(("id", id))
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_operations_for_email(&self, email_id: &str) -> Result<()> {
+        self.db
+            .query("DELETE outbox WHERE emailId = $email_id")
+            .bind(("email_id", email_id))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Point queued operations at the new location of a message after a move.
+    pub async fn retarget_operations(&self, email_id: &str, new_email_id: &str, folder: &str, uid: u32) -> Result<()> {
+        self.db
+            .query("UPDATE outbox SET emailId = $new_email_id, folder = $folder, uid = $uid WHERE emailId = $email_id")
+            .bind(("email_id", email_id))
+            .bind(("new_email_id", new_email_id))
+            .bind(("folder", folder))
+            .bind(("uid", uid))
+            .await?;
+
+        Ok(())
+    }
+
     pub async fn get_unread_count(&self, folder: &str) -> Result<i64> {
         let mut result = self.db
             .query("SELECT count() FROM email WHERE folder = $folder AND read = false GROUP ALL")
@@ -353,10 +1387,75 @@ impl Database {
     }
 }
 
+#[derive(serde::Deserialize)]
+struct ListRow {
+    #[serde(flatten)]
+    item: EmailListItem,
+    #[serde(rename = "sortKey")]
+    sort_key: serde_json::Value,
+}
+
+#[derive(serde::Deserialize)]
+struct SearchRow {
+    #[serde(flatten)]
+    email: Email,
+    score: Option<f64>,
+    #[serde(rename = "subjectHighlight")]
+    subject_highlight: Option<String>,
+    #[serde(rename = "bodyHighlight")]
+    body_highlight: Option<String>,
+}
+
+/// Milliseconds since the epoch of an RFC 3339 (or RFC 2822) date.
+fn utc_timestamp(date: &str) -> Option<i64> {
+    chrono::DateTime::parse_from_rfc3339(date)
+        .or_else(|_| chrono::DateTime::parse_from_rfc2822(date))
+        .ok()
+        .map(|date| date.timestamp_millis())
+}
+
+/// The blobs `email` references, once per reference.
+fn blob_refs(email: &Email) -> Vec<String> {
+    let attachments = email.attachments.iter().flatten().filter_map(|a| a.blob.clone());
+    email.raw_blob.iter().cloned().chain(attachments).collect()
+}
+
+fn collect_strings(value: &serde_json::Value, out: &mut Vec<String>) {
+    match value {
+        serde_json::Value::String(s) => out.push(s.clone()),
+        serde_json::Value::Array(values) => values.iter().for_each(|v| collect_strings(v, out)),
+        serde_json::Value::Object(map) => map.values().for_each(|v| collect_strings(v, out)),
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::models::EmailAddress;
+
+    /// A fresh database, removed with the returned directory.
+    async fn test_db() -> (tempfile::TempDir, Database) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = Database::new(dir.path().join("test.db")).await.unwrap();
+        (dir, db)
+    }
+
+    /// An IMAPS and submission account for me@example.com.
+    fn test_account() -> EmailAccount {
+        EmailAccount::new(
+            "me@example.com".to_string(),
+            "Me".to_string(),
+            "imap.example.com".to_string(),
+            993,
+            "smtp.example.com".to_string(),
+            587,
+            "me".to_string(),
+            "secret".to_string(),
+            false,
+            false,
+        )
+    }
     
     #[tokio::test]
     async fn test_database_operations() {
@@ -387,4 +1486,564 @@ mod tests {
         let deleted = db.get_email(&email.id).await.unwrap();
         assert!(deleted.is_none());
     }
+
+    #[tokio::test]
+    async fn test_sync_state_upsert() {
+        let (_dir, db) = test_db().await;
+
+        assert!(db.get_sync_state("acc", "inbox").await.unwrap().is_none());
+
+        let mut state = FolderSyncState {
+            account_id: "acc".to_string(),
+            folder: "inbox".to_string(),
+            uid_validity: 42,
+            last_uid: 10,
+            oldest_uid: 1,
+            highest_mod_seq: None,
+        };
+        db.save_sync_state(&state).await.unwrap();
+
+        state.last_uid = 25;
+        db.save_sync_state(&state).await.unwrap();
+
+        let stored = db.get_sync_state("acc", "inbox").await.unwrap().unwrap();
+        assert_eq!(stored.uid_validity, 42);
+        assert_eq!(stored.last_uid, 25);
+        assert!(db.get_sync_state("acc", "sent").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_email_id_migration() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("test.db");
+        {
+            let db = Database::new(&path).await.unwrap();
+            db.save_sync_state(&FolderSyncState {
+                account_id: "acc".to_string(),
+                folder: "inbox".to_string(),
+                uid_validity: 42,
+                last_uid: 5,
+                oldest_uid: 1,
+                highest_mod_seq: None,
+            })
+            .await
+            .unwrap();
+            // Earlier versions keyed synced emails by account and UID only.
+            for (id, folder) in [("acc:5", "inbox"), ("acc:6", "all"), ("local", "drafts")] {
+                let mut email = Email::new(
+                    EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+                    vec![],
+                    "Subject".to_string(),
+                    "Body".to_string(),
+                    folder.to_string(),
+                );
+                email.id = id.to_string();
+                email.account_id = Some("acc".to_string());
+                db.create_email(&email).await.unwrap();
+            }
+        }
+
+        let db = Database::new(&path).await.unwrap();
+        let migrated = db.get_email("acc:inbox:42:5").await.unwrap().unwrap();
+        assert_eq!(migrated.imap_uid(), Some(5));
+        assert!(db.get_email("acc:5").await.unwrap().is_none());
+        assert!(db.get_email("acc:6").await.unwrap().is_none());
+        assert!(db.get_email("local").await.unwrap().is_some());
+        assert!(db.get_sync_state("acc", "inbox").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rename_folder() {
+        let (_dir, db) = test_db().await;
+
+        for folder in ["Projects", "Projects/2024", "ProjectsOld"] {
+            let mut email = Email::new(
+                EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+                vec![],
+                "Subject".to_string(),
+                "Body".to_string(),
+                folder.to_string(),
+            );
+            email.id = models::imap_email_id("acc", folder, 7, 1);
+            email.account_id = Some("acc".to_string());
+            db.create_email(&email).await.unwrap();
+        }
+
+        db.rename_folder("acc", "Projects", "Work", Some("/")).await.unwrap();
+
+        assert_eq!(db.get_folder_email_ids("acc", "Work").await.unwrap(), vec!["acc:Work:7:1".to_string()]);
+        assert_eq!(db.get_folder_email_ids("acc", "Work/2024").await.unwrap(), vec!["acc:Work/2024:7:1".to_string()]);
+        assert_eq!(db.get_folder_email_ids("acc", "ProjectsOld").await.unwrap(), vec!["acc:ProjectsOld:7:1".to_string()]);
+
+        db.delete_folder("acc", "Work/2024").await.unwrap();
+        assert!(db.get_email("acc:Work/2024:7:1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_thread_emails() {
+        let (_dir, db) = test_db().await;
+
+        // Replies stored before their original, and the same one in another account.
+        for (id, folder, references) in [("acc:2", "sent", Some("<1@x>")), ("acc:3", "trash", Some("<1@x>")), ("acc:1", "inbox", None), ("acc:4", "sent", None), ("bob:5", "sent", Some("<1@x>"))] {
+            let mut email = Email::new(
+                EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+                vec![],
+                "Subject".to_string(),
+                "Body".to_string(),
+                folder.to_string(),
+            );
+            email.id = id.to_string();
+            email.account_id = Some(id[..3].to_string());
+            email.message_id = Some(format!("<{}@x>", &id[4..]));
+            email.references = references.map(|r| vec![r.to_string()]);
+            let stored = db.create_email(&email).await.unwrap();
+            crate::threading::update_threads(&db, &[stored]).await.unwrap();
+        }
+
+        let mut ids: Vec<String> = db.get_thread_emails("inbox").await.unwrap().into_iter().map(|e| e.id).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["acc:1".to_string(), "acc:2".to_string()]);
+        let thread = |id: &'static str| async { db.get_email(id).await.unwrap().unwrap().thread_id };
+        assert_eq!(thread("acc:3").await.as_deref(), Some("acc:<1@x>"));
+        assert_eq!(thread("bob:5").await.as_deref(), Some("bob:<1@x>"));
+
+        // Threads of earlier versions were not scoped by account.
+        db.db.query("UPDATE email SET baseSubject = NONE, threadId = '<1@x>'").await.unwrap().check().unwrap();
+        db.migrate_threads().await.unwrap();
+        assert_eq!(thread("acc:1").await.as_deref(), Some("acc:<1@x>"));
+        assert_eq!(thread("acc:4").await.as_deref(), Some("acc:<4@x>"));
+        assert_eq!(thread("bob:5").await.as_deref(), Some("bob:<1@x>"));
+    }
+
+    #[tokio::test]
+    async fn test_blob_garbage_collection() {
+        use crate::models::Attachment;
+
+        let (temp_dir, db) = test_db().await;
+
+        // The same file received twice is stored once.
+        for id in ["acc:1", "acc:2"] {
+            let mut email = Email::new(
+                EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+                vec![],
+                "Subject".to_string(),
+                "Body".to_string(),
+                "inbox".to_string(),
+            );
+            email.id = id.to_string();
+            email.account_id = Some("acc".to_string());
+            email.raw = Some(id.as_bytes().to_vec());
+            email.attachments = Some(vec![Attachment {
+                id: "2".to_string(),
+                filename: "a.bin".to_string(),
+                size: 3,
+                mime_type: "application/octet-stream".to_string(),
+                content_id: None,
+                inline: false,
+                blob: None,
+                data: Some(vec![1, 2, 3]),
+            }]);
+            let stored = db.create_email(&email).await.unwrap();
+            assert!(stored.attachments.unwrap()[0].data.is_none());
+        }
+
+        let hash = BlobStore::hash(&[1, 2, 3]);
+        let blob_path = temp_dir.path().join("blobs").join(&hash[..2]).join(&hash[2..]);
+        let raw_hash = BlobStore::hash(b"acc:1");
+        let raw_path = temp_dir.path().join("blobs").join(&raw_hash[..2]).join(&raw_hash[2..]);
+
+        db.rekey_email("acc:1", "acc:9").await.unwrap();
+        let attachment = db.get_attachment("acc:9", "2").await.unwrap().unwrap();
+        assert_eq!(attachment.data, Some(vec![1, 2, 3]));
+
+        db.delete_email("acc:9").await.unwrap();
+        assert!(blob_path.exists());
+        assert!(!raw_path.exists());
+        let mut result = db.db.query("SELECT VALUE refs FROM blob WHERE hash = $hash").bind(("hash", &hash)).await.unwrap();
+        let refs: Vec<i64> = result.take(0).unwrap();
+        assert_eq!(refs, vec![1]);
+
+        db.delete_account("acc").await.unwrap();
+        assert!(!blob_path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_lazy_body() {
+        use crate::models::{Attachment, BodyPart};
+
+        let (temp_dir, db) = test_db().await;
+
+        let mut email = Email::new(
+            EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+            vec![],
+            String::new(),
+            String::new(),
+            "inbox".to_string(),
+        );
+        email.id = "acc:1".to_string();
+        email.body_loaded = false;
+        email.body_parts = Some(vec![BodyPart { section: "1".to_string(), mime_type: "text/plain".to_string() }]);
+        email.attachments = Some(vec![Attachment {
+            id: "2".to_string(),
+            filename: "a.bin".to_string(),
+            size: 3,
+            mime_type: "application/octet-stream".to_string(),
+            content_id: None,
+            inline: false,
+            blob: None,
+            data: None,
+        }]);
+        let stored = db.create_email(&email).await.unwrap();
+        assert!(!stored.body_loaded);
+        assert_eq!(stored.body_parts.unwrap()[0].section, "1");
+
+        db.set_email_body("acc:1", "Hello", None).await.unwrap();
+        let loaded = db.get_email("acc:1").await.unwrap().unwrap();
+        assert!(loaded.body_loaded);
+        assert_eq!(loaded.body, "Hello");
+
+        assert!(db.get_attachment("acc:1", "2").await.unwrap().unwrap().data.is_none());
+        db.set_attachment_data("acc:1", "2", &[1, 2, 3]).await.unwrap();
+        assert_eq!(db.get_attachment("acc:1", "2").await.unwrap().unwrap().data, Some(vec![1, 2, 3]));
+
+        let mut full = loaded.clone();
+        full.attachments = None;
+        full.raw = Some(b"raw".to_vec());
+        db.set_email_content("acc:1", &full).await.unwrap();
+        let hash = BlobStore::hash(&[1, 2, 3]);
+        assert!(!temp_dir.path().join("blobs").join(&hash[..2]).join(&hash[2..]).exists());
+    }
+
+    #[tokio::test]
+    async fn test_replace_email() {
+        use crate::models::Attachment;
+
+        let (temp_dir, db) = test_db().await;
+
+        let mut draft = Email::new(
+            EmailAddress { name: "Me".to_string(), email: "me@example.com".to_string() },
+            vec![],
+            "Brouillon".to_string(),
+            "Première version".to_string(),
+            "drafts".to_string(),
+        );
+        draft.attachments = Some(vec![Attachment {
+            id: "1".to_string(),
+            filename: "a.bin".to_string(),
+            size: 3,
+            mime_type: "application/octet-stream".to_string(),
+            content_id: None,
+            inline: false,
+            blob: None,
+            data: Some(vec![1, 2, 3]),
+        }]);
+        db.create_email(&draft).await.unwrap();
+
+        draft.body = "Deuxième version".to_string();
+        draft.attachments = Some(vec![Attachment {
+            id: "1".to_string(),
+            filename: "b.bin".to_string(),
+            size: 2,
+            mime_type: "application/octet-stream".to_string(),
+            content_id: None,
+            inline: false,
+            blob: None,
+            data: Some(vec![4, 5]),
+        }]);
+        let replaced = db.replace_email(&draft).await.unwrap();
+        assert_eq!(replaced.id, draft.id);
+        assert_eq!(replaced.body, "Deuxième version");
+        assert_eq!(db.get_attachment(&draft.id, "1").await.unwrap().unwrap().data, Some(vec![4, 5]));
+
+        let hash = BlobStore::hash(&[1, 2, 3]);
+        assert!(!temp_dir.path().join("blobs").join(&hash[..2]).join(&hash[2..]).exists());
+        assert!(db.replace_email(&Email { id: "missing".to_string(), ..draft }).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sent_message() {
+        let (temp_dir, db) = test_db().await;
+
+        let mut email = Email::new(
+            EmailAddress { name: "Me".to_string(), email: "me@example.com".to_string() },
+            vec![],
+            "Envoyé".to_string(),
+            "Body".to_string(),
+            "outbox".to_string(),
+        );
+        email.account_id = Some("acc".to_string());
+        db.create_email(&email).await.unwrap();
+        assert!(db.get_raw_message(&email.id).await.unwrap().is_none());
+
+        db.set_sent_message(&email.id, Some("<1@example.com>"), b"first").await.unwrap();
+        db.set_sent_message(&email.id, Some("<2@example.com>"), b"second").await.unwrap();
+        db.move_to_folder(&email.id, "sent").await.unwrap();
+        assert_eq!(db.get_raw_message(&email.id).await.unwrap(), Some(b"second".to_vec()));
+        let hash = BlobStore::hash(b"first");
+        assert!(!temp_dir.path().join("blobs").join(&hash[..2]).join(&hash[2..]).exists());
+
+        let found = db.find_by_message_id("acc", "sent", "<2@example.com>").await.unwrap();
+        assert_eq!(found, vec![email.id.clone()]);
+        assert!(db.find_by_message_id("acc", "inbox", "<2@example.com>").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_search_emails() {
+        let (_dir, db) = test_db().await;
+
+        let emails = [
+            ("acc:1", "alice@example.com", "Budget trimestriel", "Voici les budgets du trimestre.", false),
+            ("acc:2", "bob@example.com", "Déjeuner", "On parle du budget demain ?", true),
+            ("acc:3", "alice@example.com", "Vacances", "Rien à voir.", false),
+        ];
+        for (id, from, subject, body, read) in emails {
+            let mut email = Email::new(
+                EmailAddress { name: "Someone".to_string(), email: from.to_string() },
+                vec![EmailAddress { name: "Me".to_string(), email: "me@example.com".to_string() }],
+                subject.to_string(),
+                body.to_string(),
+                "inbox".to_string(),
+            );
+            email.id = id.to_string();
+            email.read = read;
+            db.create_email(&email).await.unwrap();
+        }
+
+        let hits = db.search_emails(&SearchQuery::parse("budget"), 10).await.unwrap();
+        let ids: Vec<&str> = hits.iter().map(|h| h.email.id.as_str()).collect();
+        assert_eq!(ids, vec!["acc:1", "acc:2"]);
+        assert!(hits[0].score > hits[1].score);
+        assert!(hits[1].snippet.as_deref().unwrap().contains("<mark>budget</mark>"));
+
+        let hits = db.search_emails(&SearchQuery::parse("from:alice is:unread to:me@"), 10).await.unwrap();
+        let mut ids: Vec<&str> = hits.iter().map(|h| h.email.id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["acc:1", "acc:3"]);
+
+        let hits = db.search_emails(&SearchQuery::parse("budget is:read in:inbox"), 10).await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].email.id, "acc:2");
+    }
+
+    #[tokio::test]
+    async fn test_list_emails() {
+        let (_dir, db) = test_db().await;
+
+        for i in 1..=5u32 {
+            let mut email = Email::new(
+                EmailAddress { name: format!("Sender {}", 6 - i), email: "s@example.com".to_string() },
+                vec![],
+                format!("Subject {}", i),
+                format!("Body\n\n  of   message {}", i),
+                "inbox".to_string(),
+            );
+            email.id = format!("acc:{}", i);
+            // Offsets vary so that, as strings, the dates sort backwards.
+            email.date = format!("2024-01-01T{}:00:00+0{}:00", 20 - i, 10 - 2 * i);
+            email.size = Some(i * 100);
+            email.read = i % 2 == 0;
+            db.create_email(&email).await.unwrap();
+        }
+
+        let mut query = EmailListQuery { folder: "inbox".to_string(), limit: Some(2), ..Default::default() };
+        let mut ids = Vec::new();
+        loop {
+            let page = db.list_emails(&query).await.unwrap();
+            ids.extend(page.items.iter().map(|item| item.id.clone()));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        assert_eq!(ids, vec!["acc:5", "acc:4", "acc:3", "acc:2", "acc:1"]);
+
+        let page = db
+            .list_emails(&EmailListQuery { folder: "inbox".to_string(), sort: EmailSort::Sender, ascending: true, unread: true, ..Default::default() })
+            .await
+            .unwrap();
+        let ids: Vec<&str> = page.items.iter().map(|item| item.id.as_str()).collect();
+        assert_eq!(ids, vec!["acc:5", "acc:3", "acc:1"]);
+        assert_eq!(page.items[0].preview, "Body of message 5");
+        assert!(!page.items[0].has_attachments);
+        assert!(page.next_cursor.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_account_identities() {
+        use crate::models::Identity;
+
+        let (_dir, db) = test_db().await;
+
+        let mut account = test_account();
+        account.signature = Some("Moi".to_string());
+        account.identities.push(Identity {
+            email: "support@example.com".to_string(),
+            name: "Support".to_string(),
+            signature: Some("L'équipe support".to_string()),
+        });
+        db.create_account(&account).await.unwrap();
+
+        let stored = db.get_account(&account.id).await.unwrap().unwrap();
+        assert_eq!(stored.identities, account.identities);
+        assert_eq!(stored.identity_for("ME@example.com").unwrap().name, "Me");
+        assert_eq!(stored.identity_for("me@example.com").unwrap().signature.as_deref(), Some("Moi"));
+        assert_eq!(stored.identity_for("support@example.com").unwrap().signature.as_deref(), Some("L'équipe support"));
+        assert!(stored.identity_for("other@example.com").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_set_fingerprints() {
+        let (_dir, db) = test_db().await;
+
+        let mut account = test_account();
+        account.allow_invalid_certs = true;
+        account.allow_invalid_smtp_certs = true;
+        account.smtp_fingerprint = Some("AB:CD".to_string());
+        db.create_account(&account).await.unwrap();
+
+        account.name = "Renamed".to_string();
+        account.imap_fingerprint = Some("01:23".to_string());
+        account.smtp_fingerprint = None;
+        db.set_fingerprints(&account).await.unwrap();
+
+        let stored = db.get_account(&account.id).await.unwrap().unwrap();
+        assert_eq!(stored.name, "Me");
+        assert_eq!(stored.imap_fingerprint.as_deref(), Some("01:23"));
+        assert_eq!(stored.smtp_fingerprint.as_deref(), Some("AB:CD"));
+    }
+
+    #[tokio::test]
+    async fn test_account_password() {
+        use crate::secrets::password_key;
+
+        let (_dir, db) = test_db().await;
+
+        let mut account = test_account();
+        let stored = db.create_account(&account).await.unwrap();
+        assert!(stored.password.is_empty());
+        assert!(!serde_json::to_string(&stored).unwrap().contains("secret"));
+        let key = password_key(&account.id);
+        assert_eq!(db.secrets().get(&key).unwrap().as_deref(), Some("secret"));
+
+        // An update without a password keeps the stored one.
+        account.password.clear();
+        account.name = "Moi".to_string();
+        db.update_account(&account).await.unwrap();
+        assert_eq!(db.secrets().get(&key).unwrap().as_deref(), Some("secret"));
+
+        // Passwords left in the table by earlier versions are moved out.
+        db.db
+            .query("UPDATE account SET password = 'legacy' WHERE accountId = $id")
+            .bind(("id", &account.id))
+            .await
+            .unwrap();
+        db.migrate_passwords().await.unwrap();
+        assert_eq!(db.secrets().get(&key).unwrap().as_deref(), Some("legacy"));
+        let mut result = db.db.query("SELECT VALUE password FROM account").await.unwrap();
+        let left: Vec<Option<String>> = result.take(0).unwrap();
+        assert_eq!(left, vec![None]);
+
+        db.delete_account(&account.id).await.unwrap();
+        assert_eq!(db.secrets().get(&key).unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_account_auth() {
+        use crate::models::{AuthMethod, OAuth2Config, SaslMechanism};
+
+        let (_dir, db) = test_db().await;
+
+        let mut account = test_account();
+        account.password.clear();
+        let stored = db.create_account(&account).await.unwrap();
+        assert_eq!(stored.auth, AuthMethod::Password);
+
+        account.auth = AuthMethod::OAuth2(OAuth2Config {
+            auth_url: "https://auth.example.com/authorize".to_string(),
+            token_url: "https://auth.example.com/token".to_string(),
+            client_id: "missive".to_string(),
+            client_secret: None,
+            scopes: vec!["mail".to_string()],
+            mechanism: SaslMechanism::Oauthbearer,
+        });
+        let stored = db.update_account(&account).await.unwrap();
+        assert_eq!(stored.auth, account.auth);
+    }
+
+    #[tokio::test]
+    async fn test_account_security() {
+        let (_dir, db) = test_db().await;
+
+        let mut account = test_account();
+        account.password.clear();
+        account.smtp_security = Security::Tls;
+        let stored = db.create_account(&account).await.unwrap();
+        assert_eq!(stored.imap_security, Security::Tls);
+        assert_eq!(stored.smtp_security, Security::Tls);
+
+        // Accounts of earlier versions only had `useSsl`.
+        let legacy = |use_ssl: bool, smtp_port: i64| {
+            db.db
+                .query("UPDATE account SET useSsl = $ssl, smtpPort = $port, imapSecurity = NONE, smtpSecurity = NONE WHERE accountId = $id")
+                .bind(("id", account.id.clone()))
+                .bind(("ssl", use_ssl))
+                .bind(("port", smtp_port))
+        };
+        legacy(true, 587).await.unwrap().check().unwrap();
+        db.migrate_security().await.unwrap();
+        let stored = db.get_account(&account.id).await.unwrap().unwrap();
+        assert_eq!((stored.imap_security, stored.smtp_security), (Security::Tls, Security::StartTls));
+
+        legacy(false, 25).await.unwrap().check().unwrap();
+        db.migrate_security().await.unwrap();
+        let stored = db.get_account(&account.id).await.unwrap().unwrap();
+        assert_eq!((stored.imap_security, stored.smtp_security), (Security::Tls, Security::None));
+
+        // A bridge listening on 1143 takes STARTTLS, whatever `useSsl` says.
+        db.db.query("UPDATE account SET imapPort = 1143").await.unwrap().check().unwrap();
+        legacy(false, 1025).await.unwrap().check().unwrap();
+        db.migrate_security().await.unwrap();
+        let stored = db.get_account(&account.id).await.unwrap().unwrap();
+        assert_eq!((stored.imap_security, stored.smtp_security), (Security::StartTls, Security::None));
+        let mut result = db.db.query("SELECT VALUE useSsl FROM account").await.unwrap();
+        let left: Vec<Option<bool>> = result.take(0).unwrap();
+        assert_eq!(left, vec![None]);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_queue() {
+        use crate::models::{PendingAction, PendingOperation};
+
+        let (_dir, db) = test_db().await;
+
+        let mut email = Email::new(
+            EmailAddress { name: "Test".to_string(), email: "test@example.com".to_string() },
+            vec![],
+            "Subject".to_string(),
+            "Body".to_string(),
+            "inbox".to_string(),
+        );
+        email.id = "acc:7".to_string();
+        email.account_id = Some("acc".to_string());
+
+        let first = PendingOperation::for_message(&email, 7, PendingAction::SetRead { read: true }).unwrap();
+        let second = PendingOperation::for_message(&email, 7, PendingAction::Move { target: "trash".to_string() }).unwrap();
+        db.enqueue_operation(&first).await.unwrap();
+        db.enqueue_operation(&second).await.unwrap();
+
+        db.record_operation_failure(&first.id, "boom").await.unwrap();
+        db.retarget_operations("acc:7", "acc:12", "trash", 12).await.unwrap();
+
+        let ops = db.get_pending_operations().await.unwrap();
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].id, first.id);
+        assert_eq!(ops[0].attempts, 1);
+        assert_eq!(ops[0].last_error.as_deref(), Some("boom"));
+        assert!(matches!(ops[1].action, PendingAction::Move { ref target } if target == "trash"));
+        assert_eq!(ops[1].uid, Some(12));
+        assert_eq!(ops[1].email_id.as_deref(), Some("acc:12"));
+
+        db.delete_operations_for_email("acc:12").await.unwrap();
+        assert!(db.get_pending_operations().await.unwrap().is_empty());
+    }
 }
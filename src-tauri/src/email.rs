@@ -1,13 +1,29 @@
-use crate::models::{Email, EmailAccount, EmailAddress, NewEmail};
+use crate::models::{self, Attachment, AuthMethod, BodyPart, Email, EmailAccount, EmailAddress, FolderSyncState, MailFolder, NewEmail, SaslMechanism, Security};
+use crate::compose;
+use crate::oauth::{self, OAuthError};
+use crate::pool::SessionPool;
+use crate::secrets::{self, SecretStore};
+use crate::threading;
 use lettre::{
-    message::{header::ContentType, Mailbox, MultiPart, SinglePart},
-    transport::smtp::authentication::Credentials,
-    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
-    transport::smtp::client::{Tls, TlsParametersBuilder},
+    message::{header::ContentType, Attachment as MimeAttachment, Mailbox, MultiPart, SinglePart},
+    transport::smtp::authentication::{Credentials, Mechanism, DEFAULT_MECHANISMS},
+    transport::smtp::client::{AsyncSmtpConnection, TlsParameters},
+    transport::smtp::extension::ClientId,
+    Message,
 };
-use imap::ClientBuilder;
-use mailparse::{addrparse, parse_mail, dateparse, MailAddr, ParsedMail, MailHeaderMap};
+use imap::extensions::idle::WaitOutcome;
+use imap::types::{Fetch, Flag, UnsolicitedResponse};
+use imap_proto::types::{BodyStructure, ContentEncoding, MessageSection, SectionPath};
+use imap_proto::{NameAttribute, UidSetMember};
+use mailparse::{addrparse, parse_content_disposition, parse_content_type, parse_header, parse_mail, dateparse, DispositionType, MailAddr, ParsedMail, MailHeaderMap};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
 
 #[derive(Error, Debug)]
 pub enum EmailError {
@@ -21,362 +37,1678 @@ pub enum EmailError {
     ConnectionError(String),
     #[error("Authentication error: {0}")]
     AuthError(String),
+    #[error("Message not found on server: {0}")]
+    MessageNotFound(String),
+    /// The server presented another certificate than the one pinned for it,
+    /// and the new one does not verify either.
+    #[error("Certificate changed for {host}: expected {pinned}, got {found}")]
+    CertificateChanged { host: String, pinned: String, found: String },
 }
 
 pub type Result<T> = std::result::Result<T, EmailError>;
 
+type ImapClient = imap::Client<imap::Connection>;
+pub type ImapSession = imap::Session<imap::Connection>;
+
+/// How a connection authenticates, resolved when it is opened.
+enum Login {
+    Password(String),
+    /// An OAuth2 access token, presented with a SASL mechanism.
+    Token(SaslMechanism, String),
+}
+
+/// Give up on an SMTP server that does not answer for this long.
+const SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// IDLE is re-issued this often, well under the 29 minutes of RFC 2177.
+const IDLE_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+/// Polling interval for servers without IDLE.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What is downloaded for each message at sync: headers and structure, no body.
+const SUMMARY_FETCH: &str = "(UID FLAGS RFC822.SIZE BODYSTRUCTURE BODY.PEEK[HEADER])";
+
+pub struct FlagUpdate {
+    pub uid: u32,
+    pub read: bool,
+    pub starred: bool,
+}
+
+/// UID of a message stored or moved on the server, with the UIDVALIDITY of
+/// its mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerUid {
+    pub uid_validity: u32,
+    pub uid: u32,
+}
+
+/// Outcome of an incremental fetch of one folder.
+pub struct FolderSync {
+    pub emails: Vec<Email>,
+    /// Current flags of already stored messages that may have changed.
+    pub flags: Vec<FlagUpdate>,
+    /// Every UID present in the folder and not flagged `\Deleted`, to detect
+    /// expunged messages.
+    ///
+    /// The full `UID SEARCH UNDELETED` is needed for backfilling anyway, so
+    /// diffing against it costs nothing extra compared to QRESYNC `VANISHED`.
+    pub server_uids: Vec<u32>,
+    pub state: FolderSyncState,
+    /// The server reset UIDVALIDITY; locally stored UIDs for the folder are stale.
+    pub uid_validity_changed: bool,
+}
+
+/// Stops a `watch_mailbox` loop, cutting short the IDLE or poll it waits in.
+#[derive(Default)]
+pub struct WatchStop {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    /// Wakes async waiters in `stopped`.
+    notify: tokio::sync::Notify,
+    /// The connection of the watching session, shut down to end IDLE.
+    socket: Mutex<Option<TcpStream>>,
+}
+
+impl WatchStop {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stop(&self) {
+        *self.stopped.lock().unwrap() = true;
+        self.wake.notify_all();
+        self.notify.notify_waiters();
+        if let Some(socket) = self.socket.lock().unwrap().take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap()
+    }
+
+    /// Resolve once stopped, for async waits.
+    pub async fn stopped(&self) {
+        let notified = self.notify.notified();
+        tokio::pin!(notified);
+        // Registered before checking, so a stop in between is not missed.
+        notified.as_mut().enable();
+        if !self.is_stopped() {
+            notified.await;
+        }
+    }
+
+    /// Wait for `timeout`, or until stopped. Returns whether stopped.
+    fn sleep(&self, timeout: Duration) -> bool {
+        let stopped = self.stopped.lock().unwrap();
+        *self.wake.wait_timeout_while(stopped, timeout, |stopped| !*stopped).unwrap().0
+    }
+
+    /// Shut `socket` down on stop, or right away when already stopped.
+    fn attach(&self, socket: TcpStream) {
+        let mut slot = self.socket.lock().unwrap();
+        if self.is_stopped() {
+            let _ = socket.shutdown(Shutdown::Both);
+        } else {
+            *slot = Some(socket);
+        }
+    }
+}
+
+/// A part downloaded with `BODY.PEEK[section]`, decoded.
+pub struct FetchedPart {
+    pub section: String,
+    pub data: Vec<u8>,
+    /// Charset-decoded content of `text/*` parts.
+    pub text: Option<String>,
+}
+
+#[derive(Clone)]
 pub struct EmailClient {
     account: EmailAccount,
+    folders: Vec<MailFolder>,
+    secrets: Arc<dyn SecretStore>,
+    pool: Option<Arc<SessionPool>>,
 }
 
 impl EmailClient {
-    pub fn new(account: EmailAccount) -> Self {
-        Self { account }
+    /// A client for `account`, whose password is looked up in `secrets`
+    /// unless the account carries one (e.g. while it is being set up).
+    pub fn new(account: EmailAccount, secrets: Arc<dyn SecretStore>) -> Self {
+        Self { account, folders: Vec::new(), secrets, pool: None }
     }
-    
-    /// Test connection to IMAP and SMTP servers
-    pub async fn test_connection(&self) -> Result<()> {
-        // Test IMAP + SMTP
-        self.test_imap_connection().await?;
-        self.test_smtp_connection().await?;
-        
-        Ok(())
+
+    /// Borrow IMAP sessions from `pool` instead of logging in for every
+    /// operation.
+    pub fn with_pool(mut self, pool: Arc<SessionPool>) -> Self {
+        self.pool = Some(pool);
+        self
     }
 
-    pub async fn test_imap(&self) -> Result<()> {
+    /// Use the discovered folder tree to resolve logical folder names.
+    pub fn with_folders(mut self, folders: Vec<MailFolder>) -> Self {
+        self.folders = folders;
+        self
+    }
+
+    /// Map a logical folder name (`inbox`, `sent`, ... or a mailbox path) to
+    /// the mailbox name used on the server.
+    pub fn mailbox_name(&self, folder: &str) -> String {
+        if let Some(found) = self
+            .folders
+            .iter()
+            .find(|f| f.special_use.as_deref() == Some(folder))
+            .or_else(|| self.folders.iter().find(|f| f.path == folder))
+        {
+            return found.path.clone();
+        }
+
+        // Folder tree not discovered yet: best guess.
+        match folder {
+            "inbox" => "INBOX",
+            "sent" => "Sent",
+            "drafts" => "Drafts",
+            "trash" => "Trash",
+            other => other,
+        }
+        .to_string()
+    }
+
+    /// List the mailboxes of the account with their RFC 6154 special use.
+    ///
+    /// Servers without SPECIAL-USE get their roles guessed from common
+    /// (and localized) mailbox names.
+    pub async fn list_folders(&self) -> Result<Vec<MailFolder>> {
+        let account_id = self.account.id.clone();
+        self.with_session(move |session| {
+            let subscribed: HashSet<String> = session
+                .lsub(Some(""), Some("*"))
+                .map_err(imap_error)?
+                .iter()
+                .map(|name| name.name().to_string())
+                .collect();
+            let names = session
+                .list(Some(""), Some("*"))
+                .map_err(imap_error)?;
+
+            let mut folders: Vec<MailFolder> = names
+                .iter()
+                .map(|name| {
+                    let path = name.name().to_string();
+                    let delimiter = name.delimiter().map(str::to_string);
+                    let (parent, leaf) = match delimiter.as_deref().and_then(|d| path.rsplit_once(d)) {
+                        Some((parent, leaf)) => (Some(parent.to_string()), decode_mailbox_name(leaf)),
+                        None => (None, decode_mailbox_name(&path)),
+                    };
+                    let special_use = if path.eq_ignore_ascii_case("INBOX") {
+                        Some("inbox".to_string())
+                    } else {
+                        name.attributes().iter().find_map(special_use_name).map(str::to_string)
+                    };
+                    MailFolder {
+                        account_id: account_id.clone(),
+                        subscribed: subscribed.contains(&path),
+                        selectable: !name.attributes().contains(&NameAttribute::NoSelect),
+                        path,
+                        name: leaf,
+                        delimiter,
+                        parent,
+                        special_use,
+                    }
+                })
+                .collect();
+
+            guess_special_use(&mut folders);
+            Ok(folders)
+        })
+        .await
+    }
+    
+    /// Test connection to IMAP and SMTP servers
+    pub async fn test_imap(&self) -> Result<Option<String>> {
         self.test_imap_connection().await
     }
 
-    pub async fn test_smtp(&self) -> Result<()> {
+    pub async fn test_smtp(&self) -> Result<Option<String>> {
         self.test_smtp_connection().await
     }
     
-    async fn test_smtp_connection(&self) -> Result<()> {
-        let mailer = self.build_smtp_transport()?;
-        
-        mailer
-            .test_connection()
-            .await
-            .map_err(|e| EmailError::SmtpError(e.to_string()))?;
-        
-        Ok(())
+    /// Log in to SMTP, trusting a certificate that does not verify when the
+    /// account allows it. Returns the fingerprint of such a certificate.
+    async fn test_smtp_connection(&self) -> Result<Option<String>> {
+        let (mut connection, fingerprint) = self.smtp_connection(true).await?;
+        let _ = connection.quit().await;
+        Ok(fingerprint)
     }
 
-    async fn test_imap_connection(&self) -> Result<()> {
+    /// Like `test_smtp_connection`, for IMAP.
+    async fn test_imap_connection(&self) -> Result<Option<String>> {
         let account = self.account.clone();
+        let login = self.login().await?;
         tokio::task::spawn_blocking(move || {
-            let client = ClientBuilder::new(account.imap_server.clone(), account.imap_port)
-                .danger_skip_tls_verify(account.allow_invalid_certs)
-                .connect()
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
-
-            let username = account.username.clone();
-            let server = account.imap_server.clone();
-            let mut session = client
-                .login(account.username, account.password)
-                .map_err(|e| {
-                    log::error!("IMAP login failed server={} user={} err={}", server, username, e.0);
-                    EmailError::AuthError(format!("IMAP AUTH failed: {}", e.0))
-                })?;
+            let (mut session, fingerprint) = open_session(&account, &login, true, None)?;
+            let _ = session.logout();
+            Ok(fingerprint)
+        })
+        .await
+        .map_err(|e| EmailError::ConnectionError(e.to_string()))?
+    }
 
-            session
-                .logout()
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
+    /// Run `f` on a blocking thread against a session from the pool, or a
+    /// freshly authenticated one when the client has no pool.
+    ///
+    /// A session that failed with a connection error is not reused. When it
+    /// was an idle one, which the server may have dropped in the meantime,
+    /// `f` is retried once on a new session.
+    async fn with_session<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut(&mut ImapSession) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let Some(pool) = self.pool.clone() else {
+            return self.with_own_session(f).await;
+        };
+        let client = self.clone();
+        let runtime = tokio::runtime::Handle::current();
+        tokio::task::spawn_blocking(move || {
+            // Credentials (and OAuth2 token refreshes) are only needed for a new connection.
+            let connect = || {
+                let login = runtime.block_on(client.login())?;
+                open_session(&client.account, &login, false, None).map(|(session, _)| session)
+            };
+            let mut session = pool.get(&client.account, connect)?;
+            let result = f(&mut session);
+            if !matches!(result, Err(EmailError::ConnectionError(_))) {
+                return result;
+            }
+            session.discard();
+            if !session.reused() {
+                return result;
+            }
+            drop(session);
 
-            Ok::<(), EmailError>(())
+            let mut session = pool.get_new(&client.account, connect)?;
+            let result = f(&mut session);
+            if matches!(result, Err(EmailError::ConnectionError(_))) {
+                session.discard();
+            }
+            result
         })
         .await
-        .map_err(|e| EmailError::ConnectionError(e.to_string()))??;
+        .map_err(|e| EmailError::ConnectionError(e.to_string()))?
+    }
 
-        Ok(())
+    /// Run `f` against a freshly authenticated IMAP session on a blocking
+    /// thread, outside the pool.
+    async fn with_own_session<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut ImapSession) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let account = self.account.clone();
+        let login = self.login().await?;
+        tokio::task::spawn_blocking(move || {
+            let (mut session, _) = open_session(&account, &login, false, None)?;
+            let result = f(&mut session);
+            let _ = session.logout();
+            result
+        })
+        .await
+        .map_err(|e| EmailError::ConnectionError(e.to_string()))?
     }
     
-    /// Fetch emails from IMAP server
-    /// Note: IMAP functionality is temporarily disabled due to async runtime compatibility issues
-    pub async fn fetch_emails(&self, folder: &str, _limit: u32) -> Result<Vec<Email>> {
+    /// Fetch the messages of `folder` that are not stored locally yet.
+    ///
+    /// Only headers and `BODYSTRUCTURE` are downloaded; bodies come later
+    /// through `fetch_parts` or `fetch_message`. With no previous state, only the newest `batch_size` messages are
+    /// downloaded. Afterwards every UID above `last_uid` is fetched, plus one
+    /// batch of older messages below `oldest_uid`, so the folder is mirrored
+    /// progressively. A UIDVALIDITY change restarts from an empty state.
+    pub async fn fetch_new_emails(
+        &self,
+        folder: &str,
+        state: Option<FolderSyncState>,
+        batch_size: usize,
+    ) -> Result<FolderSync> {
         let account = self.account.clone();
         let folder = folder.to_string();
-        let limit = _limit.max(1) as usize;
-
-        let emails = tokio::task::spawn_blocking(move || {
-            let client = ClientBuilder::new(account.imap_server.clone(), account.imap_port)
-                .danger_skip_tls_verify(account.allow_invalid_certs)
-                .connect()
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
-
-            let username = account.username.clone();
-            let server = account.imap_server.clone();
-            let mut session = client
-                .login(account.username, account.password)
-                .map_err(|e| {
-                    log::error!("IMAP login failed server={} user={} err={}", server, username, e.0);
-                    EmailError::AuthError(format!("IMAP AUTH failed: {}", e.0))
-                })?;
-
-            let imap_folder = match folder.as_str() {
-                "inbox" => "INBOX",
-                "sent" => "Sent",
-                "drafts" => "Drafts",
-                "trash" => "Trash",
-                other => other,
-            };
+        let mailbox = self.mailbox_name(&folder);
+        let batch_size = batch_size.max(1);
 
-            session
-                .select(imap_folder)
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
+        self.with_session(move |session| {
+            let mailbox = session
+                .select(&mailbox)
+                .map_err(imap_error)?;
+            let uid_validity = mailbox.uid_validity.unwrap_or(0);
+
+            let uid_validity_changed = state
+                .as_ref()
+                .is_some_and(|s| s.uid_validity != uid_validity);
+            let previous = state.clone().filter(|s| s.uid_validity == uid_validity);
 
+            // Messages flagged \Deleted but not expunged count as gone.
             let uids_set = session
-                .uid_search("ALL")
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
+                .uid_search("UNDELETED")
+                .map_err(imap_error)?;
             let mut uids: Vec<u32> = uids_set.into_iter().collect();
             uids.sort_unstable();
 
-            if uids.is_empty() {
-                let _ = session.logout();
-                return Ok::<Vec<Email>, EmailError>(vec![]);
+            let mut next_state = FolderSyncState {
+                account_id: account.id.clone(),
+                folder: folder.clone(),
+                uid_validity,
+                last_uid: 0,
+                oldest_uid: 0,
+                highest_mod_seq: mailbox.highest_mod_seq,
+            };
+
+            let flags = match &previous {
+                Some(prev) if prev.last_uid > 0 => {
+                    let condstore = session
+                        .capabilities()
+                        .map_err(imap_error)?
+                        .has_str("CONDSTORE");
+                    fetch_flag_changes(session, prev, mailbox.highest_mod_seq.filter(|_| condstore))?
+                }
+                _ => vec![],
+            };
+
+            let wanted: Vec<u32> = match &previous {
+                Some(prev) => {
+                    next_state.last_uid = prev.last_uid;
+                    next_state.oldest_uid = prev.oldest_uid;
+                    let older: Vec<u32> = uids.iter().copied().filter(|u| *u < prev.oldest_uid).collect();
+                    let backfill = &older[older.len().saturating_sub(batch_size)..];
+                    backfill
+                        .iter()
+                        .copied()
+                        .chain(uids.iter().copied().filter(|u| *u > prev.last_uid))
+                        .collect()
+                }
+                None => uids[uids.len().saturating_sub(batch_size)..].to_vec(),
+            };
+
+            let mut emails = Vec::new();
+            for chunk in wanted.chunks(batch_size) {
+                let sequence = chunk
+                    .iter()
+                    .map(|u| u.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+
+                let fetches = session
+                    .uid_fetch(sequence, SUMMARY_FETCH)
+                    .map_err(imap_error)?;
+
+                for msg in fetches.iter() {
+                    let uid = match msg.uid {
+                        Some(uid) => uid,
+                        None => continue,
+                    };
+                    next_state.last_uid = next_state.last_uid.max(uid);
+                    next_state.oldest_uid = if next_state.oldest_uid == 0 {
+                        uid
+                    } else {
+                        next_state.oldest_uid.min(uid)
+                    };
+
+                    match parse_message(&account, &folder, uid_validity, uid, msg) {
+                        Ok(email) => emails.push(email),
+                        Err(e) => log::warn!("Skipping message uid={} folder={}: {}", uid, folder, e),
+                    }
+                }
             }
 
-            let start = uids.len().saturating_sub(limit);
-            let slice = &uids[start..];
-            let sequence = slice
-                .iter()
-                .map(|u: &u32| u.to_string())
-                .collect::<Vec<_>>()
-                .join(",");
+            Ok(FolderSync {
+                emails,
+                flags,
+                server_uids: uids,
+                state: next_state,
+                uid_validity_changed,
+            })
+        })
+        .await
+    }
+    
+    /// Run `UID SEARCH` on `folder` and fetch the headers of the newest `limit` hits.
+    pub async fn search(&self, folder: &str, criteria: &str, limit: usize) -> Result<Vec<Email>> {
+        let account = self.account.clone();
+        let folder = folder.to_string();
+        let mailbox = self.mailbox_name(&folder);
+        let criteria = criteria.to_string();
+        self.with_session(move |session| {
+            let uid_validity = session
+                .examine(&mailbox)
+                .map_err(imap_error)?
+                .uid_validity
+                .unwrap_or(0);
+
+            // 8-bit criteria are sent as non-synchronizing literals.
+            if !criteria.is_ascii() {
+                let capabilities = session.capabilities().map_err(imap_error)?;
+                if !capabilities.has_str("LITERAL+") && !capabilities.has_str("LITERAL-") {
+                    return Err(EmailError::ImapError("Server cannot search for non-ASCII text".to_string()));
+                }
+            }
+
+            let mut uids: Vec<u32> = session
+                .uid_search(&criteria)
+                .map_err(imap_error)?
+                .into_iter()
+                .collect();
+            uids.sort_unstable();
+            let newest = &uids[uids.len().saturating_sub(limit)..];
+            if newest.is_empty() {
+                return Ok(vec![]);
+            }
 
+            let sequence = newest.iter().map(|u| u.to_string()).collect::<Vec<_>>().join(",");
             let fetches = session
-                .uid_fetch(sequence, "(UID FLAGS RFC822)")
-                .map_err(|e| EmailError::ImapError(e.to_string()))?;
+                .uid_fetch(sequence, SUMMARY_FETCH)
+                .map_err(imap_error)?;
 
-            let mut result = Vec::new();
+            let mut emails = Vec::new();
             for msg in fetches.iter() {
-                let uid = msg.uid.ok_or_else(|| EmailError::ParseError("Missing UID".into()))?;
-                let raw = msg.body().ok_or_else(|| EmailError::ParseError("Empty message body".into()))?;
-                let parsed = parse_mail(raw).map_err(|e| EmailError::ParseError(e.to_string()))?;
-
-                let subject = header_value(&parsed, "Subject").unwrap_or_else(|| "(Sans objet)".to_string());
-                let from_list = parse_addresses(&parsed, "From");
-                let to_list = parse_addresses(&parsed, "To");
-                let cc_list = parse_addresses(&parsed, "Cc");
-                let bcc_list = parse_addresses(&parsed, "Bcc");
-
-                let from = from_list
-                    .first()
-                    .cloned()
-                    .unwrap_or(EmailAddress { name: "".into(), email: "".into() });
-
-                let date = header_value(&parsed, "Date")
-                    .and_then(|d| dateparse(&d).ok())
-                    .and_then(|ts| {
-                        if ts >= 0 {
-                            Some(chrono::DateTime::<chrono::Utc>::from(
-                                std::time::UNIX_EPOCH + std::time::Duration::from_secs(ts as u64),
-                            ).to_rfc3339())
-                        } else {
-                            None
-                        }
-                    })
-                    .unwrap_or_else(|| chrono::Utc::now().to_rfc3339());
+                let uid = match msg.uid {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+                match parse_message(&account, &folder, uid_validity, uid, msg) {
+                    Ok(email) => emails.push(email),
+                    Err(e) => log::warn!("Skipping search hit uid={} folder={}: {}", uid, folder, e),
+                }
+            }
+            Ok(emails)
+        })
+        .await
+    }
 
-                let body = find_body(&parsed, "text/plain").unwrap_or_default();
-                let html_body = find_body(&parsed, "text/html");
+    /// Download one whole message, attachments included.
+    pub async fn fetch_message(&self, folder: &str, uid: u32) -> Result<Email> {
+        let account = self.account.clone();
+        let folder = folder.to_string();
+        let mailbox = self.mailbox_name(&folder);
+        self.with_session(move |session| {
+            let uid_validity = session
+                .examine(&mailbox)
+                .map_err(imap_error)?
+                .uid_validity
+                .unwrap_or(0);
+            ensure_uid_exists(session, uid)?;
 
-                let message_id = header_value(&parsed, "Message-ID");
+            let fetches = session
+                .uid_fetch(uid.to_string(), "(UID FLAGS RFC822.SIZE BODY.PEEK[])")
+                .map_err(imap_error)?;
+            let msg = fetches
+                .iter()
+                .find(|msg| msg.uid == Some(uid))
+                .ok_or_else(|| EmailError::MessageNotFound(format!("UID {}", uid)))?;
+            parse_message(&account, &folder, uid_validity, uid, msg)
+        })
+        .await
+    }
 
-                let read = msg
-                    .flags()
-                    .iter()
-                    .any(|f| matches!(f, imap::types::Flag::Seen));
-
-                let email = Email {
-                    id: format!("{}:{}", account.id, uid),
-                    from,
-                    to: if to_list.is_empty() { vec![] } else { to_list },
-                    cc: if cc_list.is_empty() { None } else { Some(cc_list) },
-                    bcc: if bcc_list.is_empty() { None } else { Some(bcc_list) },
-                    subject,
-                    body,
-                    html_body,
-                    date,
-                    read,
-                    starred: false,
-                    folder: folder.clone(),
-                    attachments: None,
-                    account_id: Some(account.id.clone()),
-                    message_id,
-                    from_user_id: None,
-                    to_user_ids: None,
-                    cc_user_ids: None,
-                    bcc_user_ids: None,
-                };
+    /// Download some parts of a message by section number, without marking it read.
+    pub async fn fetch_parts(&self, folder: &str, uid: u32, sections: Vec<String>) -> Result<Vec<FetchedPart>> {
+        let mailbox = self.mailbox_name(folder);
+        self.with_session(move |session| {
+            session.examine(&mailbox).map_err(imap_error)?;
+            ensure_uid_exists(session, uid)?;
 
-                result.push(email);
-            }
+            // Each part comes with its MIME header so mailparse can undo the
+            // transfer encoding and charset. A single-part message has none:
+            // its headers are the message's.
+            let query = std::iter::once("BODY.PEEK[HEADER]".to_string())
+                .chain(sections.iter().map(|s| format!("BODY.PEEK[{0}.MIME] BODY.PEEK[{0}]", s)))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let fetches = session
+                .uid_fetch(uid.to_string(), format!("(UID {})", query))
+                .map_err(imap_error)?;
+            let msg = fetches
+                .iter()
+                .find(|msg| msg.uid == Some(uid))
+                .ok_or_else(|| EmailError::MessageNotFound(format!("UID {}", uid)))?;
 
-            let _ = session.logout();
-            Ok::<Vec<Email>, EmailError>(result)
+            let mut parts = Vec::with_capacity(sections.len());
+            for section in sections.iter().cloned() {
+                let path = section_path(&section)?;
+                let header = msg
+                    .section(&SectionPath::Part(path.clone(), Some(MessageSection::Mime)))
+                    .filter(|h| !h.iter().all(u8::is_ascii_whitespace))
+                    .or_else(|| msg.header())
+                    .unwrap_or_default();
+                let body = msg
+                    .section(&SectionPath::Part(path, None))
+                    .ok_or_else(|| EmailError::ParseError(format!("Part {} missing from FETCH response", section)))?;
+
+                let mut entity = header.to_vec();
+                entity.extend_from_slice(body);
+                let parsed = parse_mail(&entity).map_err(|e| EmailError::ParseError(e.to_string()))?;
+                let data = parsed.get_body_raw().map_err(|e| EmailError::ParseError(e.to_string()))?;
+                let text = if parsed.ctype.mimetype.starts_with("text/") {
+                    Some(parsed.get_body().map_err(|e| EmailError::ParseError(e.to_string()))?)
+                } else {
+                    None
+                };
+                parts.push(FetchedPart { section, data, text });
+            }
+            Ok(parts)
         })
         .await
-        .map_err(|e| EmailError::ConnectionError(e.to_string()))??;
-
-        Ok(emails)
     }
-    
-    /// Send an email via SMTP
-    pub async fn send_email(&self, email: &NewEmail) -> Result<()> {
-        let from_mailbox: Mailbox = format!("{} <{}>", email.from.name, email.from.email)
-            .parse()
-            .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
+
+    /// Send `email` over SMTP, returning the message as it was sent.
+    pub async fn send_email(&self, email: &NewEmail) -> Result<Message> {
+        let message = build_message(email)?;
         
-        let mut message_builder = Message::builder()
-            .from(from_mailbox)
-            .subject(&email.subject);
+        let (mut connection, _) = self.smtp_connection(false).await?;
+        connection
+            .send(message.envelope(), &message.formatted())
+            .await
+            .map_err(smtp_error)?;
+        let _ = connection.quit().await;
         
-        // Add recipients
-        for recipient in &email.to {
-            let to_mailbox: Mailbox = if recipient.name.is_empty() {
-                recipient.email.parse()
-            } else {
-                format!("{} <{}>", recipient.name, recipient.email).parse()
+        Ok(message)
+    }
+
+    /// Whether the provider files messages sent over SMTP in Sent by itself,
+    /// so that appending them would make duplicates.
+    pub fn saves_sent_copies(&self) -> bool {
+        let host = self.account.imap_server.to_ascii_lowercase();
+        ["gmail.com", "googlemail.com"]
+            .iter()
+            .any(|domain| host == *domain || host.ends_with(&format!(".{}", domain)))
+    }
+
+    /// The account password, read when a connection is opened.
+    fn password(&self) -> Result<String> {
+        if !self.account.password.is_empty() {
+            return Ok(self.account.password.clone());
+        }
+        self.secrets
+            .get(&secrets::password_key(&self.account.id))
+            .map_err(|e| EmailError::AuthError(e.to_string()))?
+            .ok_or_else(|| EmailError::AuthError(format!("no password stored for {}", self.account.email)))
+    }
+
+    /// The credentials to open a connection with. OAuth2 access tokens are
+    /// refreshed here when they are about to expire.
+    async fn login(&self) -> Result<Login> {
+        match &self.account.auth {
+            AuthMethod::Password => Ok(Login::Password(self.password()?)),
+            AuthMethod::OAuth2(config) => {
+                let token = oauth::access_token(config, self.secrets.as_ref(), &self.account.id)
+                    .await
+                    .map_err(|e| match e {
+                        // Unreachable token endpoint: retry later like any offline server.
+                        OAuthError::Http(e) if e.is_connect() || e.is_timeout() => {
+                            EmailError::ConnectionError(e.to_string())
+                        }
+                        e => EmailError::AuthError(e.to_string()),
+                    })?;
+                Ok(Login::Token(config.mechanism, token))
             }
-            .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
-            
-            message_builder = message_builder.to(to_mailbox);
         }
-        
-        // Add CC
-        if let Some(cc_list) = &email.cc {
-            for cc in cc_list {
-                let cc_mailbox: Mailbox = if cc.name.is_empty() {
-                    cc.email.parse()
-                } else {
-                    format!("{} <{}>", cc.name, cc.email).parse()
+    }
+
+    /// A logged in SMTP connection, with the fingerprint of the server
+    /// certificate when it was trusted by its pin rather than its chain
+    /// (see `connect_trusted`).
+    async fn smtp_connection(&self, first_use: bool) -> Result<(AsyncSmtpConnection, Option<String>)> {
+        // lettre only speaks XOAUTH2, whatever mechanism IMAP uses.
+        let (credentials, mechanisms) = match self.login().await? {
+            Login::Password(password) => (Credentials::new(self.account.username.clone(), password), DEFAULT_MECHANISMS),
+            Login::Token(_, token) => (Credentials::new(self.account.username.clone(), token), &[Mechanism::Xoauth2][..]),
+        };
+
+        let account = &self.account;
+        let trusted = account.allow_invalid_smtp_certs && account.smtp_security != Security::None;
+        let (mut connection, fingerprint) = match account.smtp_fingerprint.as_deref().filter(|_| trusted) {
+            // As for IMAP, a pinned certificate is checked in a single handshake.
+            Some(pinned) => {
+                let (connection, certificate) = self.smtp_connect(false).await?;
+                match check_pinned(&account.smtp_server, Some(pinned), certificate, first_use) {
+                    Ok(found) => (connection, Some(found)),
+                    Err(changed) => (self.smtp_connect(true).await.map_err(|_| changed)?.0, None),
                 }
-                .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
-                
-                message_builder = message_builder.cc(cc_mailbox);
             }
-        }
-        
-        // Add BCC
-        if let Some(bcc_list) = &email.bcc {
-            for bcc in bcc_list {
-                let bcc_mailbox: Mailbox = if bcc.name.is_empty() {
-                    bcc.email.parse()
+            None => {
+                let verified = self.smtp_connect(true).await;
+                if verified.is_ok() || !trusted {
+                    (verified?.0, None)
                 } else {
-                    format!("{} <{}>", bcc.name, bcc.email).parse()
+                    let (connection, certificate) = self.smtp_connect(false).await?;
+                    let found = check_pinned(&account.smtp_server, None, certificate, first_use)?;
+                    (connection, Some(found))
                 }
-                .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
-                
-                message_builder = message_builder.bcc(bcc_mailbox);
             }
+        };
+
+        connection.auth(mechanisms, &credentials).await.map_err(smtp_error)?;
+        Ok((connection, fingerprint))
+    }
+
+    /// Connect to the SMTP server, checking its certificate chain only when
+    /// `verify`. Returns the certificate it presented, if any.
+    async fn smtp_connect(&self, verify: bool) -> Result<(AsyncSmtpConnection, Option<Vec<u8>>)> {
+        let account = &self.account;
+        let tls = || {
+            TlsParameters::builder(account.smtp_server.clone())
+                .dangerous_accept_invalid_certs(!verify)
+                .dangerous_accept_invalid_hostnames(!verify)
+                .build()
+                .map_err(smtp_error)
+        };
+        let hello = ClientId::default();
+        let implicit = match account.smtp_security {
+            Security::Tls => Some(tls()?),
+            Security::StartTls | Security::None => None,
+        };
+
+        let mut connection = AsyncSmtpConnection::connect_tokio1(
+            (account.smtp_server.as_str(), account.smtp_port),
+            Some(SMTP_TIMEOUT),
+            &hello,
+            implicit,
+            None,
+        )
+        .await
+        .map_err(smtp_error)?;
+        if account.smtp_security == Security::StartTls {
+            connection.starttls(tls()?, &hello).await.map_err(smtp_error)?;
         }
-        
-        // Build message body
-        let message = if email.attachments.is_some() && !email.attachments.as_ref().unwrap().is_empty() {
-            // With attachments - multipart
-            let mut multipart = MultiPart::mixed().singlepart(
-                SinglePart::builder()
-                    .header(ContentType::TEXT_PLAIN)
-                    .body(email.body.clone()),
-            );
-            
-            // Add attachments
-            if let Some(attachments) = &email.attachments {
-                for attachment in attachments {
-                    if let Some(data) = &attachment.data {
-                        let content_type = ContentType::parse(&attachment.mime_type)
-                            .unwrap_or(ContentType::parse("application/octet-stream").unwrap());
-                        
-                        multipart = multipart.singlepart(
-                            SinglePart::builder()
-                                .header(content_type)
-                                .header(lettre::message::header::ContentDisposition::attachment(&attachment.filename))
-                                .body(data.clone()),
-                        );
-                    }
-                }
+
+        let certificate = if connection.is_encrypted() {
+            Some(connection.peer_certificate().map_err(smtp_error)?)
+        } else {
+            None
+        };
+        Ok((connection, certificate))
+    }
+    
+    pub async fn create_folder(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.with_session(move |session| {
+            session.create(&path).map_err(imap_error)?;
+            session.subscribe(&path).map_err(imap_error)
+        })
+        .await
+    }
+
+    pub async fn rename_folder(&self, from: &str, to: &str) -> Result<()> {
+        let from = from.to_string();
+        let to = to.to_string();
+        self.with_session(move |session| {
+            session.rename(&from, &to).map_err(imap_error)?;
+            // Subscriptions are not carried over by RENAME on every server.
+            let _ = session.unsubscribe(&from);
+            session.subscribe(&to).map_err(imap_error)
+        })
+        .await
+    }
+
+    pub async fn delete_folder(&self, path: &str) -> Result<()> {
+        let path = path.to_string();
+        self.with_session(move |session| {
+            let _ = session.unsubscribe(&path);
+            session.delete(&path).map_err(imap_error)
+        })
+        .await
+    }
+
+    pub async fn set_subscribed(&self, path: &str, subscribed: bool) -> Result<()> {
+        let path = path.to_string();
+        self.with_session(move |session| {
+            if subscribed {
+                session.subscribe(&path).map_err(imap_error)
+            } else {
+                session.unsubscribe(&path).map_err(imap_error)
             }
-            
-            message_builder
-                .multipart(multipart)
-                .map_err(|e| EmailError::SmtpError(e.to_string()))?
+        })
+        .await
+    }
+
+    /// Watch `folder` for changes until `stop` is set, sending on `changed`
+    /// whenever the server reports new, expunged or updated messages.
+    ///
+    /// Uses IDLE (re-issued every `IDLE_REFRESH`) when the server supports it,
+    /// and NOOP polling every `POLL_INTERVAL` otherwise. The session is never
+    /// pooled, as it stays busy until `stop` is set; stopping shuts its
+    /// connection down rather than waiting for the next refresh.
+    pub async fn watch_mailbox(
+        &self,
+        folder: &str,
+        stop: Arc<WatchStop>,
+        changed: UnboundedSender<()>,
+    ) -> Result<()> {
+        let account = self.account.clone();
+        let mailbox = self.mailbox_name(folder);
+        let login = self.login().await?;
+        tokio::task::spawn_blocking(move || {
+            let (mut session, _) = open_session(&account, &login, false, Some(&stop))?;
+            let result = watch_selected(&mut session, &mailbox, &stop, &changed);
+            if stop.is_stopped() {
+                // The connection is shut down, whatever the outcome.
+                return Ok(());
+            }
+            let _ = session.logout();
+            result
+        })
+        .await
+        .map_err(|e| EmailError::ConnectionError(e.to_string()))?
+    }
+
+    /// Mark email as read/unread on IMAP server
+    pub async fn set_read_flag(&self, folder: &str, uid: u32, read: bool) -> Result<()> {
+        self.store_flag(folder, uid, "\\Seen", read).await
+    }
+
+    /// Star/unstar email on IMAP server
+    pub async fn set_starred_flag(&self, folder: &str, uid: u32, starred: bool) -> Result<()> {
+        self.store_flag(folder, uid, "\\Flagged", starred).await
+    }
+
+    async fn store_flag(&self, folder: &str, uid: u32, flag: &'static str, enabled: bool) -> Result<()> {
+        let mailbox = self.mailbox_name(folder);
+        self.with_session(move |session| {
+            session
+                .select(&mailbox)
+                .map_err(imap_error)?;
+            ensure_uid_exists(session, uid)?;
+
+            let op = if enabled { "+FLAGS.SILENT" } else { "-FLAGS.SILENT" };
+            session
+                .uid_store(uid.to_string(), format!("{} ({})", op, flag))
+                .map_err(imap_error)?;
+            Ok(())
+        })
+        .await
+    }
+    
+    /// Delete email from the server: flag it \Deleted, and expunge it when
+    /// the server supports UIDPLUS.
+    pub async fn delete_email(&self, folder: &str, uid: u32) -> Result<()> {
+        let mailbox = self.mailbox_name(folder);
+        self.with_session(move |session| {
+            session
+                .select(&mailbox)
+                .map_err(imap_error)?;
+            ensure_uid_exists(session, uid)?;
+            let capabilities = session
+                .capabilities()
+                .map_err(imap_error)?;
+            let uidplus = capabilities.has_str("UIDPLUS");
+
+            session
+                .uid_store(uid.to_string(), "+FLAGS.SILENT (\\Deleted)")
+                .map_err(imap_error)?;
+            expunge_uid(session, uid, uidplus)
+        })
+        .await
+    }
+
+    /// Store a message in `folder` with `APPEND`.
+    ///
+    /// Returns its UID when the server reports it (UIDPLUS `APPENDUID`), or
+    /// else when it can be found again by `message_id`.
+    pub async fn append_message(
+        &self,
+        folder: &str,
+        raw: Vec<u8>,
+        flags: Vec<Flag<'static>>,
+        message_id: Option<String>,
+    ) -> Result<Option<ServerUid>> {
+        let mailbox = self.mailbox_name(folder);
+        self.with_session(move |session| {
+            let appended = session
+                .append(&mailbox, &raw)
+                .flags(flags.clone())
+                .finish()
+                .map_err(imap_error)?;
+            let uid = appended.uids.and_then(|uids| {
+                uids.into_iter().next().map(|member| match member {
+                    UidSetMember::Uid(uid) => uid,
+                    UidSetMember::UidRange(range) => *range.start(),
+                })
+            });
+            if let (Some(uid_validity), Some(uid)) = (appended.uid_validity, uid) {
+                return Ok(Some(ServerUid { uid_validity, uid }));
+            }
+
+            let message_id = match &message_id {
+                Some(message_id) => message_id,
+                None => return Ok(None),
+            };
+            let uid_validity = session
+                .examine(&mailbox)
+                .map_err(imap_error)?
+                .uid_validity
+                .unwrap_or(0);
+            let found = session
+                .uid_search(format!("HEADER Message-ID {}", quote_string(message_id)))
+                .map_err(imap_error)?;
+            Ok(found.into_iter().max().map(|uid| ServerUid { uid_validity, uid }))
+        })
+        .await
+    }
+
+    /// Move email to another folder on the server.
+    ///
+    /// Uses `UID MOVE` when advertised, otherwise COPY + \Deleted + `UID EXPUNGE`.
+    /// Returns the UID of the message in the target folder when the server
+    /// reports it (UIDPLUS `COPYUID`).
+    pub async fn move_email(&self, folder: &str, uid: u32, target: &str) -> Result<Option<ServerUid>> {
+        let mailbox = self.mailbox_name(folder);
+        let target = quote_string(&self.mailbox_name(target));
+        self.with_session(move |session| {
+            session
+                .select(&mailbox)
+                .map_err(imap_error)?;
+            ensure_uid_exists(session, uid)?;
+            let capabilities = session
+                .capabilities()
+                .map_err(imap_error)?;
+
+            if capabilities.has_str("MOVE") {
+                let (response, _) = session
+                    .run(format!("UID MOVE {} {}", uid, target))
+                    .map_err(imap_error)?;
+                return Ok(parse_copyuid(&response));
+            }
+
+            let (response, _) = session
+                .run(format!("UID COPY {} {}", uid, target))
+                .map_err(imap_error)?;
+            session
+                .uid_store(uid.to_string(), "+FLAGS.SILENT (\\Deleted)")
+                .map_err(imap_error)?;
+            expunge_uid(session, uid, capabilities.has_str("UIDPLUS"))?;
+            Ok(parse_copyuid(&response))
+        })
+        .await
+    }
+}
+
+/// Select `mailbox` and report its changes on `changed` until `stop` is set.
+fn watch_selected(session: &mut ImapSession, mailbox: &str, stop: &WatchStop, changed: &UnboundedSender<()>) -> Result<()> {
+    session
+        .select(mailbox)
+        .map_err(imap_error)?;
+    let idle = session
+        .capabilities()
+        .map_err(imap_error)?
+        .has_str("IDLE");
+
+    while !stop.is_stopped() {
+        let outcome = if idle {
+            session
+                .idle()
+                .timeout(IDLE_REFRESH)
+                .keepalive(false)
+                .wait_while(|response| !is_mailbox_change(&response))
+                .map_err(imap_error)?
         } else {
-            // Plain text only
-            message_builder
-                .header(ContentType::TEXT_PLAIN)
-                .body(email.body.clone())
-                .map_err(|e| EmailError::SmtpError(e.to_string()))?
+            if stop.sleep(POLL_INTERVAL) {
+                break;
+            }
+            session.noop().map_err(imap_error)?;
+            if session.take_all_unsolicited().any(|response| is_mailbox_change(&response)) {
+                WaitOutcome::MailboxChanged
+            } else {
+                WaitOutcome::TimedOut
+            }
         };
-        
-        // Create SMTP transport
-        let mailer = self.build_smtp_transport()?;
-        
-        // Send the email
-        mailer
-            .send(message)
-            .await
-            .map_err(|e| EmailError::SmtpError(e.to_string()))?;
-        
+
+        if outcome == WaitOutcome::MailboxChanged && changed.send(()).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Classify IMAP errors so network failures can be retried later.
+fn imap_error(e: imap::Error) -> EmailError {
+    match e {
+        imap::Error::Io(_) | imap::Error::ConnectionLost => EmailError::ConnectionError(e.to_string()),
+        other => EmailError::ImapError(other.to_string()),
+    }
+}
+
+/// Classify SMTP errors so network failures can be retried later.
+fn smtp_error(e: lettre::transport::smtp::Error) -> EmailError {
+    let server_reply = e.status().is_some() || e.is_response();
+    if !server_reply && !e.is_client() && !e.is_tls() && !e.is_transport_shutdown() {
+        EmailError::ConnectionError(e.to_string())
+    } else {
+        EmailError::SmtpError(e.to_string())
+    }
+}
+
+/// Fail with `MessageNotFound` when `uid` is no longer in the selected folder.
+fn ensure_uid_exists(session: &mut ImapSession, uid: u32) -> Result<()> {
+    let found = session
+        .uid_search(format!("UID {}", uid))
+        .map_err(imap_error)?;
+    if found.contains(&uid) {
         Ok(())
+    } else {
+        Err(EmailError::MessageNotFound(format!("UID {}", uid)))
     }
+}
 
-    fn build_smtp_transport(&self) -> Result<AsyncSmtpTransport<Tokio1Executor>> {
-        let creds = Credentials::new(
-            self.account.username.clone(),
-            self.account.password.clone(),
-        );
+fn is_mailbox_change(response: &UnsolicitedResponse) -> bool {
+    matches!(
+        response,
+        UnsolicitedResponse::Exists(_)
+            | UnsolicitedResponse::Expunge(_)
+            | UnsolicitedResponse::Vanished { .. }
+            | UnsolicitedResponse::Fetch { .. }
+    )
+}
 
-        let tls_parameters = if self.account.allow_invalid_smtp_certs {
-            Some(
-                TlsParametersBuilder::new(self.account.smtp_server.clone())
-                    .dangerous_accept_invalid_hostnames(true)
-                    .dangerous_accept_invalid_certs(true)
-                    .build()
-                    .map_err(|e: lettre::transport::smtp::Error| EmailError::SmtpError(e.to_string()))?,
-            )
+/// Expunge a single message flagged `\Deleted`, with `UID EXPUNGE`.
+///
+/// Without UIDPLUS the message is left flagged, as a plain EXPUNGE would
+/// also remove the messages other clients only flagged; sync hides it.
+fn expunge_uid(session: &mut ImapSession, uid: u32, uidplus: bool) -> Result<()> {
+    if uidplus {
+        session
+            .uid_expunge(uid.to_string())
+            .map_err(imap_error)?;
+    }
+    Ok(())
+}
+
+fn quote_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Extract the destination UID from a `[COPYUID <validity> <src> <dst>]` response code.
+fn parse_copyuid(response: &[u8]) -> Option<ServerUid> {
+    let text = String::from_utf8_lossy(response);
+    let start = text.find("[COPYUID ")? + "[COPYUID ".len();
+    let code = &text[start..start + text[start..].find(']')?];
+    let mut fields = code.split_whitespace();
+    let uid_validity = fields.next()?.parse().ok()?;
+    let uid = fields.nth(1)?.parse().ok()?;
+    Some(ServerUid { uid_validity, uid })
+}
+
+/// A SASL exchange whose client response is known upfront.
+struct SaslResponse(String);
+
+impl imap::Authenticator for SaslResponse {
+    type Response = String;
+
+    fn process(&self, challenge: &[u8]) -> Self::Response {
+        // After a failure the server sends an error challenge, which must be
+        // answered with an empty response to get the tagged NO.
+        if challenge.is_empty() {
+            self.0.clone()
         } else {
-            None
+            String::new()
+        }
+    }
+}
+
+/// SHA-256 of a DER certificate, as colon-separated hex pairs.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der)
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// Whether `certificate`, presented by `host` without a valid chain, can be
+/// trusted: it must match the fingerprint `pinned` for the host, or there
+/// must be none yet on `first_use`. Returns its fingerprint.
+fn check_pinned(host: &str, pinned: Option<&str>, certificate: Option<Vec<u8>>, first_use: bool) -> Result<String> {
+    let found = certificate
+        .map(|der| fingerprint(&der))
+        .ok_or_else(|| EmailError::ConnectionError(format!("{} presented no certificate", host)))?;
+    match pinned {
+        Some(pinned) if pinned.eq_ignore_ascii_case(&found) => Ok(found),
+        Some(pinned) => Err(EmailError::CertificateChanged {
+            host: host.to_string(),
+            pinned: pinned.to_string(),
+            found,
+        }),
+        None if first_use => Ok(found),
+        None => Err(EmailError::ConnectionError(format!(
+            "the certificate of {} is not trusted, test the connection to accept it",
+            host
+        ))),
+    }
+}
+
+/// Connect to the IMAP server of `account`. A certificate that does not
+/// verify is accepted, with `allow_invalid_certs`, when it matches the pinned
+/// `imap_fingerprint`; its fingerprint is returned then.
+///
+/// A pinned certificate is checked in a single handshake; the chain is only
+/// verified when the pin no longer matches, or when there is none yet.
+fn connect_imap(account: &EmailAccount, first_use: bool, watch: Option<&WatchStop>) -> Result<(ImapClient, Option<String>)> {
+    let trusted = account.allow_invalid_certs && account.imap_security != Security::None;
+    if let Some(pinned) = account.imap_fingerprint.as_deref().filter(|_| trusted) {
+        let (client, certificate) = imap_connect(account, false, watch)?;
+        return match check_pinned(&account.imap_server, Some(pinned), certificate, first_use) {
+            Ok(found) => Ok((client, Some(found))),
+            // The server may have moved to a certificate that verifies.
+            Err(changed) => imap_connect(account, true, watch)
+                .map(|(client, _)| (client, None))
+                .map_err(|_| changed),
         };
+    }
 
-        let builder = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
-            self.account.smtp_server.clone(),
-        )
-        .port(self.account.smtp_port)
-        .credentials(creds);
+    let verified = imap_connect(account, true, watch);
+    if verified.is_ok() || !trusted {
+        return verified.map(|(client, _)| (client, None));
+    }
+
+    let (client, certificate) = imap_connect(account, false, watch)?;
+    let found = check_pinned(&account.imap_server, account.imap_fingerprint.as_deref(), certificate, first_use)?;
+    Ok((client, Some(found)))
+}
+
+/// Connect to the IMAP server of `account`, checking its certificate chain
+/// only when `verify`. Returns the certificate it presented, if any. The
+/// connection is handed to `watch`, to be shut down when it stops.
+fn imap_connect(account: &EmailAccount, verify: bool, watch: Option<&WatchStop>) -> Result<(ImapClient, Option<Vec<u8>>)> {
+    let connection_error = |e: &dyn std::fmt::Display| EmailError::ConnectionError(e.to_string());
+    let host = account.imap_server.as_str();
+    let connector = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(!verify)
+        .danger_accept_invalid_hostnames(!verify)
+        .build()
+        .map_err(|e| connection_error(&e))?;
+    let handshake = |tcp: TcpStream| -> Result<(imap::Connection, Option<Vec<u8>>)> {
+        let stream = connector.connect(host, tcp).map_err(|e| connection_error(&e))?;
+        let certificate = stream
+            .peer_certificate()
+            .and_then(|certificate| certificate.map(|c| c.to_der()).transpose())
+            .map_err(|e| connection_error(&e))?;
+        Ok((Box::new(stream), certificate))
+    };
+
+    let tcp = TcpStream::connect((host, account.imap_port)).map_err(|e| connection_error(&e))?;
+    if let Some(watch) = watch {
+        watch.attach(tcp.try_clone().map_err(|e| connection_error(&e))?);
+    }
+    let (stream, certificate, greeted) = match account.imap_security {
+        Security::Tls => {
+            let (stream, certificate) = handshake(tcp)?;
+            (stream, certificate, false)
+        }
+        Security::StartTls => {
+            starttls(&tcp, host)?;
+            let (stream, certificate) = handshake(tcp)?;
+            (stream, certificate, true)
+        }
+        Security::None => (Box::new(tcp) as imap::Connection, None, false),
+    };
+
+    let mut client = imap::Client::new(stream);
+    if greeted {
+        // The greeting came before STARTTLS.
+        client.greeting_read = true;
+    } else {
+        client.read_greeting().map_err(|e| connection_error(&e))?;
+    }
+    Ok((client, certificate))
+}
+
+/// Read the greeting of an IMAP server and ask it to start TLS.
+fn starttls(tcp: &TcpStream, host: &str) -> Result<()> {
+    let connection_error = |e: std::io::Error| EmailError::ConnectionError(e.to_string());
+    // The server is silent after its reply, so nothing is read past it.
+    let mut reader = BufReader::new(tcp);
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(connection_error)?;
+    (&mut &*tcp).write_all(b"a0 STARTTLS\r\n").map_err(connection_error)?;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(connection_error)? == 0 {
+            return Err(EmailError::ConnectionError(format!("{} closed the connection", host)));
+        }
+        if let Some(status) = line.strip_prefix("a0 ") {
+            if status.starts_with("OK") {
+                return Ok(());
+            }
+            return Err(EmailError::ConnectionError(format!("{} refused STARTTLS: {}", host, status.trim())));
+        }
+    }
+}
+
+/// Open a logged in session; see `connect_imap` for `first_use` and `watch`.
+fn open_session(
+    account: &EmailAccount,
+    login: &Login,
+    first_use: bool,
+    watch: Option<&WatchStop>,
+) -> Result<(ImapSession, Option<String>)> {
+    let (client, fingerprint) = connect_imap(account, first_use, watch)?;
 
-        let builder = if self.account.use_ssl {
-            if let Some(tls) = tls_parameters {
-                builder.tls(Tls::Required(tls))
+    let session = match login {
+        Login::Password(password) => client.login(&account.username, password),
+        Login::Token(mechanism, token) => {
+            let response = oauth::sasl_response(*mechanism, &account.username, &account.imap_server, account.imap_port, token);
+            client.authenticate(mechanism.name(), &SaslResponse(response))
+        }
+    };
+    let session = session.map_err(|e| {
+        log::error!("IMAP login failed server={} user={} err={}", account.imap_server, account.username, e.0);
+        EmailError::AuthError(format!("IMAP AUTH failed: {}", e.0))
+    })?;
+    Ok((session, fingerprint))
+}
+
+/// Encode a mailbox name in IMAP modified UTF-7 (RFC 3501 §5.1.3).
+pub fn encode_mailbox_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+
+    fn flush(out: &mut String, pending: &mut Vec<u16>) {
+        if pending.is_empty() {
+            return;
+        }
+        let bytes: Vec<u8> = pending.iter().flat_map(|u| u.to_be_bytes()).collect();
+        out.push('&');
+        out.push_str(&modified_base64_encode(&bytes));
+        out.push('-');
+        pending.clear();
+    }
+
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut out, &mut pending);
+            if c == '&' {
+                out.push_str("&-");
             } else {
-                builder
+                out.push(c);
             }
-        } else if let Some(tls) = tls_parameters {
-            builder.tls(Tls::Wrapper(tls))
         } else {
-            builder
+            let mut buf = [0u16; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut buf));
+        }
+    }
+    flush(&mut out, &mut pending);
+    out
+}
+
+/// Decode an IMAP modified UTF-7 mailbox name, leaving malformed input as is.
+pub fn decode_mailbox_name(name: &str) -> String {
+    let mut out = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let end = match after.find('-') {
+            Some(end) => end,
+            None => return name.to_string(),
         };
+        if end == 0 {
+            out.push('&');
+        } else {
+            let bytes = match modified_base64_decode(&after[..end]) {
+                Some(bytes) if bytes.len() % 2 == 0 => bytes,
+                _ => return name.to_string(),
+            };
+            let units: Vec<u16> = bytes.chunks(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            match String::from_utf16(&units) {
+                Ok(decoded) => out.push_str(&decoded),
+                Err(_) => return name.to_string(),
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    out
+}
+
+const MODIFIED_BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+fn modified_base64_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            out.push(MODIFIED_BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    out
+}
 
-        Ok(builder.build())
+fn modified_base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = MODIFIED_BASE64.iter().position(|b| *b == c)? as u32;
+        acc = (acc << 6) | value;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
     }
+    Some(out)
+}
+
+/// A fresh `Message-ID` in the domain of address `from`.
+pub fn new_message_id(from: &str) -> String {
+    let domain = from.rsplit_once('@').map_or("localhost", |(_, domain)| domain);
+    format!("<{}@{}>", uuid::Uuid::new_v4(), domain)
+}
+
+/// The `Message-ID` header of a built message.
+pub fn message_id(message: &Message) -> Option<String> {
+    message.headers().get_raw("Message-ID").map(str::to_string)
+}
+
+/// Format `email` as a MIME message.
+///
+/// Text and HTML bodies go in a `multipart/alternative`, the HTML part in a
+/// `multipart/related` with its inline images, and everything in a
+/// `multipart/mixed` when there are attachments.
+pub fn build_message(email: &NewEmail) -> Result<Message> {
+    let from_mailbox: Mailbox = format!("{} <{}>", email.from.name, email.from.email)
+        .parse()
+        .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
     
-    /// Mark email as read/unread on IMAP server
-    /// Note: IMAP functionality is temporarily disabled
-    pub async fn set_read_flag(&self, _uid: &str, _read: bool) -> Result<()> {
-        // TODO: Implement with proper async runtime compatibility
-        log::warn!("IMAP set_read_flag not yet implemented");
-        Ok(())
+    let message_id = email
+        .message_id
+        .clone()
+        .unwrap_or_else(|| new_message_id(&email.from.email));
+    let mut message_builder = Message::builder()
+        .from(from_mailbox)
+        .subject(&email.subject)
+        .message_id(Some(message_id));
+
+    // Threading headers of replies
+    if let Some(in_reply_to) = &email.in_reply_to {
+        message_builder = message_builder.in_reply_to(in_reply_to.clone());
+    }
+    if let Some(references) = email.references.as_ref().filter(|r| !r.is_empty()) {
+        message_builder = message_builder.references(references.join(" "));
     }
     
-    /// Delete email (move to trash or permanently delete)
-    /// Note: IMAP functionality is temporarily disabled
-    pub async fn delete_email(&self, _uid: &str) -> Result<()> {
-        // TODO: Implement with proper async runtime compatibility
-        log::warn!("IMAP delete_email not yet implemented");
-        Ok(())
+    // Add recipients
+    for recipient in &email.to {
+        let to_mailbox: Mailbox = if recipient.name.is_empty() {
+            recipient.email.parse()
+        } else {
+            format!("{} <{}>", recipient.name, recipient.email).parse()
+        }
+        .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
+        
+        message_builder = message_builder.to(to_mailbox);
+    }
+    
+    // Add CC
+    if let Some(cc_list) = &email.cc {
+        for cc in cc_list {
+            let cc_mailbox: Mailbox = if cc.name.is_empty() {
+                cc.email.parse()
+            } else {
+                format!("{} <{}>", cc.name, cc.email).parse()
+            }
+            .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
+            
+            message_builder = message_builder.cc(cc_mailbox);
+        }
+    }
+    
+    // Add BCC
+    if let Some(bcc_list) = &email.bcc {
+        for bcc in bcc_list {
+            let bcc_mailbox: Mailbox = if bcc.name.is_empty() {
+                bcc.email.parse()
+            } else {
+                format!("{} <{}>", bcc.name, bcc.email).parse()
+            }
+            .map_err(|e: lettre::address::AddressError| EmailError::ParseError(e.to_string()))?;
+            
+            message_builder = message_builder.bcc(bcc_mailbox);
+        }
+    }
+    
+    // Build message body
+    let html = email.html_body.as_deref().filter(|html| !html.trim().is_empty());
+    let text = match html {
+        Some(html) if email.body.trim().is_empty() => compose::html_to_text(html),
+        _ => email.body.clone(),
+    };
+    let text_part = || {
+        SinglePart::builder()
+            .header(ContentType::TEXT_PLAIN)
+            .body(text.clone())
+    };
+
+    let (inline, attached): (Vec<&Attachment>, Vec<&Attachment>) = email
+        .attachments
+        .iter()
+        .flatten()
+        .filter(|attachment| attachment.data.is_some())
+        .partition(|attachment| html.is_some() && attachment.inline && attachment.content_id.is_some());
+
+    let content = match html {
+        Some(html) => {
+            let html_part = SinglePart::builder()
+                .header(ContentType::TEXT_HTML)
+                .body(html.to_string());
+            let alternative = MultiPart::alternative().singlepart(text_part());
+            if inline.is_empty() {
+                Some(alternative.singlepart(html_part))
+            } else {
+                let related = inline.iter().fold(MultiPart::related().singlepart(html_part), |related, attachment| {
+                    let content_id = attachment.content_id.as_deref().unwrap_or_default();
+                    related.singlepart(
+                        MimeAttachment::new_inline(content_id.trim_matches(|c| c == '<' || c == '>').to_string())
+                            .body(attachment.data.clone().unwrap_or_default(), attachment_content_type(attachment)),
+                    )
+                });
+                Some(alternative.multipart(related))
+            }
+        }
+        None => None,
+    };
+
+    let message = if attached.is_empty() {
+        match content {
+            Some(alternative) => message_builder.multipart(alternative),
+            None => message_builder.singlepart(text_part()),
+        }
+    } else {
+        let mixed = match content {
+            Some(alternative) => MultiPart::mixed().multipart(alternative),
+            None => MultiPart::mixed().singlepart(text_part()),
+        };
+        let mixed = attached.iter().fold(mixed, |mixed, attachment| {
+            mixed.singlepart(
+                MimeAttachment::new(attachment.filename.clone())
+                    .body(attachment.data.clone().unwrap_or_default(), attachment_content_type(attachment)),
+            )
+        });
+        message_builder.multipart(mixed)
+    };
+    message.map_err(|e| EmailError::SmtpError(e.to_string()))
+}
+
+fn attachment_content_type(attachment: &Attachment) -> ContentType {
+    ContentType::parse(&attachment.mime_type)
+        .unwrap_or_else(|_| ContentType::parse("application/octet-stream").unwrap())
+}
+
+fn special_use_name(attribute: &NameAttribute) -> Option<&'static str> {
+    match attribute {
+        NameAttribute::Sent => Some("sent"),
+        NameAttribute::Drafts => Some("drafts"),
+        NameAttribute::Trash => Some("trash"),
+        NameAttribute::Junk => Some("junk"),
+        NameAttribute::Archive => Some("archive"),
+        NameAttribute::All => Some("all"),
+        NameAttribute::Flagged => Some("flagged"),
+        _ => None,
+    }
+}
+
+/// Fallback names for servers that do not advertise SPECIAL-USE.
+const SPECIAL_USE_GUESSES: &[(&str, &[&str])] = &[
+    ("sent", &["sent", "sent items", "sent messages", "sent mail", "envoyés", "éléments envoyés", "messages envoyés"]),
+    ("drafts", &["drafts", "draft", "brouillons"]),
+    ("trash", &["trash", "deleted items", "deleted messages", "bin", "corbeille", "éléments supprimés"]),
+    ("junk", &["junk", "spam", "junk e-mail", "junk email", "courrier indésirable", "indésirables"]),
+    ("archive", &["archive", "archives"]),
+];
+
+/// Assign the roles that no mailbox claimed through SPECIAL-USE by name.
+fn guess_special_use(folders: &mut [MailFolder]) {
+    for (role, names) in SPECIAL_USE_GUESSES {
+        if folders.iter().any(|f| f.special_use.as_deref() == Some(*role)) {
+            continue;
+        }
+        let candidate = folders.iter_mut().find(|f| {
+            f.special_use.is_none() && f.selectable && names.contains(&f.name.to_lowercase().as_str())
+        });
+        if let Some(folder) = candidate {
+            folder.special_use = Some(role.to_string());
+        }
+    }
+}
+
+fn has_flag(msg: &Fetch, flag: &Flag) -> bool {
+    msg.flags().iter().any(|f| f == flag)
+}
+
+/// Read the flags of the already synced UID range.
+///
+/// With CONDSTORE, only messages changed since the stored HIGHESTMODSEQ are
+/// returned, and nothing is fetched when the mod-sequence did not move.
+fn fetch_flag_changes(
+    session: &mut ImapSession,
+    previous: &FolderSyncState,
+    highest_mod_seq: Option<u64>,
+) -> Result<Vec<FlagUpdate>> {
+    let range = format!("{}:{}", previous.oldest_uid.max(1), previous.last_uid);
+    let query = match (previous.highest_mod_seq, highest_mod_seq) {
+        (Some(known), Some(current)) if known == current => return Ok(vec![]),
+        (Some(known), Some(_)) => format!("(UID FLAGS) (CHANGEDSINCE {})", known),
+        _ => "(UID FLAGS)".to_string(),
+    };
+
+    let fetches = session
+        .uid_fetch(range, query)
+        .map_err(imap_error)?;
+
+    Ok(fetches
+        .iter()
+        .filter_map(|msg| {
+            Some(FlagUpdate {
+                uid: msg.uid?,
+                read: has_flag(msg, &Flag::Seen),
+                starred: has_flag(msg, &Flag::Flagged),
+            })
+        })
+        .collect())
+}
+
+/// Build an email from a FETCH response.
+///
+/// A response with `BODY[]` gives a fully loaded email. One with only
+/// `BODY[HEADER]` and `BODYSTRUCTURE` gives an email whose body is still on
+/// the server, with the sections needed to load it later.
+fn parse_message(account: &EmailAccount, folder: &str, uid_validity: u32, uid: u32, msg: &Fetch) -> Result<Email> {
+    let id = models::imap_email_id(&account.id, folder, uid_validity, uid);
+    if let Some(raw) = msg.body() {
+        let parsed = parse_mail(raw).map_err(|e| EmailError::ParseError(e.to_string()))?;
+        let mut email = email_from_headers(account, folder, id, &parsed, msg);
+        let attachments = find_attachments(&parsed);
+        email.body = find_body(&parsed, "text/plain").unwrap_or_default();
+        email.html_body = find_body(&parsed, "text/html");
+        email.attachments = if attachments.is_empty() { None } else { Some(attachments) };
+        email.raw = Some(raw.to_vec());
+        return Ok(email);
+    }
+
+    let header = msg.header().ok_or_else(|| EmailError::ParseError("Empty message header".into()))?;
+    let structure = msg
+        .bodystructure()
+        .ok_or_else(|| EmailError::ParseError("Missing BODYSTRUCTURE".into()))?;
+    let parsed = parse_mail(header).map_err(|e| EmailError::ParseError(e.to_string()))?;
+
+    let mut email = email_from_headers(account, folder, id, &parsed, msg);
+    let mut body_parts = Vec::new();
+    let mut attachments = Vec::new();
+    match structure {
+        BodyStructure::Multipart { bodies, .. } => {
+            for (i, part) in bodies.iter().enumerate() {
+                walk_structure(part, (i + 1).to_string(), &mut body_parts, &mut attachments);
+            }
+        }
+        part => walk_structure(part, "1".to_string(), &mut body_parts, &mut attachments),
+    }
+    email.body_loaded = body_parts.is_empty();
+    email.body_parts = if body_parts.is_empty() { None } else { Some(body_parts) };
+    email.attachments = if attachments.is_empty() { None } else { Some(attachments) };
+    Ok(email)
+}
+
+/// An email with the headers of `parsed` and an empty body.
+fn email_from_headers(account: &EmailAccount, folder: &str, id: String, parsed: &ParsedMail, msg: &Fetch) -> Email {
+    let subject = header_value(parsed, "Subject").unwrap_or_else(|| "(Sans objet)".to_string());
+    let from_list = parse_addresses(parsed, "From");
+    let to_list = parse_addresses(parsed, "To");
+    let cc_list = parse_addresses(parsed, "Cc");
+    let bcc_list = parse_addresses(parsed, "Bcc");
+
+    let from = from_list
+        .first()
+        .cloned()
+        .unwrap_or(EmailAddress { name: "".into(), email: "".into() });
+
+    let date = header_value(parsed, "Date")
+        .and_then(|d| dateparse(&d).ok())
+        .and_then(|ts| {
+            if ts >= 0 {
+                Some(chrono::DateTime::<chrono::Utc>::from(
+                    std::time::UNIX_EPOCH + std::time::Duration::from_secs(ts as u64),
+                ).to_rfc3339())
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| chrono::Utc::now().to_rfc3339());
+
+    let message_id = header_value(parsed, "Message-ID");
+    let in_reply_to = header_value(parsed, "In-Reply-To")
+        .and_then(|value| threading::parse_message_ids(&value).pop());
+    let references = header_value(parsed, "References")
+        .map(|value| threading::parse_message_ids(&value))
+        .filter(|ids| !ids.is_empty());
+
+    Email {
+        id,
+        from,
+        to: to_list,
+        cc: if cc_list.is_empty() { None } else { Some(cc_list) },
+        bcc: if bcc_list.is_empty() { None } else { Some(bcc_list) },
+        subject,
+        body: String::new(),
+        html_body: None,
+        date,
+        read: has_flag(msg, &Flag::Seen),
+        starred: has_flag(msg, &Flag::Flagged),
+        folder: folder.to_string(),
+        attachments: None,
+        account_id: Some(account.id.clone()),
+        message_id,
+        in_reply_to,
+        references,
+        thread_id: None,
+        raw_blob: None,
+        raw: None,
+        body_loaded: true,
+        size: msg.size,
+        body_parts: None,
+        from_user_id: None,
+        to_user_ids: None,
+        cc_user_ids: None,
+        bcc_user_ids: None,
+    }
+}
+
+/// Sort the leaves of a `BODYSTRUCTURE` into body text parts and attachments,
+/// mirroring `find_body` and `find_attachments`: the first `text/plain` and
+/// `text/html` parts are the body.
+fn walk_structure(part: &BodyStructure, section: String, body_parts: &mut Vec<BodyPart>, attachments: &mut Vec<Attachment>) {
+    let (common, other) = match part {
+        BodyStructure::Multipart { bodies, .. } => {
+            for (i, sub) in bodies.iter().enumerate() {
+                walk_structure(sub, format!("{}.{}", section, i + 1), body_parts, attachments);
+            }
+            return;
+        }
+        BodyStructure::Basic { common, other, .. }
+        | BodyStructure::Text { common, other, .. }
+        | BodyStructure::Message { common, other, .. } => (common, other),
+    };
+
+    let mime_type = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+    let content_type = decode_structure_params(&mime_type, &common.ty.params);
+    let content_type = parse_content_type(&content_type);
+    let disposition = common
+        .disposition
+        .as_ref()
+        .map(|d| parse_content_disposition(&decode_structure_params(&d.ty, &d.params)))
+        .unwrap_or_default();
+
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| content_type.params.get("name"))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty());
+    let is_attachment = disposition.disposition == DispositionType::Attachment
+        || filename.is_some()
+        || !mime_type.starts_with("text/");
+
+    if !is_attachment {
+        if (mime_type == "text/plain" || mime_type == "text/html") && !body_parts.iter().any(|p| p.mime_type == mime_type) {
+            body_parts.push(BodyPart { section, mime_type });
+        }
+        return;
+    }
+
+    let content_id = other
+        .id
+        .as_deref()
+        .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+        .filter(|id| !id.is_empty());
+    // BODYSTRUCTURE gives the encoded size; base64 inflates content by a third.
+    let size = match other.transfer_encoding {
+        ContentEncoding::Base64 => other.octets as u64 * 3 / 4,
+        _ => other.octets as u64,
+    };
+
+    attachments.push(Attachment {
+        filename: filename.unwrap_or_else(|| match mime_type.as_str() {
+            "message/rfc822" => "message.eml".to_string(),
+            _ => format!("attachment-{}", section),
+        }),
+        id: section,
+        size,
+        inline: disposition.disposition == DispositionType::Inline && content_id.is_some(),
+        content_id,
+        mime_type,
+        blob: None,
+        data: None,
+    });
+}
+
+/// Rebuild a header value from `BODYSTRUCTURE` parameters and decode it like a
+/// real header, so RFC 2231 and RFC 2047 encoded names come out readable.
+fn decode_structure_params(value: &str, params: &Option<Vec<(std::borrow::Cow<str>, std::borrow::Cow<str>)>>) -> String {
+    let mut header = format!("X: {}", value);
+    for (key, param) in params.iter().flatten() {
+        header.push_str(&format!("; {}=\"{}\"", key, param.replace('\\', "\\\\").replace('"', "\\\"")));
+    }
+    match parse_header(header.as_bytes()) {
+        Ok((parsed, _)) => parsed.get_value(),
+        Err(_) => header[3..].to_string(),
     }
 }
 
+fn section_path(section: &str) -> Result<Vec<u32>> {
+    section
+        .split('.')
+        .map(|n| n.parse::<u32>().map_err(|_| EmailError::ParseError(format!("Invalid section {:?}", section))))
+        .collect()
+}
+
 fn header_value(mail: &ParsedMail, name: &str) -> Option<String> {
     mail.get_headers()
         .get_first_value(name)
@@ -414,6 +1746,9 @@ fn parse_addresses(mail: &ParsedMail, header_name: &str) -> Vec<EmailAddress> {
 }
 
 fn find_body(part: &ParsedMail, mime: &str) -> Option<String> {
+    if is_attachment(part) {
+        return None;
+    }
     if part.ctype.mimetype.eq_ignore_ascii_case(mime) {
         return part.get_body().ok();
     }
@@ -426,3 +1761,300 @@ fn find_body(part: &ParsedMail, mime: &str) -> Option<String> {
 
     None
 }
+
+/// Whether a leaf part is a file rather than a body alternative.
+fn is_attachment(part: &ParsedMail) -> bool {
+    if part.ctype.mimetype.starts_with("multipart/") {
+        return false;
+    }
+    let disposition = part.get_content_disposition();
+    disposition.disposition == DispositionType::Attachment
+        || disposition.params.contains_key("filename")
+        || part.ctype.params.contains_key("name")
+        || !part.ctype.mimetype.starts_with("text/")
+}
+
+/// Attachment and inline parts of a message, with their content.
+///
+/// Attachments are identified by their IMAP section number (`2`, `1.3`...).
+/// `mailparse` already decodes RFC 2231 and RFC 2047 parameter values.
+fn find_attachments(mail: &ParsedMail) -> Vec<Attachment> {
+    let mut attachments = Vec::new();
+    if mail.subparts.is_empty() {
+        collect_attachments(mail, "1".to_string(), &mut attachments);
+    } else {
+        for (i, sub) in mail.subparts.iter().enumerate() {
+            collect_attachments(sub, (i + 1).to_string(), &mut attachments);
+        }
+    }
+    attachments
+}
+
+fn collect_attachments(part: &ParsedMail, section: String, out: &mut Vec<Attachment>) {
+    if !part.subparts.is_empty() {
+        for (i, sub) in part.subparts.iter().enumerate() {
+            collect_attachments(sub, format!("{}.{}", section, i + 1), out);
+        }
+        return;
+    }
+    if !is_attachment(part) {
+        return;
+    }
+
+    let disposition = part.get_content_disposition();
+    let content_id = header_value(part, "Content-ID")
+        .map(|id| id.trim().trim_start_matches('<').trim_end_matches('>').to_string())
+        .filter(|id| !id.is_empty());
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| match part.ctype.mimetype.as_str() {
+            "message/rfc822" => "message.eml".to_string(),
+            _ => format!("attachment-{}", section),
+        });
+    let data = part.get_body_raw().unwrap_or_default();
+
+    out.push(Attachment {
+        id: section,
+        filename,
+        size: data.len() as u64,
+        mime_type: part.ctype.mimetype.clone(),
+        inline: disposition.disposition == DispositionType::Inline && content_id.is_some(),
+        content_id,
+        blob: None,
+        data: Some(data),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::FileSecretStore;
+
+    fn folder(path: &str, special_use: Option<&str>) -> MailFolder {
+        MailFolder {
+            account_id: "acc".to_string(),
+            path: path.to_string(),
+            name: decode_mailbox_name(path.rsplit('/').next().unwrap()),
+            delimiter: Some("/".to_string()),
+            parent: None,
+            special_use: special_use.map(str::to_string),
+            selectable: true,
+            subscribed: true,
+        }
+    }
+
+    #[test]
+    fn test_special_use_resolution() {
+        let mut folders = vec![
+            folder("INBOX", Some("inbox")),
+            folder("[Gmail]/Sent Mail", Some("sent")),
+            folder("Sent", None),
+            folder("&AMk-l&AOk-ments supprim&AOk-s", None),
+        ];
+        guess_special_use(&mut folders);
+
+        // A mailbox flagged \Sent wins over one merely named "Sent".
+        assert_eq!(folders[2].special_use, None);
+        assert_eq!(folders[3].special_use.as_deref(), Some("trash"));
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let client = EmailClient::new(
+            EmailAccount::new(
+                "me@example.com".into(), "Me".into(), "imap.example.com".into(), 993,
+                "smtp.example.com".into(), 465, "me".into(), "secret".into(), false, false,
+            ),
+            Arc::new(FileSecretStore::open(temp_dir.path()).unwrap()),
+        )
+        .with_folders(folders);
+        assert_eq!(client.mailbox_name("sent"), "[Gmail]/Sent Mail");
+        assert_eq!(client.mailbox_name("trash"), "&AMk-l&AOk-ments supprim&AOk-s");
+        assert_eq!(client.mailbox_name("inbox"), "INBOX");
+        assert_eq!(client.mailbox_name("Projects/2024"), "Projects/2024");
+    }
+
+    #[test]
+    fn test_mailbox_name_utf7() {
+        assert_eq!(decode_mailbox_name("&AMk-l&AOk-ments envoy&AOk-s"), "Éléments envoyés");
+        assert_eq!(encode_mailbox_name("Éléments envoyés"), "&AMk-l&AOk-ments envoy&AOk-s");
+        assert_eq!(encode_mailbox_name("R&D"), "R&-D");
+        assert_eq!(decode_mailbox_name("R&-D"), "R&D");
+        assert_eq!(decode_mailbox_name(&encode_mailbox_name("日本語")), "日本語");
+    }
+
+    #[test]
+    fn test_find_attachments() {
+        let raw = concat!(
+            "Content-Type: multipart/mixed; boundary=\"outer\"\r\n\r\n",
+            "--outer\r\n",
+            "Content-Type: multipart/related; boundary=\"inner\"\r\n\r\n",
+            "--inner\r\n",
+            "Content-Type: text/html\r\n\r\n",
+            "<img src=\"cid:logo@x\">\r\n",
+            "--inner\r\n",
+            "Content-Type: image/png\r\n",
+            "Content-ID: <logo@x>\r\n",
+            "Content-Transfer-Encoding: base64\r\n\r\n",
+            "iVBORw==\r\n",
+            "--inner--\r\n",
+            "--outer\r\n",
+            "Content-Type: text/plain\r\n",
+            "Content-Disposition: attachment; filename*=UTF-8''r%C3%A9sum%C3%A9.txt\r\n\r\n",
+            "not the body\r\n",
+            "--outer\r\n",
+            "Content-Type: application/pdf; name=\"=?UTF-8?Q?facture_n=C2=B01.pdf?=\"\r\n\r\n",
+            "%PDF\r\n",
+            "--outer--\r\n",
+        );
+        let parsed = parse_mail(raw.as_bytes()).unwrap();
+        let attachments = find_attachments(&parsed);
+
+        let summary: Vec<(&str, &str, bool)> = attachments
+            .iter()
+            .map(|a| (a.id.as_str(), a.filename.as_str(), a.inline))
+            .collect();
+        assert_eq!(summary, vec![("1.2", "attachment-1.2", true), ("2", "résumé.txt", false), ("3", "facture n°1.pdf", false)]);
+        assert_eq!(attachments[0].content_id.as_deref(), Some("logo@x"));
+        assert_eq!(attachments[0].data.as_deref(), Some(&[0x89, b'P', b'N', b'G'][..]));
+        assert_eq!(find_body(&parsed, "text/plain"), None);
+    }
+
+    #[test]
+    fn test_walk_structure() {
+        let response = concat!(
+            "* 1 FETCH (BODYSTRUCTURE (",
+            "((\"text\" \"plain\" (\"charset\" \"utf-8\") NIL NIL \"7bit\" 10 1 NIL NIL NIL NIL)",
+            "(\"text\" \"html\" (\"charset\" \"utf-8\") NIL NIL \"quoted-printable\" 20 1 NIL NIL NIL NIL) \"alternative\" NIL NIL NIL NIL)",
+            "(\"image\" \"png\" NIL \"<logo@x>\" NIL \"base64\" 400 NIL (\"inline\" NIL) NIL NIL)",
+            "(\"text\" \"plain\" NIL NIL NIL \"7bit\" 5 1 NIL (\"attachment\" (\"filename*\" \"UTF-8''r%C3%A9sum%C3%A9.txt\")) NIL NIL)",
+            "(\"application\" \"pdf\" (\"name\" \"=?UTF-8?Q?facture_n=C2=B01.pdf?=\") NIL NIL \"base64\" 4 NIL NIL NIL NIL)",
+            " \"mixed\" (\"boundary\" \"b\") NIL NIL NIL))\r\n",
+        );
+        let structure = match imap_proto::parser::parse_response(response.as_bytes()).unwrap().1 {
+            imap_proto::Response::Fetch(_, attributes) => attributes
+                .into_iter()
+                .find_map(|a| match a {
+                    imap_proto::AttributeValue::BodyStructure(bs) => Some(bs),
+                    _ => None,
+                })
+                .unwrap(),
+            other => panic!("unexpected response {:?}", other),
+        };
+
+        let mut body_parts = Vec::new();
+        let mut attachments = Vec::new();
+        match &structure {
+            BodyStructure::Multipart { bodies, .. } => {
+                for (i, part) in bodies.iter().enumerate() {
+                    walk_structure(part, (i + 1).to_string(), &mut body_parts, &mut attachments);
+                }
+            }
+            _ => panic!("expected a multipart structure"),
+        }
+
+        let sections: Vec<(&str, &str)> = body_parts.iter().map(|p| (p.section.as_str(), p.mime_type.as_str())).collect();
+        assert_eq!(sections, vec![("1.1", "text/plain"), ("1.2", "text/html")]);
+
+        let summary: Vec<(&str, &str, u64, bool)> = attachments
+            .iter()
+            .map(|a| (a.id.as_str(), a.filename.as_str(), a.size, a.inline))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![("2", "attachment-2", 300, true), ("3", "résumé.txt", 5, false), ("4", "facture n°1.pdf", 3, false)]
+        );
+        assert_eq!(attachments[0].content_id.as_deref(), Some("logo@x"));
+    }
+
+    #[test]
+    fn test_build_html_message() {
+        let part = |filename: &str, inline: bool, content_id: Option<&str>| Attachment {
+            id: filename.to_string(),
+            filename: filename.to_string(),
+            size: 3,
+            mime_type: "image/png".to_string(),
+            content_id: content_id.map(str::to_string),
+            inline,
+            blob: None,
+            data: Some(vec![1, 2, 3]),
+        };
+        let email = NewEmail {
+            account_id: None,
+            from: EmailAddress { name: "Me".to_string(), email: "me@example.com".to_string() },
+            to: vec![EmailAddress { name: String::new(), email: "you@example.com".to_string() }],
+            cc: None,
+            bcc: None,
+            subject: "Hello".to_string(),
+            body: String::new(),
+            html_body: Some("<p>Hello <b>you</b></p><img src=\"cid:logo@x\">".to_string()),
+            attachments: Some(vec![part("logo.png", true, Some("<logo@x>")), part("photo.png", false, None)]),
+            in_reply_to: Some("<1@x>".to_string()),
+            references: Some(vec!["<0@x>".to_string(), "<1@x>".to_string()]),
+            message_id: None,
+        };
+
+        let raw = build_message(&email).unwrap().formatted();
+        let parsed = parse_mail(&raw).unwrap();
+        assert_eq!(parsed.ctype.mimetype, "multipart/mixed");
+        assert_eq!(parsed.headers.get_first_value("In-Reply-To").as_deref(), Some("<1@x>"));
+        assert_eq!(parsed.headers.get_first_value("References").as_deref(), Some("<0@x> <1@x>"));
+        assert!(parsed.headers.get_first_value("Message-ID").unwrap().ends_with("@example.com>"));
+
+        let alternative = &parsed.subparts[0];
+        assert_eq!(alternative.ctype.mimetype, "multipart/alternative");
+        assert_eq!(alternative.subparts[0].get_body().unwrap().trim(), "Hello you");
+        let related = &alternative.subparts[1];
+        assert_eq!(related.ctype.mimetype, "multipart/related");
+        assert_eq!(related.subparts[0].ctype.mimetype, "text/html");
+        assert_eq!(related.subparts[1].headers.get_first_value("Content-ID").as_deref(), Some("<logo@x>"));
+
+        let attached = &parsed.subparts[1];
+        assert_eq!(attached.get_content_disposition().params.get("filename").map(String::as_str), Some("photo.png"));
+    }
+
+    #[test]
+    fn test_parse_copyuid() {
+        assert_eq!(
+            parse_copyuid(b"* OK [COPYUID 1700000000 12 345] Moved\r\n"),
+            Some(ServerUid { uid_validity: 1700000000, uid: 345 })
+        );
+        assert_eq!(parse_copyuid(b"A3 OK Done\r\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_watch_stop_wakes_async_waiters() {
+        let stop = Arc::new(WatchStop::new());
+        let waiter = tokio::spawn({
+            let stop = stop.clone();
+            async move { stop.stopped().await }
+        });
+        tokio::task::yield_now().await;
+        stop.stop();
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        // Already stopped: returns right away.
+        stop.stopped().await;
+    }
+
+    #[test]
+    fn test_check_pinned() {
+        let certificate = b"not really DER".to_vec();
+        let found = fingerprint(&certificate);
+        assert_eq!(found.len(), 32 * 3 - 1);
+        let host = "mail.example.com";
+
+        assert_eq!(check_pinned(host, Some(&found.to_lowercase()), Some(certificate.clone()), false).unwrap(), found);
+        assert_eq!(check_pinned(host, None, Some(certificate.clone()), true).unwrap(), found);
+        assert!(matches!(
+            check_pinned(host, None, Some(certificate.clone()), false),
+            Err(EmailError::ConnectionError(_))
+        ));
+        assert!(matches!(
+            check_pinned(host, Some("AB:CD"), Some(certificate), true),
+            Err(EmailError::CertificateChanged { pinned, found: changed, .. }) if pinned == "AB:CD" && changed == found
+        ));
+        assert!(check_pinned(host, None, None, true).is_err());
+    }
+}
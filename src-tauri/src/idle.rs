@@ -0,0 +1,118 @@
+use crate::database::Database;
+use crate::email::{EmailClient, WatchStop};
+use crate::models::{EmailAccount, NewMailEvent};
+use crate::sync;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter};
+use tokio::sync::{mpsc, Mutex};
+
+/// Event emitted after new mail was stored by a push-triggered sync.
+pub const NEW_MAIL_EVENT: &str = "mail://new";
+
+/// The only folder watched in the background.
+const WATCHED_FOLDER: &str = "inbox";
+
+const MIN_BACKOFF: Duration = Duration::from_secs(15);
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// One background watcher per account, keeping an IDLE session on INBOX.
+#[derive(Default)]
+pub struct IdleManager {
+    watchers: std::sync::Mutex<HashMap<String, Arc<WatchStop>>>,
+}
+
+impl IdleManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start watching `account`, replacing any watcher it already had.
+    pub fn start(&self, app: AppHandle, db: Arc<Mutex<Database>>, account: EmailAccount) {
+        let stop = Arc::new(WatchStop::new());
+        let previous = self
+            .watchers
+            .lock()
+            .unwrap()
+            .insert(account.id.clone(), stop.clone());
+        if let Some(previous) = previous {
+            previous.stop();
+        }
+
+        tauri::async_runtime::spawn(watch_account(app, db, account, stop));
+    }
+
+    /// Stop the watcher of an account, closing its IDLE session.
+    pub fn stop(&self, account_id: &str) {
+        if let Some(stop) = self.watchers.lock().unwrap().remove(account_id) {
+            stop.stop();
+        }
+    }
+}
+
+async fn watch_account(app: AppHandle, db: Arc<Mutex<Database>>, account: EmailAccount, stop: Arc<WatchStop>) {
+    let account = match sync::pin_certificates(&db, account.clone()).await {
+        Ok(account) => account,
+        Err(e) => {
+            log::warn!("Could not pin the certificates of account={}: {}", account.id, e);
+            account
+        }
+    };
+    let (changed_tx, mut changed_rx) = mpsc::unbounded_channel();
+    let secrets = db.lock().await.secrets();
+
+    // Sync whatever arrived while we were not watching, then on every change.
+    let syncer = {
+        let stop = stop.clone();
+        let account_id = account.id.clone();
+        tauri::async_runtime::spawn(async move {
+            loop {
+                if stop.is_stopped() {
+                    break;
+                }
+                match sync::sync_folder(&db, &account_id, WATCHED_FOLDER).await {
+                    Ok(stored) if !stored.is_empty() => {
+                        let event = NewMailEvent {
+                            account_id: account_id.clone(),
+                            folder: WATCHED_FOLDER.to_string(),
+                            count: stored.len(),
+                            ids: stored.into_iter().map(|email| email.id).collect(),
+                        };
+                        if let Err(e) = app.emit(NEW_MAIL_EVENT, event) {
+                            log::warn!("Failed to emit {}: {}", NEW_MAIL_EVENT, e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::warn!("Push sync failed for account={}: {}", account_id, e),
+                }
+                if changed_rx.recv().await.is_none() {
+                    break;
+                }
+                // Coalesce bursts of notifications into a single sync.
+                while changed_rx.try_recv().is_ok() {}
+            }
+        })
+    };
+
+    let client = EmailClient::new(account.clone(), secrets);
+    let mut backoff = MIN_BACKOFF;
+    while !stop.is_stopped() {
+        match client.watch_mailbox(WATCHED_FOLDER, stop.clone(), changed_tx.clone()).await {
+            Ok(()) => backoff = MIN_BACKOFF,
+            Err(e) => {
+                log::warn!("IDLE watcher for account={} failed: {}, retrying in {:?}", account.id, e, backoff);
+                tokio::select! {
+                    _ = stop.stopped() => break,
+                    _ = tokio::time::sleep(backoff) => {}
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                // Catch up on anything missed while disconnected.
+                let _ = changed_tx.send(());
+            }
+        }
+    }
+
+    drop(changed_tx);
+    let _ = syncer.await;
+}
@@ -1,18 +1,35 @@
+mod blobs;
 mod database;
 mod email;
 mod models;
 mod commands;
+mod sync;
+mod outbox;
+mod idle;
+mod threading;
+mod search;
+mod compose;
+mod secrets;
+mod oauth;
+mod pool;
 
 use tauri::Manager;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, Notify};
 use database::Database;
+use idle::IdleManager;
+use pool::SessionPool;
 use std::path::PathBuf;
 use env_logger;
 
 pub struct AppState {
     pub db: Arc<Mutex<Database>>,
     pub db_path: String,
+    /// Wakes the outbox worker after an operation is queued.
+    pub outbox: Arc<Notify>,
+    pub idle: IdleManager,
+    /// IMAP sessions shared by all operations, also reachable through `db`.
+    pub sessions: Arc<SessionPool>,
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -36,17 +53,40 @@ pub fn run() {
 
             let db = tauri::async_runtime::block_on(Database::new(&db_path))
                 .expect("Failed to initialize database");
+            let sessions = db.sessions();
             let state = AppState {
                 db: Arc::new(Mutex::new(db)),
                 db_path: db_path.to_string_lossy().to_string(),
+                outbox: Arc::new(Notify::new()),
+                idle: IdleManager::new(),
+                sessions,
             };
+            tauri::async_runtime::spawn(outbox::run_worker(state.db.clone(), state.outbox.clone()));
+            tauri::async_runtime::spawn(pool::run_sweeper(state.sessions.clone()));
+
+            let accounts = tauri::async_runtime::block_on(async {
+                state.db.lock().await.get_all_accounts().await
+            })
+            .unwrap_or_else(|e| {
+                log::error!("Failed to load accounts, not watching any: {}", e);
+                Vec::new()
+            });
+            for account in accounts {
+                state.idle.start(handle.clone(), state.db.clone(), account);
+            }
             handle.manage(state);
             
             Ok(())
         })
         .invoke_handler(tauri::generate_handler![
-            commands::fetch_emails,
+            commands::list_emails,
             commands::send_email,
+            commands::save_draft,
+            commands::update_draft,
+            commands::discard_draft,
+            commands::send_draft,
+            commands::compose_reply,
+            commands::compose_forward,
             commands::mark_as_read,
             commands::mark_as_unread,
             commands::toggle_star,
@@ -55,11 +95,22 @@ pub fn run() {
             commands::move_to_folder,
             commands::save_account,
             commands::remove_account,
+            commands::oauth2_authorize,
             commands::get_accounts,
             commands::test_connection,
             commands::test_imap_connection,
             commands::test_smtp_connection,
             commands::sync_emails,
+            commands::list_folders,
+            commands::get_threads,
+            commands::get_attachment,
+            commands::save_attachment,
+            commands::load_email_body,
+            commands::search_emails,
+            commands::create_folder,
+            commands::rename_folder,
+            commands::delete_folder,
+            commands::subscribe_folder,
             commands::get_db_path,
         ])
         .run(tauri::generate_context!())
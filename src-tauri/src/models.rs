@@ -22,10 +22,31 @@ pub struct Attachment {
     pub size: u64,
     #[serde(rename = "mimeType")]
     pub mime_type: String,
+    /// `Content-ID` of inline parts, referenced as `cid:` from the HTML body.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "contentId")]
+    pub content_id: Option<String>,
+    #[serde(default)]
+    pub inline: bool,
+    /// Hash of the content in the blob store.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blob: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub data: Option<Vec<u8>>,
 }
 
+fn default_true() -> bool {
+    true
+}
+
+/// A text part of a message on the server, by IMAP section number.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BodyPart {
+    pub section: String,
+    #[serde(rename = "mimeType")]
+    pub mime_type: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Email {
     pub id: String,
@@ -51,6 +72,31 @@ pub struct Email {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub message_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "inReplyTo")]
+    pub in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub references: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<String>,
+    /// Hash of the raw RFC 822 message in the blob store.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "rawBlob")]
+    pub raw_blob: Option<String>,
+    /// Raw message waiting to be written to the blob store by `create_email`.
+    #[serde(skip)]
+    pub raw: Option<Vec<u8>>,
+    /// False while only the headers and structure were synced.
+    #[serde(rename = "bodyLoaded", default = "default_true")]
+    pub body_loaded: bool,
+    /// Size of the whole message on the server, in bytes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+    /// Text parts to download when the body is loaded.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "bodyParts")]
+    pub body_parts: Option<Vec<BodyPart>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "fromUserId")]
     pub from_user_id: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -78,20 +124,135 @@ pub struct EmailAccount {
     #[serde(rename = "smtpPort")]
     pub smtp_port: u16,
     pub username: String,
+    /// Only ever received from the webview: it is kept in the secret store,
+    /// never written to the `account` table nor sent back.
+    #[serde(default, skip_serializing)]
     pub password: String,
-    #[serde(rename = "useSsl")]
-    pub use_ssl: bool,
+    #[serde(rename = "imapSecurity")]
+    pub imap_security: Security,
+    #[serde(rename = "smtpSecurity")]
+    pub smtp_security: Security,
+    /// Trust on first use: a certificate that does not verify is accepted
+    /// when it matches `imap_fingerprint`, pinned by the connection test or,
+    /// failing that, by the first background connection.
     #[serde(rename = "allowInvalidCerts")]
     pub allow_invalid_certs: bool,
     #[serde(rename = "allowInvalidSmtpCerts")]
     pub allow_invalid_smtp_certs: bool,
+    /// SHA-256 fingerprint of the pinned IMAP server certificate.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "imapFingerprint")]
+    pub imap_fingerprint: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "smtpFingerprint")]
+    pub smtp_fingerprint: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "userId")]
     pub user_id: Option<String>,
+    /// Signature of the account's own address; aliases carry their own.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+    /// Aliases that may send through this account, besides its own address.
+    #[serde(default)]
+    pub identities: Vec<Identity>,
+    /// How the account logs in to IMAP and SMTP.
+    #[serde(default)]
+    pub auth: AuthMethod,
+}
+
+/// How a connection to a server is encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Security {
+    /// TLS from the first byte (IMAPS on 993, SMTPS on 465).
+    Tls,
+    /// Plaintext upgraded with `STARTTLS`, which the server must offer.
+    StartTls,
+    /// No encryption at all.
+    None,
+}
+
+impl Security {
+    /// The usual security of an IMAP server listening on `port`: implicit
+    /// TLS on 993 only, as local bridges (e.g. 1143) start in plaintext.
+    pub fn default_for_imap_port(port: u16) -> Self {
+        match port {
+            993 => Security::Tls,
+            _ => Security::StartTls,
+        }
+    }
+
+    /// The usual security of an SMTP server listening on `port`.
+    pub fn default_for_smtp_port(port: u16) -> Self {
+        match port {
+            465 => Security::Tls,
+            _ => Security::StartTls,
+        }
+    }
+}
+
+/// Login method of an account.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AuthMethod {
+    /// `LOGIN` / SMTP `AUTH PLAIN` with the stored password.
+    #[default]
+    Password,
+    /// SASL with an OAuth2 access token, the tokens being in the secret store.
+    #[serde(rename = "oauth2")]
+    OAuth2(OAuth2Config),
+}
+
+/// Endpoints and client registration of an OAuth2 provider.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OAuth2Config {
+    #[serde(rename = "authUrl")]
+    pub auth_url: String,
+    #[serde(rename = "tokenUrl")]
+    pub token_url: String,
+    #[serde(rename = "clientId")]
+    pub client_id: String,
+    /// Some providers (Google) issue one even to desktop apps, where it is not secret.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "clientSecret")]
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+    /// SASL mechanism used for IMAP; SMTP always uses XOAUTH2.
+    #[serde(default)]
+    pub mechanism: SaslMechanism,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SaslMechanism {
+    #[default]
+    Xoauth2,
+    Oauthbearer,
+}
+
+impl SaslMechanism {
+    pub fn name(self) -> &'static str {
+        match self {
+            SaslMechanism::Xoauth2 => "XOAUTH2",
+            SaslMechanism::Oauthbearer => "OAUTHBEARER",
+        }
+    }
+}
+
+/// An address an account sends as, with its display name and signature.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub email: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NewEmail {
+    /// Account to send through; resolved from `from` when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
     pub from: EmailAddress,
     pub to: Vec<EmailAddress>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -99,15 +260,233 @@ pub struct NewEmail {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bcc: Option<Vec<EmailAddress>>,
     pub subject: String,
+    /// Plain text body; generated from `html_body` when empty.
     pub body: String,
     #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "htmlBody")]
+    pub html_body: Option<String>,
+    /// Inline attachments with a `content_id` are embedded in the HTML body.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub attachments: Option<Vec<Attachment>>,
+    /// Message-ID of the message replied to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "inReplyTo")]
+    pub in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub references: Option<Vec<String>>,
+    /// Message-ID to send with; one is generated when absent.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+}
+
+/// A mailbox discovered on the server with `LIST`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailFolder {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    /// Full mailbox name on the server, e.g. `[Gmail]/Sent Mail`.
+    pub path: String,
+    /// Last component of the path, for display.
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delimiter: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+    /// Logical role from RFC 6154 (`sent`, `drafts`, `trash`, `junk`, `archive`,
+    /// `all`, `flagged`), or `inbox`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "specialUse")]
+    pub special_use: Option<String>,
+    pub selectable: bool,
+    pub subscribed: bool,
+}
+
+impl MailFolder {
+    /// Name stored in `Email.folder` for messages of this mailbox.
+    pub fn logical_name(&self) -> &str {
+        self.special_use.as_deref().unwrap_or(&self.path)
+    }
+}
+
+/// The headers of a stored email that threading looks at.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ThreadHeaders {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "inReplyTo")]
+    pub in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub references: Option<Vec<String>>,
+    pub subject: String,
+    pub date: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<String>,
+}
+
+/// A conversation, as listed by `get_threads`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Thread {
+    pub id: String,
+    pub subject: String,
+    #[serde(rename = "messageCount")]
+    pub message_count: usize,
+    #[serde(rename = "unreadCount")]
+    pub unread_count: usize,
+    pub starred: bool,
+    pub participants: Vec<EmailAddress>,
+    #[serde(rename = "latestDate")]
+    pub latest_date: String,
+    /// Messages of the conversation, oldest first.
+    #[serde(rename = "emailIds")]
+    pub email_ids: Vec<String>,
+}
+
+/// An email as shown in a message list: no body, just a short preview.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailListItem {
+    pub id: String,
+    pub from: EmailAddress,
+    pub to: Vec<EmailAddress>,
+    pub subject: String,
+    pub preview: String,
+    pub date: String,
+    pub read: bool,
+    pub starred: bool,
+    pub folder: String,
+    #[serde(rename = "hasAttachments")]
+    pub has_attachments: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "threadId")]
+    pub thread_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EmailSort {
+    #[default]
+    Date,
+    Sender,
+    Subject,
+    Size,
+}
+
+/// One page request of `list_emails`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmailListQuery {
+    pub folder: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// `nextCursor` of the previous page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    pub sort: EmailSort,
+    pub ascending: bool,
+    pub unread: bool,
+    pub starred: bool,
+    #[serde(rename = "hasAttachments")]
+    pub has_attachments: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmailPage {
+    pub items: Vec<EmailListItem>,
+    /// Absent on the last page.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "nextCursor")]
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FolderSyncState {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    pub folder: String,
+    #[serde(rename = "uidValidity")]
+    pub uid_validity: u32,
+    /// Highest UID stored locally for this folder.
+    #[serde(rename = "lastUid")]
+    pub last_uid: u32,
+    /// Lowest UID stored locally; older messages are backfilled on later syncs.
+    #[serde(rename = "oldestUid")]
+    pub oldest_uid: u32,
+    /// CONDSTORE HIGHESTMODSEQ at the last sync, when the server supports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "highestModSeq")]
+    pub highest_mod_seq: Option<u64>,
+}
+
+/// A server-side change waiting in the outbox.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum PendingAction {
+    SetRead { read: bool },
+    SetStarred { starred: bool },
+    Move { target: String },
+    Delete,
+    Send { email: Box<NewEmail> },
+    /// Mirror the local draft `email_id` to the server Drafts folder,
+    /// replacing the revision at `folder`/`uid` when there is one.
+    UploadDraft,
+    /// Store the raw message of the sent email `email_id` in the server Sent folder.
+    AppendSent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingOperation {
+    pub id: String,
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "emailId")]
+    pub email_id: Option<String>,
+    /// Server folder and UID of the message, for IMAP operations.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub folder: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uid: Option<u32>,
+    pub action: PendingAction,
+    #[serde(rename = "createdAt")]
+    pub created_at: String,
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "lastError")]
+    pub last_error: Option<String>,
+}
+
+/// Payload of the `mail://new` event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewMailEvent {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    pub folder: String,
+    pub count: usize,
+    pub ids: Vec<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ConnectionTestResult {
     pub success: bool,
     pub message: String,
+    /// Certificates to pin: those trusted on first use, or the new ones
+    /// when `certificate_changed`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "imapFingerprint")]
+    pub imap_fingerprint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "smtpFingerprint")]
+    pub smtp_fingerprint: Option<String>,
+    #[serde(rename = "certificateChanged")]
+    pub certificate_changed: bool,
 }
 
 impl Email {
@@ -134,6 +513,14 @@ impl Email {
             attachments: None,
             account_id: None,
             message_id: None,
+            in_reply_to: None,
+            references: None,
+            thread_id: None,
+            raw_blob: None,
+            raw: None,
+            body_loaded: true,
+            size: None,
+            body_parts: None,
             from_user_id: None,
             to_user_ids: None,
             cc_user_ids: None,
@@ -142,6 +529,109 @@ impl Email {
     }
 }
 
+impl PendingOperation {
+    pub fn new(account_id: String, action: PendingAction) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            account_id,
+            email_id: None,
+            folder: None,
+            uid: None,
+            action,
+            created_at: Utc::now().to_rfc3339(),
+            attempts: 0,
+            last_error: None,
+        }
+    }
+
+    /// Target a message previously synced from `folder` with `uid`.
+    pub fn for_message(email: &Email, uid: u32, action: PendingAction) -> Option<Self> {
+        let mut op = Self::new(email.account_id.clone()?, action);
+        op.email_id = Some(email.id.clone());
+        op.folder = Some(email.folder.clone());
+        op.uid = Some(uid);
+        Some(op)
+    }
+}
+
+impl Email {
+    /// A local copy of an outgoing message, filed in `folder`.
+    pub fn from_new(email: NewEmail, folder: &str) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            from: email.from,
+            to: email.to,
+            cc: email.cc,
+            bcc: email.bcc,
+            subject: email.subject,
+            body: email.body,
+            html_body: email.html_body,
+            date: Utc::now().to_rfc3339(),
+            read: true,
+            starred: false,
+            folder: folder.to_string(),
+            attachments: email.attachments,
+            account_id: email.account_id,
+            message_id: email.message_id,
+            in_reply_to: email.in_reply_to,
+            references: email.references,
+            thread_id: None,
+            raw_blob: None,
+            raw: None,
+            body_loaded: true,
+            size: None,
+            body_parts: None,
+            from_user_id: None,
+            to_user_ids: None,
+            cc_user_ids: None,
+            bcc_user_ids: None,
+        }
+    }
+
+    /// The message as something to send; attachment contents are not included.
+    pub fn to_new_email(&self) -> NewEmail {
+        NewEmail {
+            account_id: self.account_id.clone(),
+            from: self.from.clone(),
+            to: self.to.clone(),
+            cc: self.cc.clone(),
+            bcc: self.bcc.clone(),
+            subject: self.subject.clone(),
+            body: self.body.clone(),
+            html_body: self.html_body.clone(),
+            attachments: self.attachments.clone(),
+            in_reply_to: self.in_reply_to.clone(),
+            references: self.references.clone(),
+            message_id: self.message_id.clone(),
+        }
+    }
+
+    /// UID of the message on the IMAP server, for emails synced under an
+    /// id built by `imap_email_id`.
+    pub fn imap_uid(&self) -> Option<u32> {
+        let account_id = self.account_id.as_deref()?;
+        let location = self.id.strip_prefix(account_id)?.strip_prefix(':')?;
+        let mut fields = location.rsplitn(3, ':');
+        let uid = fields.next()?.parse().ok()?;
+        fields.next()?.parse::<u32>().ok()?;
+        fields.next().filter(|folder| !folder.is_empty())?;
+        Some(uid)
+    }
+}
+
+/// Id of the message `uid` of `folder` under `uid_validity`.
+///
+/// UIDs are only unique within one mailbox and one UIDVALIDITY, so both are
+/// part of the id.
+pub fn imap_email_id(account_id: &str, folder: &str, uid_validity: u32, uid: u32) -> String {
+    format!("{}{}", imap_folder_prefix(account_id, folder, uid_validity), uid)
+}
+
+/// Prefix shared by the ids of the messages of `folder` under `uid_validity`.
+pub fn imap_folder_prefix(account_id: &str, folder: &str, uid_validity: u32) -> String {
+    format!("{}:{}:{}:", account_id, folder, uid_validity)
+}
+
 impl EmailAccount {
     pub fn new(
         email: String,
@@ -152,7 +642,6 @@ impl EmailAccount {
         smtp_port: u16,
         username: String,
         password: String,
-        use_ssl: bool,
         allow_invalid_certs: bool,
         allow_invalid_smtp_certs: bool,
     ) -> Self {
@@ -166,10 +655,33 @@ impl EmailAccount {
             smtp_port,
             username,
             password,
-            use_ssl,
+            imap_security: Security::default_for_imap_port(imap_port),
+            smtp_security: Security::default_for_smtp_port(smtp_port),
             allow_invalid_certs,
             allow_invalid_smtp_certs,
+            imap_fingerprint: None,
+            smtp_fingerprint: None,
             user_id: None,
+            signature: None,
+            identities: Vec::new(),
+            auth: AuthMethod::Password,
         }
     }
+
+    /// The account address first, then its aliases.
+    pub fn all_identities(&self) -> Vec<Identity> {
+        let primary = Identity {
+            email: self.email.clone(),
+            name: self.name.clone(),
+            signature: self.signature.clone(),
+        };
+        std::iter::once(primary).chain(self.identities.iter().cloned()).collect()
+    }
+
+    /// The identity sending as `address`, compared case-insensitively.
+    pub fn identity_for(&self, address: &str) -> Option<Identity> {
+        self.all_identities()
+            .into_iter()
+            .find(|identity| identity.email.trim().eq_ignore_ascii_case(address.trim()))
+    }
 }
@@ -0,0 +1,384 @@
+use crate::models::{OAuth2Config, SaslMechanism};
+use crate::secrets::{self, SecretError, SecretStore};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::Utc;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use url::Url;
+
+/// How long the user has to sign in in the browser.
+const AUTHORIZE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Access tokens are refreshed this many seconds before they expire.
+const REFRESH_MARGIN: i64 = 60;
+
+#[derive(Error, Debug)]
+pub enum OAuthError {
+    #[error("OAuth2 request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("OAuth2 authorization failed: {0}")]
+    Denied(String),
+    #[error("Invalid OAuth2 configuration: {0}")]
+    Config(String),
+    #[error("OAuth2 redirect error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error("The account is not signed in")]
+    NotAuthorized,
+}
+
+pub type Result<T> = std::result::Result<T, OAuthError>;
+
+/// Tokens granted to an account, kept in the secret store.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TokenSet {
+    #[serde(rename = "accessToken")]
+    pub access_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: Option<String>,
+    /// Unix time the access token expires at, when the provider tells.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<i64>,
+}
+
+impl TokenSet {
+    pub fn expires_soon(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|at| at - REFRESH_MARGIN <= now)
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// A PKCE code verifier and its `S256` challenge (RFC 7636).
+pub struct Pkce {
+    pub verifier: String,
+    pub challenge: String,
+}
+
+impl Pkce {
+    pub fn new() -> Self {
+        let verifier = random_token();
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+        Self { verifier, challenge }
+    }
+}
+
+fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    SystemRandom::new().fill(&mut bytes).expect("system randomness");
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// The page the user signs in on to grant access to the mailbox of `login_hint`.
+pub fn authorization_url(config: &OAuth2Config, redirect_uri: &str, state: &str, pkce: &Pkce, login_hint: &str) -> Result<Url> {
+    let scope = config.scopes.join(" ");
+    Url::parse_with_params(
+        &config.auth_url,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.client_id.as_str()),
+            ("redirect_uri", redirect_uri),
+            ("scope", scope.as_str()),
+            ("state", state),
+            ("code_challenge", pkce.challenge.as_str()),
+            ("code_challenge_method", "S256"),
+            ("login_hint", login_hint),
+            // Google only issues a refresh token when asked for offline access.
+            ("access_type", "offline"),
+            ("prompt", "consent"),
+        ],
+    )
+    .map_err(|e| OAuthError::Config(format!("{}: {}", config.auth_url, e)))
+}
+
+/// Run the authorization-code flow with PKCE, the code coming back on a
+/// loopback redirect (RFC 8252).
+///
+/// `open` shows the authorization page to the user, normally in their browser.
+pub async fn authorize(
+    config: &OAuth2Config,
+    login_hint: &str,
+    open: impl FnOnce(&str) -> std::io::Result<()>,
+) -> Result<TokenSet> {
+    let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+    let redirect_uri = format!("http://127.0.0.1:{}/", listener.local_addr()?.port());
+    let state = random_token();
+    let pkce = Pkce::new();
+
+    open(authorization_url(config, &redirect_uri, &state, &pkce, login_hint)?.as_str())?;
+    let code = tokio::time::timeout(AUTHORIZE_TIMEOUT, receive_code(&listener, &state))
+        .await
+        .map_err(|_| OAuthError::Denied("timed out waiting for the browser".to_string()))??;
+
+    request_tokens(
+        config,
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code.as_str()),
+            ("redirect_uri", redirect_uri.as_str()),
+            ("code_verifier", pkce.verifier.as_str()),
+        ],
+    )
+    .await
+}
+
+/// Wait for the browser to come back with the authorization code.
+async fn receive_code(listener: &TcpListener, state: &str) -> Result<String> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let mut request = vec![0u8; 8192];
+        let read = stream.read(&mut request).await?;
+        let request = String::from_utf8_lossy(&request[..read]);
+        let target = request.split_whitespace().nth(1).unwrap_or("/");
+        let params: HashMap<String, String> = Url::parse("http://127.0.0.1")
+            .and_then(|base| base.join(target))
+            .map(|url| url.query_pairs().into_owned().collect())
+            .unwrap_or_default();
+
+        // Anything else the browser asks for, like a favicon.
+        if params.get("state").map(String::as_str) != Some(state) {
+            respond(&mut stream, "404 Not Found", "").await?;
+            continue;
+        }
+        if let Some(code) = params.get("code") {
+            respond(&mut stream, "200 OK", "Connexion réussie, vous pouvez fermer cette page.").await?;
+            return Ok(code.clone());
+        }
+
+        let error = params
+            .get("error_description")
+            .or_else(|| params.get("error"))
+            .cloned()
+            .unwrap_or_else(|| "no authorization code".to_string());
+        respond(&mut stream, "400 Bad Request", "La connexion a échoué, vous pouvez fermer cette page.").await?;
+        return Err(OAuthError::Denied(error));
+    }
+}
+
+async fn respond(stream: &mut TcpStream, status: &str, message: &str) -> std::io::Result<()> {
+    let body = format!("<!doctype html><meta charset=\"utf-8\"><title>Missive</title><p>{}</p>", message);
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// New tokens obtained with the refresh token of `tokens`.
+pub async fn refresh(config: &OAuth2Config, tokens: &TokenSet) -> Result<TokenSet> {
+    let refresh_token = tokens.refresh_token.as_deref().ok_or(OAuthError::NotAuthorized)?;
+    let mut refreshed = request_tokens(
+        config,
+        &[("grant_type", "refresh_token"), ("refresh_token", refresh_token)],
+    )
+    .await?;
+    // Not every provider rotates the refresh token.
+    if refreshed.refresh_token.is_none() {
+        refreshed.refresh_token = tokens.refresh_token.clone();
+    }
+    Ok(refreshed)
+}
+
+async fn request_tokens(config: &OAuth2Config, params: &[(&str, &str)]) -> Result<TokenSet> {
+    let mut form = params.to_vec();
+    form.push(("client_id", &config.client_id));
+    if let Some(secret) = &config.client_secret {
+        form.push(("client_secret", secret));
+    }
+
+    let response = reqwest::Client::new()
+        .post(&config.token_url)
+        .form(&form)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let message = match response.json::<ErrorResponse>().await {
+            Ok(error) => error.error_description.unwrap_or(error.error),
+            Err(_) => status.to_string(),
+        };
+        return Err(OAuthError::Denied(message));
+    }
+
+    let granted: TokenResponse = response.json().await?;
+    Ok(TokenSet {
+        access_token: granted.access_token,
+        refresh_token: granted.refresh_token,
+        expires_at: granted.expires_in.map(|seconds| Utc::now().timestamp() + seconds),
+    })
+}
+
+pub fn store_tokens(secrets: &dyn SecretStore, account_id: &str, tokens: &TokenSet) -> Result<()> {
+    let tokens = serde_json::to_string(tokens).expect("tokens serialize");
+    secrets.set(&secrets::tokens_key(account_id), &tokens)?;
+    Ok(())
+}
+
+/// A usable access token for account `account_id`, refreshed first when it
+/// is about to expire.
+pub async fn access_token(config: &OAuth2Config, secrets: &dyn SecretStore, account_id: &str) -> Result<String> {
+    let stored = secrets
+        .get(&secrets::tokens_key(account_id))?
+        .ok_or(OAuthError::NotAuthorized)?;
+    let tokens: TokenSet = serde_json::from_str(&stored).map_err(|_| OAuthError::NotAuthorized)?;
+    if !tokens.expires_soon(Utc::now().timestamp()) {
+        return Ok(tokens.access_token);
+    }
+
+    let refreshed = refresh(config, &tokens).await?;
+    store_tokens(secrets, account_id, &refreshed)?;
+    Ok(refreshed.access_token)
+}
+
+/// The initial client response of SASL `mechanism`: Google's XOAUTH2, or
+/// OAUTHBEARER from RFC 7628.
+pub fn sasl_response(mechanism: SaslMechanism, user: &str, host: &str, port: u16, token: &str) -> String {
+    match mechanism {
+        SaslMechanism::Xoauth2 => format!("user={}\x01auth=Bearer {}\x01\x01", user, token),
+        SaslMechanism::Oauthbearer => {
+            // GS2 header escaping (RFC 5801).
+            let user = user.replace('=', "=3D").replace(',', "=2C");
+            format!("n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01", user, host, port, token)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::FileSecretStore;
+
+    /// A token endpoint accepting `code` for the verifier of `challenge`, and
+    /// refresh token `r1`. Returns its URL.
+    async fn fake_token_server(challenge: std::sync::Arc<std::sync::Mutex<String>>) -> String {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await.unwrap();
+        let url = format!("http://127.0.0.1:{}/token", listener.local_addr().unwrap().port());
+        tokio::spawn(async move {
+            let mut issued = 0;
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0u8; 8192];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_string();
+                let body = request.split("\r\n\r\n").nth(1).unwrap_or_default();
+                let form: HashMap<String, String> = url::form_urlencoded::parse(body.as_bytes()).into_owned().collect();
+
+                let verified = form.get("code_verifier").is_some_and(|verifier| {
+                    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes())) == *challenge.lock().unwrap()
+                });
+                let valid = form.get("client_id").map(String::as_str) == Some("missive")
+                    && match form.get("grant_type").map(String::as_str) {
+                        Some("authorization_code") => form.get("code").map(String::as_str) == Some("c0de") && verified,
+                        Some("refresh_token") => form.get("refresh_token").map(String::as_str) == Some("r1"),
+                        _ => false,
+                    };
+
+                issued += 1;
+                let (status, body) = if !valid {
+                    ("400 Bad Request", r#"{"error":"invalid_grant","error_description":"bad grant"}"#.to_string())
+                } else if form["grant_type"] == "authorization_code" {
+                    ("200 OK", format!(r#"{{"access_token":"a{}","refresh_token":"r1","expires_in":30}}"#, issued))
+                } else {
+                    ("200 OK", format!(r#"{{"access_token":"a{}","expires_in":3600}}"#, issued))
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        url
+    }
+
+    fn config(token_url: String) -> OAuth2Config {
+        OAuth2Config {
+            auth_url: "https://auth.example.com/authorize".to_string(),
+            token_url,
+            client_id: "missive".to_string(),
+            client_secret: None,
+            scopes: vec!["mail".to_string(), "offline_access".to_string()],
+            mechanism: SaslMechanism::Xoauth2,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_authorize_and_refresh() {
+        let challenge = std::sync::Arc::new(std::sync::Mutex::new(String::new()));
+        let config = config(fake_token_server(challenge.clone()).await);
+
+        // The "browser" signs in and follows the redirect with the code.
+        let tokens = authorize(&config, "me@example.com", |url| {
+            let url = Url::parse(url).unwrap();
+            let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["scope"], "mail offline_access");
+            assert_eq!(params["login_hint"], "me@example.com");
+            *challenge.lock().unwrap() = params["code_challenge"].clone();
+
+            let mut redirect = Url::parse(&params["redirect_uri"]).unwrap();
+            redirect.query_pairs_mut().append_pair("code", "c0de").append_pair("state", &params["state"]);
+            tokio::spawn(async move {
+                let response = reqwest::get(redirect).await.unwrap();
+                assert!(response.status().is_success());
+            });
+            Ok(())
+        })
+        .await
+        .unwrap();
+        assert_eq!(tokens.access_token, "a1");
+        assert_eq!(tokens.refresh_token.as_deref(), Some("r1"));
+        assert!(tokens.expires_soon(Utc::now().timestamp()));
+
+        // A token about to expire is refreshed and stored, keeping the refresh token.
+        let temp_dir = tempfile::tempdir().unwrap();
+        let secrets = FileSecretStore::open(temp_dir.path()).unwrap();
+        store_tokens(&secrets, "acc", &tokens).unwrap();
+        assert_eq!(access_token(&config, &secrets, "acc").await.unwrap(), "a2");
+        assert_eq!(access_token(&config, &secrets, "acc").await.unwrap(), "a2");
+        let stored: TokenSet = serde_json::from_str(&secrets.get(&secrets::tokens_key("acc")).unwrap().unwrap()).unwrap();
+        assert_eq!(stored.refresh_token.as_deref(), Some("r1"));
+
+        let revoked = TokenSet { refresh_token: Some("old".to_string()), ..stored };
+        assert!(matches!(refresh(&config, &revoked).await, Err(OAuthError::Denied(message)) if message == "bad grant"));
+        assert!(matches!(access_token(&config, &secrets, "other").await, Err(OAuthError::NotAuthorized)));
+    }
+
+    #[test]
+    fn test_sasl_response() {
+        assert_eq!(
+            sasl_response(SaslMechanism::Xoauth2, "me@example.com", "imap.example.com", 993, "t0k"),
+            "user=me@example.com\x01auth=Bearer t0k\x01\x01"
+        );
+        assert_eq!(
+            sasl_response(SaslMechanism::Oauthbearer, "a,b=c@example.com", "imap.example.com", 993, "t0k"),
+            "n,a=a=2Cb=3Dc@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer t0k\x01\x01"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+use crate::database::Database;
+use crate::email::{self, EmailClient, EmailError};
+use crate::models::{self, PendingAction, PendingOperation};
+use crate::sync::{self, Result, SyncError};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+
+/// How often the queue is retried when nothing wakes the worker up.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Operations failing this many times for a non-network reason are dropped,
+/// and messages that could not be sent go back to the drafts.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Replay the outbox forever, whenever `wake` is notified or every `RETRY_INTERVAL`.
+pub async fn run_worker(db: Arc<Mutex<Database>>, wake: Arc<Notify>) {
+    loop {
+        if let Err(e) = replay(&db).await {
+            log::warn!("Outbox replay failed: {}", e);
+        }
+
+        tokio::select! {
+            _ = wake.notified() => {}
+            _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+        }
+    }
+}
+
+/// Run every pending operation in order.
+///
+/// Accounts whose server cannot be reached are skipped until the next replay,
+/// and so is the rest of an account's queue after a failure, so operations on
+/// one account are never applied out of order.
+pub async fn replay(db: &Mutex<Database>) -> Result<()> {
+    let ops = db.lock().await.get_pending_operations().await?;
+    let mut blocked: HashSet<String> = HashSet::new();
+
+    for op in ops {
+        if blocked.contains(&op.account_id) {
+            continue;
+        }
+
+        let client = {
+            let db = db.lock().await;
+            match db.get_account(&op.account_id).await? {
+                Some(account) => sync::client_for(&db, account).await?,
+                None => {
+                    db.delete_operation(&op.id).await?;
+                    continue;
+                }
+            }
+        };
+
+        match execute(db, &client, &op).await {
+            Ok(()) => {
+                db.lock().await.delete_operation(&op.id).await?;
+            }
+            Err(SyncError::Email(EmailError::ConnectionError(e))) => {
+                log::info!("Account {} unreachable, keeping outbox: {}", op.account_id, e);
+                blocked.insert(op.account_id.clone());
+            }
+            Err(SyncError::Email(EmailError::MessageNotFound(e))) => {
+                // The message vanished server-side: whatever we wanted to do
+                // with it is moot, and a synced local copy is a ghost.
+                log::warn!("Outbox conflict on {:?}: {}", op.email_id, e);
+                let db = db.lock().await;
+                db.delete_operation(&op.id).await?;
+                match &op.email_id {
+                    Some(email_id) if acts_on_server_copy(&op) => {
+                        db.delete_operations_for_email(email_id).await?;
+                        db.delete_email(email_id).await?;
+                    }
+                    _ => {}
+                }
+            }
+            Err(e) if op.attempts + 1 >= MAX_ATTEMPTS => {
+                log::error!("Dropping outbox operation {} after {} attempts: {}", op.id, op.attempts + 1, e);
+                give_up(&*db.lock().await, &op).await?;
+            }
+            Err(e) => {
+                log::warn!("Outbox operation {} failed: {}", op.id, e);
+                db.lock().await.record_operation_failure(&op.id, &e.to_string()).await?;
+                blocked.insert(op.account_id.clone());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Drop `op` for good. The message of a send is moved back to the drafts,
+/// so the user finds it there instead of it sitting in the outbox forever.
+async fn give_up(db: &Database, op: &PendingOperation) -> Result<()> {
+    db.delete_operation(&op.id).await?;
+    if let (PendingAction::Send { .. }, Some(email_id)) = (&op.action, &op.email_id) {
+        db.move_to_folder(email_id, "drafts").await?;
+    }
+    Ok(())
+}
+
+/// Whether `op` changes a message already on the server, so that the message
+/// missing there means its local copy is gone too.
+fn acts_on_server_copy(op: &PendingOperation) -> bool {
+    let on_message = matches!(
+        op.action,
+        PendingAction::SetRead { .. } | PendingAction::SetStarred { .. } | PendingAction::Delete | PendingAction::Move { .. }
+    );
+    on_message && op.uid.is_some()
+}
+
+async fn execute(db: &Mutex<Database>, client: &EmailClient, op: &PendingOperation) -> Result<()> {
+    match (&op.action, &op.email_id) {
+        (PendingAction::Send { email }, email_id) => {
+            let message = client.send_email(email).await?;
+            if let Some(email_id) = email_id {
+                let db = db.lock().await;
+                db.set_sent_message(email_id, email::message_id(&message).as_deref(), &message.formatted())
+                    .await?;
+                db.move_to_folder(email_id, "sent").await?;
+                if let Some(sent) = db.get_email(email_id).await? {
+                    sync::queue_sent_copy(&db, client, &sent).await?;
+                }
+            }
+            return Ok(());
+        }
+        (PendingAction::UploadDraft, Some(email_id)) => {
+            sync::upload_draft(db, email_id).await?;
+            return Ok(());
+        }
+        (PendingAction::AppendSent, Some(email_id)) => {
+            return sync::append_sent(db, email_id).await;
+        }
+        _ => {}
+    }
+
+    let (email_id, folder, uid) = match (&op.email_id, &op.folder, op.uid) {
+        (Some(email_id), Some(folder), Some(uid)) => (email_id, folder, uid),
+        _ => {
+            log::warn!("Dropping outbox operation {} without a target", op.id);
+            return Ok(());
+        }
+    };
+
+    match &op.action {
+        PendingAction::SetRead { read } => client.set_read_flag(folder, uid, *read).await?,
+        PendingAction::SetStarred { starred } => client.set_starred_flag(folder, uid, *starred).await?,
+        PendingAction::Delete => client.delete_email(folder, uid).await?,
+        PendingAction::Move { target } if target != folder => {
+            let new_uid = client.move_email(folder, uid, target).await?;
+
+            // The old UID is meaningless in the target folder: re-key the local
+            // copy, or drop it and let the next sync of the target bring it back.
+            let db = db.lock().await;
+            let new_id = new_uid.map(|new_uid| {
+                let id = models::imap_email_id(&op.account_id, target, new_uid.uid_validity, new_uid.uid);
+                (id, new_uid.uid)
+            });
+            match new_id {
+                Some((new_id, new_uid)) if db.get_email(&new_id).await?.is_none() => {
+                    db.rekey_email(email_id, &new_id).await?;
+                    db.retarget_operations(email_id, &new_id, target, new_uid).await?;
+                }
+                _ => {
+                    db.delete_email(email_id).await?;
+                    db.delete_operations_for_email(email_id).await?;
+                }
+            }
+        }
+        PendingAction::Move { .. }
+        | PendingAction::Send { .. }
+        | PendingAction::UploadDraft
+        | PendingAction::AppendSent => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::{EmailAddress, NewEmail};
+
+    #[tokio::test]
+    async fn test_give_up_send() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let db = Database::new(temp_dir.path().join("test.db")).await.unwrap();
+
+        let outgoing = NewEmail {
+            account_id: Some("acc".to_string()),
+            from: EmailAddress { name: "Me".to_string(), email: "me@example.com".to_string() },
+            to: vec![EmailAddress { name: String::new(), email: "you@example.com".to_string() }],
+            cc: None,
+            bcc: None,
+            subject: "Subject".to_string(),
+            body: "Body".to_string(),
+            html_body: None,
+            attachments: None,
+            in_reply_to: None,
+            references: None,
+            message_id: None,
+        };
+        let queued = db.create_email(&models::Email::from_new(outgoing.clone(), "outbox")).await.unwrap();
+        let mut op = PendingOperation::new("acc".to_string(), PendingAction::Send { email: Box::new(outgoing) });
+        op.email_id = Some(queued.id.clone());
+        db.enqueue_operation(&op).await.unwrap();
+
+        give_up(&db, &op).await.unwrap();
+        assert!(db.get_pending_operations().await.unwrap().is_empty());
+        assert_eq!(db.get_email(&queued.id).await.unwrap().unwrap().folder, "drafts");
+    }
+}
@@ -0,0 +1,434 @@
+use crate::email::{EmailError, ImapSession, Result};
+use crate::models::EmailAccount;
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Connections kept open to one server at a time, IDLE watchers aside.
+const MAX_PER_SERVER: usize = 4;
+/// A session unused for longer is checked with NOOP before being reused.
+const CHECK_AFTER: Duration = Duration::from_secs(60);
+/// A session unused for longer is logged out instead of reused, well before
+/// servers may drop it (30 minutes at the earliest, RFC 3501 §5.4).
+const MAX_IDLE: Duration = Duration::from_secs(10 * 60);
+/// How long to wait for a connection when the server is at its cap.
+const WAIT_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often sessions idle for longer than `MAX_IDLE` are logged out.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Authenticated IMAP sessions kept open between operations, per account.
+///
+/// Sessions keep whatever mailbox they had selected, so operations always
+/// SELECT the mailbox they work on.
+pub struct SessionPool {
+    state: Mutex<PoolState>,
+    released: Condvar,
+    max_per_server: usize,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Sessions waiting for reuse, by account id.
+    idle: HashMap<String, Vec<IdleSession>>,
+    /// Connections open (idle or in use), by server.
+    open: HashMap<String, usize>,
+    /// Bumped by `evict` so that sessions in use are not returned.
+    generations: HashMap<String, u64>,
+}
+
+struct IdleSession {
+    session: ImapSession,
+    server: String,
+    since: Instant,
+}
+
+impl PoolState {
+    fn generation(&self, account_id: &str) -> u64 {
+        self.generations.get(account_id).copied().unwrap_or(0)
+    }
+
+    fn take_idle(&mut self, account_id: &str) -> Option<IdleSession> {
+        let sessions = self.idle.get_mut(account_id)?;
+        let session = sessions.pop();
+        if sessions.is_empty() {
+            self.idle.remove(account_id);
+        }
+        session
+    }
+
+    /// The least recently used idle session to `server`, of any account.
+    fn take_oldest_on(&mut self, server: &str) -> Option<IdleSession> {
+        let (account_id, index) = self
+            .idle
+            .iter()
+            .flat_map(|(id, sessions)| sessions.iter().enumerate().map(move |(i, s)| (id, i, s)))
+            .filter(|(_, _, session)| session.server == server)
+            .min_by_key(|(_, _, session)| session.since)
+            .map(|(id, i, _)| (id.clone(), i))?;
+        let sessions = self.idle.get_mut(&account_id)?;
+        let session = sessions.remove(index);
+        if sessions.is_empty() {
+            self.idle.remove(&account_id);
+        }
+        Some(session)
+    }
+}
+
+impl Default for SessionPool {
+    fn default() -> Self {
+        Self::with_limit(MAX_PER_SERVER)
+    }
+}
+
+impl SessionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_limit(max_per_server: usize) -> Self {
+        Self { state: Mutex::default(), released: Condvar::new(), max_per_server }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        self.state.lock().unwrap()
+    }
+
+    /// A session for `account`: an idle one when it is still alive, or one
+    /// opened with `connect`. Blocks while the server is at its cap.
+    pub fn get(
+        &self,
+        account: &EmailAccount,
+        connect: impl FnOnce() -> Result<ImapSession>,
+    ) -> Result<PooledSession<'_>> {
+        self.acquire(account, true, connect)
+    }
+
+    /// A session for `account` opened with `connect`, never an idle one,
+    /// e.g. to retry after a reused session turned out to be dead.
+    pub fn get_new(
+        &self,
+        account: &EmailAccount,
+        connect: impl FnOnce() -> Result<ImapSession>,
+    ) -> Result<PooledSession<'_>> {
+        self.acquire(account, false, connect)
+    }
+
+    fn acquire(
+        &self,
+        account: &EmailAccount,
+        reuse: bool,
+        connect: impl FnOnce() -> Result<ImapSession>,
+    ) -> Result<PooledSession<'_>> {
+        let server = server_key(account);
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        let mut state = self.lock();
+        loop {
+            let generation = state.generation(&account.id);
+            let idle = if reuse { state.take_idle(&account.id) } else { None };
+            if let Some(idle) = idle {
+                drop(state);
+                if let Some(session) = self.revive(idle) {
+                    return Ok(self.lease(account, server, generation, session, true));
+                }
+                state = self.lock();
+                continue;
+            }
+
+            let open = state.open.entry(server.clone()).or_default();
+            if *open < self.max_per_server {
+                *open += 1;
+                drop(state);
+                return match connect() {
+                    Ok(session) => Ok(self.lease(account, server, generation, session, false)),
+                    Err(e) => {
+                        self.release(&server);
+                        Err(e)
+                    }
+                };
+            }
+
+            // Make room by closing a session another account left idle.
+            if let Some(idle) = state.take_oldest_on(&server) {
+                drop(state);
+                self.close(idle.session, &server);
+                state = self.lock();
+                continue;
+            }
+
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                return Err(EmailError::ConnectionError(format!("Too many connections to {}", server)));
+            }
+            state = self.released.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Close the idle sessions of an account and keep those in use from
+    /// coming back, e.g. after its credentials or security changed.
+    pub fn evict(&self, account_id: &str) {
+        let mut state = self.lock();
+        *state.generations.entry(account_id.to_string()).or_default() += 1;
+        let sessions = state.idle.remove(account_id).unwrap_or_default();
+        for idle in &sessions {
+            if let Some(open) = state.open.get_mut(&idle.server) {
+                *open = open.saturating_sub(1);
+            }
+        }
+        drop(state);
+        if !sessions.is_empty() {
+            self.released.notify_all();
+        }
+    }
+
+    /// Log out of the sessions left idle for longer than `max_idle`.
+    fn sweep(&self, max_idle: Duration) {
+        let mut state = self.lock();
+        let mut expired = Vec::new();
+        for sessions in state.idle.values_mut() {
+            let (old, fresh) = std::mem::take(sessions)
+                .into_iter()
+                .partition(|idle| idle.since.elapsed() >= max_idle);
+            *sessions = fresh;
+            expired.extend::<Vec<_>>(old);
+        }
+        state.idle.retain(|_, sessions| !sessions.is_empty());
+        drop(state);
+        for idle in expired {
+            self.close(idle.session, &idle.server);
+        }
+    }
+
+    /// Check an idle session, returning it when it is fit for reuse.
+    fn revive(&self, idle: IdleSession) -> Option<ImapSession> {
+        let IdleSession { mut session, server, since } = idle;
+        let unused = since.elapsed();
+        if unused > MAX_IDLE {
+            self.close(session, &server);
+            return None;
+        }
+        if unused > CHECK_AFTER {
+            if let Err(e) = session.noop() {
+                log::info!("Dropping stale IMAP session to {}: {}", server, e);
+                drop(session);
+                self.release(&server);
+                return None;
+            }
+        }
+        Some(session)
+    }
+
+    fn lease(&self, account: &EmailAccount, server: String, generation: u64, session: ImapSession, reused: bool) -> PooledSession<'_> {
+        PooledSession {
+            pool: self,
+            session: Some(session),
+            account_id: account.id.clone(),
+            server,
+            generation,
+            reused,
+            broken: false,
+        }
+    }
+
+    fn close(&self, mut session: ImapSession, server: &str) {
+        let _ = session.logout();
+        self.release(server);
+    }
+
+    /// Free the slot of a connection to `server`.
+    fn release(&self, server: &str) {
+        if let Some(open) = self.lock().open.get_mut(server) {
+            *open = open.saturating_sub(1);
+        }
+        self.released.notify_one();
+    }
+}
+
+/// A session borrowed from the pool, returned to it when dropped.
+pub struct PooledSession<'a> {
+    pool: &'a SessionPool,
+    session: Option<ImapSession>,
+    account_id: String,
+    server: String,
+    generation: u64,
+    /// Taken from the idle sessions rather than just opened.
+    reused: bool,
+    broken: bool,
+}
+
+impl PooledSession<'_> {
+    pub fn reused(&self) -> bool {
+        self.reused
+    }
+
+    /// Close the connection when done instead of returning it, e.g. after
+    /// an I/O error.
+    pub fn discard(&mut self) {
+        self.broken = true;
+    }
+}
+
+impl Deref for PooledSession<'_> {
+    type Target = ImapSession;
+
+    fn deref(&self) -> &ImapSession {
+        self.session.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledSession<'_> {
+    fn deref_mut(&mut self) -> &mut ImapSession {
+        self.session.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledSession<'_> {
+    fn drop(&mut self) {
+        let Some(session) = self.session.take() else {
+            return;
+        };
+        if self.broken {
+            drop(session);
+            self.pool.release(&self.server);
+            return;
+        }
+
+        let mut state = self.pool.lock();
+        if state.generation(&self.account_id) != self.generation {
+            drop(state);
+            self.pool.close(session, &self.server);
+            return;
+        }
+        state.idle.entry(self.account_id.clone()).or_default().push(IdleSession {
+            session,
+            server: self.server.clone(),
+            since: Instant::now(),
+        });
+        drop(state);
+        self.pool.released.notify_one();
+    }
+}
+
+/// Log out of sessions left idle too long, so that they do not hold
+/// connections open until the next operation on their account.
+pub async fn run_sweeper(pool: Arc<SessionPool>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let pool = pool.clone();
+        let _ = tokio::task::spawn_blocking(move || pool.sweep(MAX_IDLE)).await;
+    }
+}
+
+fn server_key(account: &EmailAccount) -> String {
+    format!("{}:{}", account.imap_server.to_ascii_lowercase(), account.imap_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Accepts any login and answers every other command with OK.
+    fn fake_server() -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let count = connections.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                count.fetch_add(1, Ordering::SeqCst);
+                std::thread::spawn(move || {
+                    stream.write_all(b"* OK ready\r\n").unwrap();
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut line = String::new();
+                    while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                        let tag = line.split(' ').next().unwrap_or("*").to_string();
+                        if line.contains("LOGOUT") {
+                            let _ = write!(stream, "* BYE\r\n{} OK done\r\n", tag);
+                            break;
+                        }
+                        let _ = write!(stream, "{} OK done\r\n", tag);
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (port, connections)
+    }
+
+    fn connect(port: u16) -> Result<ImapSession> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let connection: imap::Connection = Box::new(stream);
+        let mut client = imap::Client::new(connection);
+        client.read_greeting().unwrap();
+        client.login("user", "pass").map_err(|(e, _)| EmailError::AuthError(e.to_string()))
+    }
+
+    fn account(id: &str, port: u16) -> EmailAccount {
+        let mut account = EmailAccount::new(
+            format!("{}@example.com", id),
+            "Test".to_string(),
+            "127.0.0.1".to_string(),
+            port,
+            "127.0.0.1".to_string(),
+            587,
+            id.to_string(),
+            "pass".to_string(),
+            false,
+            false,
+        );
+        account.id = id.to_string();
+        account
+    }
+
+    #[test]
+    fn test_session_pool() {
+        let (port, connections) = fake_server();
+        let pool = SessionPool::with_limit(1);
+        let alice = account("alice", port);
+        let bob = account("bob", port);
+
+        // A returned session is reused, unless a new one is asked for.
+        drop(pool.get(&alice, || connect(port)).unwrap());
+        let mut session = pool.get(&alice, || panic!("should reuse")).unwrap();
+        assert!(session.reused());
+        session.noop().unwrap();
+        drop(session);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        let session = pool.get_new(&alice, || connect(port)).unwrap();
+        assert!(!session.reused());
+        drop(session);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        // At the cap, another account's idle session is closed to make room.
+        drop(pool.get(&bob, || connect(port)).unwrap());
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        assert_eq!(pool.lock().open[&server_key(&bob)], 1);
+
+        // Discarded and evicted sessions are not reused.
+        pool.get(&bob, || panic!("should reuse")).unwrap().discard();
+        drop(pool.get(&bob, || connect(port)).unwrap());
+        pool.evict("bob");
+        drop(pool.get(&bob, || connect(port)).unwrap());
+        assert_eq!(connections.load(Ordering::SeqCst), 5);
+
+        // A session in use while its account is evicted is closed on return.
+        let session = pool.get(&bob, || panic!("should reuse")).unwrap();
+        pool.evict("bob");
+        drop(session);
+        assert!(pool.lock().idle.is_empty());
+        assert_eq!(pool.lock().open[&server_key(&bob)], 0);
+
+        // Sweeping logs out of sessions idle for too long.
+        drop(pool.get(&bob, || connect(port)).unwrap());
+        pool.sweep(Duration::from_secs(3600));
+        assert_eq!(pool.lock().open[&server_key(&bob)], 1);
+        pool.sweep(Duration::ZERO);
+        assert!(pool.lock().idle.is_empty());
+        assert_eq!(pool.lock().open[&server_key(&bob)], 0);
+    }
+}
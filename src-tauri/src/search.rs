@@ -0,0 +1,284 @@
+use crate::models::Email;
+use chrono::{DateTime, NaiveDate};
+use serde::{Deserialize, Serialize};
+
+/// Characters of context kept on each side of the first highlighted match.
+const SNIPPET_CONTEXT: usize = 80;
+
+/// Markers the index puts around matches, from the private use area so that
+/// they cannot be confused with the text, and turned into `<mark>` tags once
+/// the text is escaped.
+pub const HIGHLIGHT_START: &str = "\u{E000}";
+pub const HIGHLIGHT_END: &str = "\u{E001}";
+
+/// A search box query, split into free text and Gmail-style operators.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchQuery {
+    /// Words matched against the full-text index of subjects and bodies.
+    pub text: String,
+    pub from: Vec<String>,
+    pub to: Vec<String>,
+    pub subject: Vec<String>,
+    pub has_attachment: bool,
+    pub read: Option<bool>,
+    pub starred: Option<bool>,
+    /// RFC 3339 bounds on the message date.
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub folder: Option<String>,
+}
+
+impl SearchQuery {
+    pub fn parse(input: &str) -> Self {
+        let mut query = SearchQuery::default();
+        let mut words = Vec::new();
+
+        for token in tokenize(input) {
+            let (operator, value) = match token.split_once(':') {
+                Some((operator, value)) if !value.is_empty() => (operator.to_lowercase(), value),
+                _ => {
+                    words.push(token);
+                    continue;
+                }
+            };
+            let value = value.trim_matches('"').to_string();
+
+            match (operator.as_str(), value.to_lowercase().as_str()) {
+                ("from", _) => query.from.push(value.to_lowercase()),
+                ("to", _) => query.to.push(value.to_lowercase()),
+                ("subject", _) => query.subject.push(value.to_lowercase()),
+                ("in", _) => query.folder = Some(value.to_lowercase()),
+                ("has", "attachment") => query.has_attachment = true,
+                ("is", "unread") => query.read = Some(false),
+                ("is", "read") => query.read = Some(true),
+                ("is", "starred") => query.starred = Some(true),
+                ("is", "unstarred") => query.starred = Some(false),
+                ("before", _) if parse_date(&value).is_some() => query.before = parse_date(&value),
+                ("after", _) if parse_date(&value).is_some() => query.after = parse_date(&value),
+                _ => words.push(token),
+            }
+        }
+
+        query.text = words.join(" ");
+        query
+    }
+
+    /// The same query as IMAP `SEARCH` criteria.
+    ///
+    /// `has:attachment` has no IMAP equivalent and is approximated by a
+    /// `multipart/mixed` content type. Folder selection is left to the caller.
+    /// Non-ASCII values are sent as non-synchronizing literals, which need
+    /// the server to support `LITERAL+` or `LITERAL-`.
+    pub fn to_imap(&self) -> String {
+        let mut criteria = Vec::new();
+        for word in self.text.split_whitespace() {
+            criteria.push(format!("TEXT {}", imap_string(word)));
+        }
+        for from in &self.from {
+            criteria.push(format!("FROM {}", imap_string(from)));
+        }
+        for to in &self.to {
+            criteria.push(format!("OR TO {0} CC {0}", imap_string(to)));
+        }
+        for subject in &self.subject {
+            criteria.push(format!("SUBJECT {}", imap_string(subject)));
+        }
+        if self.has_attachment {
+            criteria.push("HEADER Content-Type \"multipart/mixed\"".to_string());
+        }
+        match self.read {
+            Some(true) => criteria.push("SEEN".to_string()),
+            Some(false) => criteria.push("UNSEEN".to_string()),
+            None => {}
+        }
+        match self.starred {
+            Some(true) => criteria.push("FLAGGED".to_string()),
+            Some(false) => criteria.push("UNFLAGGED".to_string()),
+            None => {}
+        }
+        if let Some(before) = self.before.as_deref().and_then(imap_date) {
+            criteria.push(format!("BEFORE {}", before));
+        }
+        if let Some(after) = self.after.as_deref().and_then(imap_date) {
+            criteria.push(format!("SINCE {}", after));
+        }
+
+        if criteria.is_empty() {
+            return "ALL".to_string();
+        }
+        let criteria = criteria.join(" ");
+        if criteria.is_ascii() {
+            criteria
+        } else {
+            format!("CHARSET UTF-8 {}", criteria)
+        }
+    }
+}
+
+/// A quoted string, or a literal when `value` is 8-bit (RFC 3501 forbids
+/// 8-bit characters in quoted strings).
+fn imap_string(value: &str) -> String {
+    if value.is_ascii() {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        format!("{{{}+}}\r\n{}", value.len(), value)
+    }
+}
+
+/// An RFC 3339 date as an IMAP `date` (`31-Jan-2024`).
+fn imap_date(value: &str) -> Option<String> {
+    Some(DateTime::parse_from_rfc3339(value).ok()?.format("%-d-%b-%Y").to_string())
+}
+
+/// Split on whitespace, keeping `"quoted phrases"` (also after an operator) together.
+fn tokenize(input: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => {
+                quoted = !quoted;
+                current.push(c);
+            }
+            c if c.is_whitespace() && !quoted => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+        .into_iter()
+        .map(|t| if t.contains(':') { t } else { t.trim_matches('"').to_string() })
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` or `YYYY/MM/DD` date, as stored dates are compared.
+fn parse_date(value: &str) -> Option<String> {
+    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%Y/%m/%d"))
+        .ok()?;
+    Some(date.and_hms_opt(0, 0, 0)?.and_utc().to_rfc3339())
+}
+
+/// A search result.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub email: Email,
+    /// BM25 relevance; 0 when the query has no free text.
+    pub score: f64,
+    /// HTML excerpt around the first match, with `<mark>` around matched words.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+/// Cut a highlighted field down to the text around its first match, as
+/// escaped HTML.
+pub fn snippet(highlighted: &str) -> Option<String> {
+    let start = highlighted.find(HIGHLIGHT_START)?;
+    let before: Vec<char> = highlighted[..start].chars().collect();
+    let after: Vec<char> = highlighted[start..].chars().collect();
+
+    let from = before.len().saturating_sub(SNIPPET_CONTEXT);
+    let mut excerpt: String = before[from..].iter().collect();
+    let mut tail: String = after.iter().take(SNIPPET_CONTEXT * 2).collect();
+    // Never cut a marker in half.
+    if let Some(open) = tail.rfind(HIGHLIGHT_START) {
+        if !tail[open..].contains(HIGHLIGHT_END) {
+            tail.truncate(open);
+        }
+    }
+
+    let mut snippet = String::new();
+    if from > 0 {
+        snippet.push('…');
+    }
+    excerpt.push_str(&tail);
+    snippet.push_str(excerpt.split_whitespace().collect::<Vec<_>>().join(" ").as_str());
+    if after.len() > SNIPPET_CONTEXT * 2 {
+        snippet.push('…');
+    }
+    Some(
+        escape_html(&snippet)
+            .replace(HIGHLIGHT_START, "<mark>")
+            .replace(HIGHLIGHT_END, "</mark>"),
+    )
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_operators() {
+        let query = SearchQuery::parse(r#"from:alice subject:"weekly report" is:unread has:attachment in:Inbox after:2024/01/31 budget draft"#);
+        assert_eq!(query.text, "budget draft");
+        assert_eq!(query.from, vec!["alice".to_string()]);
+        assert_eq!(query.subject, vec!["weekly report".to_string()]);
+        assert_eq!(query.read, Some(false));
+        assert!(query.has_attachment);
+        assert_eq!(query.folder.as_deref(), Some("inbox"));
+        assert_eq!(query.after.as_deref(), Some("2024-01-31T00:00:00+00:00"));
+        assert_eq!(query.before, None);
+    }
+
+    #[test]
+    fn test_unknown_operators_are_text() {
+        let query = SearchQuery::parse("re: meeting before:someday is:important");
+        assert_eq!(query.text, "re: meeting before:someday is:important");
+        assert_eq!(query, SearchQuery { text: query.text.clone(), ..SearchQuery::default() });
+    }
+
+    #[test]
+    fn test_to_imap() {
+        let query = SearchQuery::parse(r#"from:alice to:bob subject:"q3 final" is:unread is:starred before:2024-02-01 after:2024-01-05 budget"#);
+        assert_eq!(
+            query.to_imap(),
+            r#"TEXT "budget" FROM "alice" OR TO "bob" CC "bob" SUBJECT "q3 final" UNSEEN FLAGGED BEFORE 1-Feb-2024 SINCE 5-Jan-2024"#
+        );
+        assert_eq!(SearchQuery::parse("").to_imap(), "ALL");
+        assert_eq!(imap_string(r#"a "b" \c"#), r#""a \"b\" \\c""#);
+        assert_eq!(SearchQuery::parse("réunion").to_imap(), "CHARSET UTF-8 TEXT {8+}\r\nréunion");
+        assert_eq!(
+            SearchQuery::parse("from:zoé budget").to_imap(),
+            "CHARSET UTF-8 TEXT \"budget\" FROM {4+}\r\nzoé"
+        );
+    }
+
+    #[test]
+    fn test_snippet() {
+        let body = format!("{} the {}budget{} is {}", "word ".repeat(40), HIGHLIGHT_START, HIGHLIGHT_END, "more ".repeat(60));
+        let snippet = snippet(&body).unwrap();
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("the <mark>budget</mark> is"));
+        assert_eq!(super::snippet("no match"), None);
+
+        let body = format!("<img src=x onerror=\"alert(1)\"> & {}budget{}", HIGHLIGHT_START, HIGHLIGHT_END);
+        assert_eq!(
+            super::snippet(&body).unwrap(),
+            "&lt;img src=x onerror=&quot;alert(1)&quot;&gt; &amp; <mark>budget</mark>"
+        );
+    }
+}
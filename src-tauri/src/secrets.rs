@@ -0,0 +1,271 @@
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum SecretError {
+    #[error("Secret store I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Secret store is corrupted: {0}")]
+    Corrupted(String),
+    #[error("Could not decrypt secret {0}")]
+    Decrypt(String),
+}
+
+pub type Result<T> = std::result::Result<T, SecretError>;
+
+/// Where account passwords and tokens live, away from the `account` table.
+pub trait SecretStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn set(&self, key: &str, secret: &str) -> Result<()>;
+    /// Forget `key`. Deleting a missing key is a no-op.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Key of the password of account `account_id`.
+pub fn password_key(account_id: &str) -> String {
+    format!("account:{}:password", account_id)
+}
+
+/// Key of the OAuth2 tokens of account `account_id`.
+pub fn tokens_key(account_id: &str) -> String {
+    format!("account:{}:oauth2", account_id)
+}
+
+/// Secrets encrypted with ChaCha20-Poly1305 in a JSON file.
+///
+/// The 256-bit key is generated on first use into a file readable by the
+/// owner only, next to the store. Each secret is bound to its key name as
+/// associated data, so entries cannot be swapped between accounts.
+///
+/// This is obfuscation rather than encryption at rest: the key is stored in
+/// plaintext, so anyone who can read the user's files can decrypt the store.
+/// It keeps secrets out of the database, its exports and casual reading, and
+/// relies on the file permissions for the rest.
+pub struct FileSecretStore {
+    path: PathBuf,
+    key: LessSafeKey,
+    rng: SystemRandom,
+    // Serializes read-modify-write cycles of the file.
+    lock: std::sync::Mutex<()>,
+}
+
+impl FileSecretStore {
+    const KEY_FILE: &'static str = "secrets.key";
+    const STORE_FILE: &'static str = "secrets.json";
+
+    /// Open the store kept in directory `dir`, creating its key if needed.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let rng = SystemRandom::new();
+        let key_bytes = Self::load_key(&dir.join(Self::KEY_FILE), &rng)?;
+        let key = UnboundKey::new(&CHACHA20_POLY1305, &key_bytes)
+            .map_err(|_| SecretError::Corrupted("invalid key length".to_string()))?;
+
+        Ok(Self {
+            path: dir.join(Self::STORE_FILE),
+            key: LessSafeKey::new(key),
+            rng,
+            lock: std::sync::Mutex::new(()),
+        })
+    }
+
+    fn load_key(path: &Path, rng: &SystemRandom) -> Result<Vec<u8>> {
+        let key_len = CHACHA20_POLY1305.key_len();
+        match fs::read(path) {
+            Ok(key) if key.len() == key_len => return Ok(key),
+            Ok(key) => {
+                return Err(SecretError::Corrupted(format!(
+                    "{}: expected a {}-byte key, found {} bytes",
+                    path.display(),
+                    key_len,
+                    key.len()
+                )))
+            }
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            Err(_) => {}
+        }
+
+        let mut key = vec![0u8; key_len];
+        rng.fill(&mut key)
+            .map_err(|_| SecretError::Corrupted("no system randomness".to_string()))?;
+        write_private(path, &key)?;
+        Ok(key)
+    }
+
+    fn load(&self) -> Result<BTreeMap<String, String>> {
+        match fs::read(&self.path) {
+            Ok(data) => serde_json::from_slice(&data).map_err(|e| SecretError::Corrupted(e.to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, entries: &BTreeMap<String, String>) -> Result<()> {
+        let data = serde_json::to_vec_pretty(entries).map_err(|e| SecretError::Corrupted(e.to_string()))?;
+        write_private(&self.path, &data)
+    }
+
+    fn seal(&self, name: &str, secret: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .map_err(|_| SecretError::Corrupted("no system randomness".to_string()))?;
+        let mut data = secret.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(name.as_bytes()), &mut data)
+            .map_err(|_| SecretError::Decrypt(name.to_string()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(data);
+        Ok(BASE64.encode(sealed))
+    }
+
+    fn open_entry(&self, name: &str, sealed: &str) -> Result<String> {
+        let mut data = BASE64.decode(sealed).map_err(|_| SecretError::Decrypt(name.to_string()))?;
+        if data.len() < NONCE_LEN {
+            return Err(SecretError::Decrypt(name.to_string()));
+        }
+        let mut ciphertext = data.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&data).map_err(|_| SecretError::Decrypt(name.to_string()))?;
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(name.as_bytes()), &mut ciphertext)
+            .map_err(|_| SecretError::Decrypt(name.to_string()))?;
+        String::from_utf8(plain.to_vec()).map_err(|_| SecretError::Decrypt(name.to_string()))
+    }
+}
+
+impl SecretStore for FileSecretStore {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let _guard = self.lock.lock().unwrap();
+        match self.load()?.get(key) {
+            Some(sealed) => self.open_entry(key, sealed).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn set(&self, key: &str, secret: &str) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries = self.load()?;
+        entries.insert(key.to_string(), self.seal(key, secret)?);
+        self.save(&entries)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        let mut entries = self.load()?;
+        if entries.remove(key).is_some() {
+            self.save(&entries)?;
+        }
+        Ok(())
+    }
+}
+
+/// Replace `path` with `data`, readable by the owner only.
+///
+/// The data is written aside then renamed, so a crash never leaves a
+/// truncated file.
+fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    // A leftover from a crash keeps its permissions: start afresh.
+    match fs::remove_file(&partial) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(&partial)?;
+    // Restricted while still empty, so the data is never readable by others.
+    #[cfg(windows)]
+    restrict_to_owner(&partial)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&partial, path)?;
+    Ok(())
+}
+
+/// Give the current user sole access to `path`, dropping the permissions it
+/// inherits from its directory, like mode 0600 on Unix.
+#[cfg(windows)]
+fn restrict_to_owner(path: &Path) -> Result<()> {
+    let user = std::env::var("USERNAME")
+        .map_err(|_| std::io::Error::new(ErrorKind::NotFound, "USERNAME is not set"))?;
+    let status = std::process::Command::new("icacls")
+        .arg(path)
+        .args(["/inheritance:r", "/grant:r"])
+        .arg(format!("{}:F", user))
+        .stdout(std::process::Stdio::null())
+        .status()?;
+    if !status.success() {
+        return Err(std::io::Error::new(ErrorKind::PermissionDenied, format!("icacls failed with {}", status)).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_secret_store() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let store = FileSecretStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+
+        store.set("a", "hunter2").unwrap();
+        store.set("b", "correct horse").unwrap();
+        let file = fs::read_to_string(temp_dir.path().join("secrets.json")).unwrap();
+        assert!(!file.contains("hunter2"));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(temp_dir.path().join("secrets.json")).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Reopening reuses the key.
+        let store = FileSecretStore::open(temp_dir.path()).unwrap();
+        assert_eq!(store.get("a").unwrap().as_deref(), Some("hunter2"));
+
+        // An entry moved under another name does not decrypt.
+        let mut entries = store.load().unwrap();
+        let sealed = entries["b"].clone();
+        entries.insert("a".to_string(), sealed);
+        store.save(&entries).unwrap();
+        assert!(matches!(store.get("a"), Err(SecretError::Decrypt(_))));
+
+        store.delete("b").unwrap();
+        store.delete("missing").unwrap();
+        assert_eq!(store.get("b").unwrap(), None);
+    }
+
+    #[test]
+    fn test_truncated_key() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("secrets.key"), [0u8; 7]).unwrap();
+        match FileSecretStore::open(temp_dir.path()) {
+            Err(SecretError::Corrupted(message)) => {
+                assert!(message.contains("secrets.key"));
+                assert!(message.contains("found 7 bytes"));
+            }
+            _ => panic!("a truncated key must be reported as corrupted"),
+        }
+    }
+}
@@ -0,0 +1,514 @@
+use crate::database::{Database, DatabaseError};
+use crate::email::{self, build_message, encode_mailbox_name, EmailClient, EmailError, ServerUid};
+use crate::models::{self, Attachment, Email, EmailAccount, MailFolder, PendingAction, PendingOperation, Security};
+use crate::search::SearchQuery;
+use crate::threading;
+use imap::types::Flag;
+use std::collections::HashSet;
+use thiserror::Error;
+use tokio::sync::Mutex;
+
+/// Number of messages downloaded per IMAP FETCH, and per backfill step.
+const SYNC_BATCH_SIZE: usize = 50;
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error(transparent)]
+    Database(#[from] DatabaseError),
+    #[error(transparent)]
+    Email(#[from] EmailError),
+    #[error("Account not found: {0}")]
+    AccountNotFound(String),
+    #[error("Folder not found: {0}")]
+    FolderNotFound(String),
+    #[error("Invalid folder operation: {0}")]
+    InvalidFolder(String),
+}
+
+pub type Result<T> = std::result::Result<T, SyncError>;
+
+/// Build a client for `account` that resolves folders through its stored tree.
+pub async fn client_for(db: &Database, account: EmailAccount) -> Result<EmailClient> {
+    let folders = db.get_folders(&account.id).await?;
+    Ok(EmailClient::new(account, db.secrets()).with_pool(db.sessions()).with_folders(folders))
+}
+
+/// Pin the certificates of `account` when it accepts self-signed ones but
+/// has none pinned yet, as accounts set up before pinning existed: whatever
+/// the servers present now is trusted on first use. Returns the account with
+/// its new pins.
+pub async fn pin_certificates(db: &Mutex<Database>, mut account: EmailAccount) -> Result<EmailAccount> {
+    let imap = account.allow_invalid_certs && account.imap_fingerprint.is_none() && account.imap_security != Security::None;
+    let smtp = account.allow_invalid_smtp_certs && account.smtp_fingerprint.is_none() && account.smtp_security != Security::None;
+    if !imap && !smtp {
+        return Ok(account);
+    }
+
+    let client = EmailClient::new(account.clone(), db.lock().await.secrets());
+    if imap {
+        account.imap_fingerprint = client.test_imap().await?;
+    }
+    if smtp {
+        account.smtp_fingerprint = client.test_smtp().await?;
+    }
+    if account.imap_fingerprint.is_some() || account.smtp_fingerprint.is_some() {
+        log::warn!(
+            "Pinned the certificates of account={} on first use: imap={:?} smtp={:?}",
+            account.id,
+            account.imap_fingerprint,
+            account.smtp_fingerprint
+        );
+        db.lock().await.set_fingerprints(&account).await?;
+    }
+    Ok(account)
+}
+
+/// Discover the folder tree of an account with `LIST` and store it.
+pub async fn refresh_folders(db: &Mutex<Database>, account_id: &str) -> Result<Vec<MailFolder>> {
+    let client = {
+        let db = db.lock().await;
+        let account = db
+            .get_account(account_id)
+            .await?
+            .ok_or_else(|| SyncError::AccountNotFound(account_id.to_string()))?;
+        EmailClient::new(account, db.secrets()).with_pool(db.sessions())
+    };
+
+    let folders = client.list_folders().await?;
+    db.lock().await.replace_folders(account_id, &folders).await?;
+    Ok(folders)
+}
+
+/// A client for an account, and its stored folder tree.
+async fn load_account(db: &Mutex<Database>, account_id: &str) -> Result<(EmailClient, Vec<MailFolder>)> {
+    let db = db.lock().await;
+    let account = db
+        .get_account(account_id)
+        .await?
+        .ok_or_else(|| SyncError::AccountNotFound(account_id.to_string()))?;
+    let folders = db.get_folders(account_id).await?;
+    let client = EmailClient::new(account, db.secrets()).with_pool(db.sessions()).with_folders(folders.clone());
+    Ok((client, folders))
+}
+
+fn find_folder<'a>(folders: &'a [MailFolder], path: &str) -> Option<&'a MailFolder> {
+    folders.iter().find(|folder| folder.path == path)
+}
+
+/// Server path of a folder called `name` (as displayed) under `parent`.
+fn child_path(parent: Option<&MailFolder>, name: &str) -> std::result::Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("folder name is empty".to_string());
+    }
+
+    let name = encode_mailbox_name(name);
+    match parent {
+        Some(parent) => {
+            let delimiter = parent
+                .delimiter
+                .as_deref()
+                .ok_or_else(|| format!("{} cannot have subfolders", parent.path))?;
+            if name.contains(delimiter) {
+                return Err(format!("folder name cannot contain {:?}", delimiter));
+            }
+            Ok(format!("{}{}{}", parent.path, delimiter, name))
+        }
+        None => Ok(name),
+    }
+}
+
+/// Create a folder on the server and add it to the stored tree.
+pub async fn create_folder(db: &Mutex<Database>, account_id: &str, parent: Option<&str>, name: &str) -> Result<Vec<MailFolder>> {
+    let (client, folders) = load_account(db, account_id).await?;
+    let parent = match parent {
+        Some(parent) => Some(find_folder(&folders, parent).ok_or_else(|| SyncError::FolderNotFound(parent.to_string()))?),
+        None => None,
+    };
+    let path = child_path(parent, name).map_err(SyncError::InvalidFolder)?;
+    if folders.iter().any(|folder| folder.path == path) {
+        return Err(SyncError::InvalidFolder(format!("{} already exists", path)));
+    }
+
+    client.create_folder(&path).await?;
+    refresh_folders(db, account_id).await
+}
+
+/// Rename a folder in place, keeping its parent, and follow it locally.
+pub async fn rename_folder(db: &Mutex<Database>, account_id: &str, path: &str, new_name: &str) -> Result<Vec<MailFolder>> {
+    let (client, folders) = load_account(db, account_id).await?;
+    let folder = find_folder(&folders, path).ok_or_else(|| SyncError::FolderNotFound(path.to_string()))?;
+    if folder.special_use.as_deref() == Some("inbox") {
+        return Err(SyncError::InvalidFolder("INBOX cannot be renamed".to_string()));
+    }
+    let parent = folder.parent.as_deref().and_then(|parent| find_folder(&folders, parent));
+    let new_path = match (parent, &folder.delimiter, &folder.parent) {
+        // Parents that are not listed still share the folder's delimiter.
+        (None, Some(delimiter), Some(parent)) => {
+            let leaf = child_path(None, new_name).map_err(SyncError::InvalidFolder)?;
+            format!("{}{}{}", parent, delimiter, leaf)
+        }
+        _ => child_path(parent, new_name).map_err(SyncError::InvalidFolder)?,
+    };
+    if folders.iter().any(|f| f.path == new_path) {
+        return Err(SyncError::InvalidFolder(format!("{} already exists", new_path)));
+    }
+    client.rename_folder(path, &new_path).await?;
+
+    // Special-use folders are stored under their logical name, which does not change.
+    db.lock()
+        .await
+        .rename_folder(account_id, path, &new_path, folder.delimiter.as_deref())
+        .await?;
+    refresh_folders(db, account_id).await
+}
+
+/// Delete a folder on the server, with the local copies of its messages.
+pub async fn delete_folder(db: &Mutex<Database>, account_id: &str, path: &str) -> Result<Vec<MailFolder>> {
+    let (client, folders) = load_account(db, account_id).await?;
+    let folder = find_folder(&folders, path).ok_or_else(|| SyncError::FolderNotFound(path.to_string()))?;
+    if let Some(special_use) = &folder.special_use {
+        return Err(SyncError::InvalidFolder(format!("the {} folder cannot be deleted", special_use)));
+    }
+
+    client.delete_folder(path).await?;
+    db.lock().await.delete_folder(account_id, folder.logical_name()).await?;
+    refresh_folders(db, account_id).await
+}
+
+/// Subscribe to or unsubscribe from a folder.
+pub async fn set_subscribed(db: &Mutex<Database>, account_id: &str, path: &str, subscribed: bool) -> Result<Vec<MailFolder>> {
+    let (client, folders) = load_account(db, account_id).await?;
+    find_folder(&folders, path).ok_or_else(|| SyncError::FolderNotFound(path.to_string()))?;
+
+    client.set_subscribed(path, subscribed).await?;
+    refresh_folders(db, account_id).await
+}
+
+/// The email `id` with a client for its account and its UID, when it lives on a server.
+async fn locate(db: &Mutex<Database>, id: &str) -> Result<(Email, Option<(EmailClient, u32)>)> {
+    let db = db.lock().await;
+    let email = db
+        .get_email(id)
+        .await?
+        .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", id)))?;
+    let account = match email.account_id.as_deref() {
+        Some(account_id) => db.get_account(account_id).await?,
+        None => None,
+    };
+    let remote = match (account, email.imap_uid()) {
+        (Some(account), Some(uid)) => Some((client_for(&db, account).await?, uid)),
+        _ => None,
+    };
+    Ok((email, remote))
+}
+
+/// Download the body of an email that was synced without it.
+///
+/// With `with_attachments`, the whole message is downloaded so attachment
+/// contents and the raw message are stored too.
+pub async fn load_body(db: &Mutex<Database>, id: &str, with_attachments: bool) -> Result<Email> {
+    let (email, remote) = locate(db, id).await?;
+    let missing_files = email.attachments.iter().flatten().any(|a| a.blob.is_none());
+    let (client, uid) = match remote {
+        Some(remote) if !email.body_loaded || (with_attachments && missing_files) => remote,
+        _ => return Ok(email),
+    };
+
+    if with_attachments {
+        let full = client.fetch_message(&email.folder, uid).await?;
+        db.lock().await.set_email_content(id, &full).await?;
+    } else {
+        let sections = email.body_parts.iter().flatten().map(|p| p.section.clone()).collect();
+        let parts = client.fetch_parts(&email.folder, uid, sections).await?;
+        let text_of = |mime_type: &str| {
+            let section = email.body_parts.iter().flatten().find(|p| p.mime_type == mime_type)?;
+            parts.iter().find(|p| p.section == section.section)?.text.clone()
+        };
+        let body = text_of("text/plain").unwrap_or_default();
+        let html_body = text_of("text/html");
+        db.lock().await.set_email_body(id, &body, html_body.as_deref()).await?;
+    }
+
+    let db = db.lock().await;
+    Ok(db.get_email(id).await?.unwrap_or(email))
+}
+
+/// An attachment with its content, downloading it first if needed.
+pub async fn load_attachment(db: &Mutex<Database>, email_id: &str, attachment_id: &str) -> Result<Option<Attachment>> {
+    let attachment = match db.lock().await.get_attachment(email_id, attachment_id).await? {
+        Some(attachment) if attachment.data.is_none() => attachment,
+        other => return Ok(other),
+    };
+
+    let (email, remote) = locate(db, email_id).await?;
+    let (client, uid) = match remote {
+        Some(remote) => remote,
+        None => return Ok(Some(attachment)),
+    };
+    let part = client
+        .fetch_parts(&email.folder, uid, vec![attachment_id.to_string()])
+        .await?
+        .pop();
+    if let Some(part) = part {
+        db.lock().await.set_attachment_data(email_id, attachment_id, &part.data).await?;
+    }
+
+    let db = db.lock().await;
+    Ok(db.get_attachment(email_id, attachment_id).await?)
+}
+
+/// Mirror the current revision of draft `id` to the server Drafts folder.
+///
+/// The new revision is appended with `\Draft`, the previous one deleted, and
+/// the local copy re-keyed by the UID of the new one when the server tells it.
+pub async fn upload_draft(db: &Mutex<Database>, id: &str) -> Result<Email> {
+    let (draft, client, message) = {
+        let db = db.lock().await;
+        let (draft, client) = load_local(&db, id).await?;
+        let mut message = draft.to_new_email();
+        for attachment in message.attachments.iter_mut().flatten() {
+            attachment.data = db.get_attachment(id, &attachment.id).await?.and_then(|a| a.data);
+        }
+        (draft, client, message)
+    };
+
+    let built = build_message(&message)?;
+    let uid = client
+        .append_message("drafts", built.formatted(), vec![Flag::Draft, Flag::Seen], email::message_id(&built))
+        .await?;
+
+    // A failure here leaves an extra revision on the server, which is better
+    // than appending the new one again on retry.
+    if let Some(previous) = draft.imap_uid() {
+        match client.delete_email("drafts", previous).await {
+            Ok(()) | Err(EmailError::MessageNotFound(_)) => {}
+            Err(e) => log::warn!("Could not delete previous revision of draft {}: {}", id, e),
+        }
+    }
+
+    let db = db.lock().await;
+    let uid = match uid {
+        Some(uid) => uid,
+        None => return Ok(draft),
+    };
+    let new_id = adopt_uid(&db, &draft, "drafts", uid).await?;
+    Ok(db.get_email(&new_id).await?.unwrap_or(draft))
+}
+
+/// Queue storing the sent email `email` in the server Sent folder, unless
+/// the provider files it there by itself.
+pub async fn queue_sent_copy(db: &Database, client: &EmailClient, email: &Email) -> Result<()> {
+    let account_id = match &email.account_id {
+        Some(account_id) if !client.saves_sent_copies() => account_id.clone(),
+        _ => return Ok(()),
+    };
+    let mut op = PendingOperation::new(account_id, PendingAction::AppendSent);
+    op.email_id = Some(email.id.clone());
+    db.enqueue_operation(&op).await?;
+    Ok(())
+}
+
+/// `APPEND` the raw message of sent email `id` to the server Sent folder.
+///
+/// The local copy then takes the UID of the server one, so that syncing Sent
+/// does not bring the message in twice.
+pub async fn append_sent(db: &Mutex<Database>, id: &str) -> Result<()> {
+    let (email, client, raw) = {
+        let db = db.lock().await;
+        if db.get_email(id).await?.is_none() {
+            // Deleted locally in the meantime.
+            return Ok(());
+        }
+        let (email, client) = load_local(&db, id).await?;
+        let raw = db
+            .get_raw_message(id)
+            .await?
+            .ok_or_else(|| DatabaseError::NotFound(format!("Raw message of {} not found", id)))?;
+        (email, client, raw)
+    };
+
+    let uid = client
+        .append_message("sent", raw, vec![Flag::Seen], email.message_id.clone())
+        .await?;
+
+    if let Some(uid) = uid {
+        let db = db.lock().await;
+        adopt_uid(&db, &email, "sent", uid).await?;
+    }
+    Ok(())
+}
+
+/// A locally created email and a client for the account it belongs to.
+async fn load_local(db: &Database, id: &str) -> Result<(Email, EmailClient)> {
+    let email = db
+        .get_email(id)
+        .await?
+        .ok_or_else(|| DatabaseError::NotFound(format!("Email {} not found", id)))?;
+    let account_id = email
+        .account_id
+        .clone()
+        .ok_or_else(|| SyncError::AccountNotFound(format!("none for email {}", id)))?;
+    let account = db
+        .get_account(&account_id)
+        .await?
+        .ok_or(SyncError::AccountNotFound(account_id))?;
+    let client = client_for(db, account).await?;
+    Ok((email, client))
+}
+
+/// Re-key the local `email` by the UID its server copy got in `folder`.
+///
+/// Keeps the local copy under its current id when another message already
+/// holds the new id, as it is then not the copy that was just stored.
+async fn adopt_uid(db: &Database, email: &Email, folder: &str, server_uid: ServerUid) -> Result<String> {
+    let account_id = email.account_id.as_deref().unwrap_or_default();
+    let new_id = models::imap_email_id(account_id, folder, server_uid.uid_validity, server_uid.uid);
+    match db.get_email(&new_id).await? {
+        Some(existing) if existing.folder == folder && existing.message_id.is_some() && existing.message_id == email.message_id => {
+            // Already brought in by a sync of the folder.
+            db.delete_email(&email.id).await?;
+        }
+        Some(_) => {
+            log::warn!("Not adopting {} for {}: it holds another message", new_id, email.id);
+            return Ok(email.id.clone());
+        }
+        None => db.rekey_email(&email.id, &new_id).await?,
+    }
+    db.retarget_operations(&email.id, &new_id, folder, server_uid.uid).await?;
+    Ok(new_id)
+}
+
+/// Search one account on its server, storing the headers of hits not cached yet.
+///
+/// Searches the folder of an `in:` operator, or else "All Mail" when the
+/// server has one and INBOX otherwise.
+pub async fn search_server(db: &Mutex<Database>, account_id: &str, query: &SearchQuery, limit: usize) -> Result<Vec<Email>> {
+    let (client, folders) = load_account(db, account_id).await?;
+    let folder = match &query.folder {
+        Some(folder) => folder.clone(),
+        None if folders.iter().any(|f| f.special_use.as_deref() == Some("all")) => "all".to_string(),
+        None => "inbox".to_string(),
+    };
+    // `in:` names are lowercased by the parser; match them back to a real folder.
+    let folder = folders
+        .iter()
+        .map(|f| f.logical_name())
+        .find(|name| name.eq_ignore_ascii_case(&folder))
+        .map(str::to_string)
+        .unwrap_or(folder);
+
+    let found = client.search(&folder, &query.to_imap(), limit).await?;
+
+    let db = db.lock().await;
+    let mut emails = Vec::with_capacity(found.len());
+    let mut stored = Vec::new();
+    for mut email in found {
+        match db.get_email(&email.id).await? {
+            // Also when moved locally: the row is the latest state of the hit.
+            Some(local) => emails.push(local),
+            None => {
+                db.link_users(&mut email).await?;
+                let email = db.create_email(&email).await?;
+                stored.push(email.clone());
+                emails.push(email);
+            }
+        }
+    }
+    if !stored.is_empty() {
+        threading::update_threads(&db, &stored).await?;
+    }
+    Ok(emails)
+}
+
+/// Incrementally synchronise one folder of an account into the database.
+///
+/// Returns the emails that were newly stored. The database lock is only held
+/// while reading or writing, never across the IMAP round-trips.
+pub async fn sync_folder(db: &Mutex<Database>, account_id: &str, folder: &str) -> Result<Vec<Email>> {
+    let (client, folders, state) = {
+        let db = db.lock().await;
+        let account = db
+            .get_account(account_id)
+            .await?
+            .ok_or_else(|| SyncError::AccountNotFound(account_id.to_string()))?;
+        let folders = db.get_folders(account_id).await?;
+        let state = db.get_sync_state(account_id, folder).await?;
+        (EmailClient::new(account, db.secrets()).with_pool(db.sessions()), folders, state)
+    };
+
+    let folders = if folders.is_empty() {
+        refresh_folders(db, account_id).await?
+    } else {
+        folders
+    };
+    let client = client.with_folders(folders);
+    let fetched = client.fetch_new_emails(folder, state, SYNC_BATCH_SIZE).await?;
+
+    let db = db.lock().await;
+    if fetched.uid_validity_changed {
+        log::info!("UIDVALIDITY changed for account={} folder={}, resyncing", account_id, folder);
+        db.delete_folder_emails(account_id, folder).await?;
+    }
+
+    // Local changes still waiting in the outbox win over the server state.
+    let pending: HashSet<String> = db
+        .get_pending_operations()
+        .await?
+        .into_iter()
+        .filter_map(|op| op.email_id)
+        .collect();
+    let uid_validity = fetched.state.uid_validity;
+    for update in &fetched.flags {
+        let id = models::imap_email_id(account_id, folder, uid_validity, update.uid);
+        if !pending.contains(&id) {
+            db.set_flags(&id, folder, update.read, update.starred).await?;
+        }
+    }
+
+    // Messages expunged or moved away by another client. Moved ones come back
+    // with their new UID when their new folder is synced.
+    let server_uids: HashSet<u32> = fetched.server_uids.iter().copied().collect();
+    let folder_prefix = models::imap_folder_prefix(account_id, folder, uid_validity);
+    for id in db.get_folder_email_ids(account_id, folder).await? {
+        let uid = match id.strip_prefix(&folder_prefix).and_then(|uid| uid.parse::<u32>().ok()) {
+            Some(uid) => uid,
+            None => continue,
+        };
+        if uid <= fetched.state.last_uid && !server_uids.contains(&uid) && !pending.contains(&id) {
+            db.delete_email(&id).await?;
+        }
+    }
+
+    // Ids of this account's server copies; local ones are plain UUIDs.
+    let prefix = format!("{}:", account_id);
+    let mut stored = Vec::with_capacity(fetched.emails.len());
+    for mut email in fetched.emails {
+        if db.get_email(&email.id).await?.is_some() {
+            continue;
+        }
+        // A message sent or saved from here before its server UID was known.
+        if let Some(message_id) = &email.message_id {
+            let local = db
+                .find_by_message_id(account_id, folder, message_id)
+                .await?
+                .into_iter()
+                .find(|id| !id.starts_with(&prefix));
+            if let Some(local) = local {
+                db.delete_operations_for_email(&local).await?;
+                db.rekey_email(&local, &email.id).await?;
+                continue;
+            }
+        }
+        db.link_users(&mut email).await?;
+        db.create_email(&email).await?;
+        stored.push(email);
+    }
+    if !stored.is_empty() {
+        threading::update_threads(&db, &stored).await?;
+    }
+
+    db.save_sync_state(&fetched.state).await?;
+
+    Ok(stored)
+}
@@ -0,0 +1,425 @@
+use crate::database::{self, Database};
+use crate::models::{Email, EmailAddress, Thread, ThreadHeaders};
+use std::collections::{HashMap, HashSet};
+
+/// Prefixes stripped from subjects before comparing them, lowercased.
+const REPLY_PREFIXES: &[&str] = &["re", "fw", "fwd", "aw", "wg", "tr", "sv", "vs", "antw", "rif", "r"];
+
+/// Extract the `<...>` message ids of an `In-Reply-To` or `References` header.
+pub fn parse_message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let id = rest[start + 1..end].trim();
+        if !id.is_empty() {
+            ids.push(format!("<{}>", id));
+        }
+        rest = &rest[end + 1..];
+    }
+    ids
+}
+
+fn normalize_id(id: &str) -> Option<String> {
+    let id = id.trim().trim_start_matches('<').trim_end_matches('>').trim();
+    if id.is_empty() {
+        None
+    } else {
+        Some(id.to_string())
+    }
+}
+
+/// Length of the reply/forward prefix (`Re:`, `Fwd[2]:`...) `subject` starts with.
+fn reply_prefix(subject: &str) -> Option<usize> {
+    let colon = subject.find(':')?;
+    let word = &subject[..colon];
+    let word = word.split('[').next().unwrap_or(word).trim().to_lowercase();
+    REPLY_PREFIXES.contains(&word.as_str()).then_some(colon + 1)
+}
+
+/// Strip reply/forward prefixes and `[list]` tags, returning the base subject
+/// and whether any reply prefix was found.
+pub fn base_subject(subject: &str) -> (String, bool) {
+    let mut rest = subject.trim();
+    let mut is_reply = false;
+    loop {
+        if rest.starts_with('[') {
+            if let Some(end) = rest.find(']') {
+                rest = rest[end + 1..].trim_start();
+                continue;
+            }
+        }
+        match reply_prefix(rest) {
+            Some(len) => {
+                rest = rest[len..].trim_start();
+                is_reply = true;
+            }
+            None => break,
+        }
+    }
+    (rest.to_lowercase(), is_reply)
+}
+
+/// `subject` without its leading reply/forward prefixes, case and list tags kept.
+pub fn strip_reply_prefixes(subject: &str) -> &str {
+    let mut rest = subject.trim();
+    while let Some(len) = reply_prefix(rest) {
+        rest = rest[len..].trim_start();
+    }
+    rest
+}
+
+struct Container {
+    key: String,
+    messages: Vec<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+struct Threader<'a> {
+    messages: &'a [ThreadHeaders],
+    containers: Vec<Container>,
+    by_key: HashMap<String, usize>,
+}
+
+impl<'a> Threader<'a> {
+    fn container(&mut self, key: String) -> usize {
+        if let Some(index) = self.by_key.get(&key) {
+            return *index;
+        }
+        let index = self.containers.len();
+        self.containers.push(Container { key: key.clone(), messages: Vec::new(), parent: None, children: Vec::new() });
+        self.by_key.insert(key, index);
+        index
+    }
+
+    /// Whether `ancestor` is `node` or one of its parents.
+    fn is_ancestor(&self, ancestor: usize, node: usize) -> bool {
+        let mut current = Some(node);
+        while let Some(index) = current {
+            if index == ancestor {
+                return true;
+            }
+            current = self.containers[index].parent;
+        }
+        false
+    }
+
+    fn unlink(&mut self, child: usize) {
+        if let Some(parent) = self.containers[child].parent.take() {
+            self.containers[parent].children.retain(|c| *c != child);
+        }
+    }
+
+    fn link(&mut self, parent: usize, child: usize) {
+        self.unlink(child);
+        self.containers[child].parent = Some(parent);
+        self.containers[parent].children.push(child);
+    }
+
+    fn add(&mut self, index: usize) {
+        let message = &self.messages[index];
+        let own = message
+            .message_id
+            .as_deref()
+            .and_then(normalize_id)
+            .filter(|key| {
+                self.by_key
+                    .get(key)
+                    .is_none_or(|c| self.containers[*c].messages.is_empty() || self.is_duplicate(*c, index))
+            })
+            .unwrap_or_else(|| format!("\0{}", message.id));
+        let own = self.container(own);
+        self.containers[own].messages.push(index);
+
+        let mut refs: Vec<String> = message
+            .references
+            .iter()
+            .flatten()
+            .filter_map(|id| normalize_id(id))
+            .collect();
+        if let Some(in_reply_to) = message.in_reply_to.as_deref().and_then(normalize_id) {
+            if refs.last() != Some(&in_reply_to) {
+                refs.push(in_reply_to);
+            }
+        }
+
+        // Chain the references together, without overriding links already known.
+        let mut previous: Option<usize> = None;
+        for key in refs {
+            let current = self.container(key);
+            if let Some(parent) = previous {
+                if self.containers[current].parent.is_none() && !self.is_ancestor(current, parent) {
+                    self.link(parent, current);
+                }
+            }
+            previous = Some(current);
+        }
+
+        // The message's own parent is always the last reference.
+        match previous {
+            Some(parent) if !self.is_ancestor(own, parent) => self.link(parent, own),
+            Some(_) => {}
+            None => self.unlink(own),
+        }
+    }
+
+    /// The same message stored twice (e.g. in Sent and All Mail) shares a container.
+    fn is_duplicate(&self, container: usize, index: usize) -> bool {
+        let first = &self.messages[self.containers[container].messages[0]];
+        let message = &self.messages[index];
+        first.subject == message.subject && first.date == message.date
+    }
+
+    fn subtree(&self, root: usize) -> Vec<usize> {
+        let mut messages = Vec::new();
+        let mut stack = vec![root];
+        while let Some(index) = stack.pop() {
+            messages.extend(&self.containers[index].messages);
+            stack.extend(&self.containers[index].children);
+        }
+        messages
+    }
+
+    fn thread_id(&self, root: usize) -> String {
+        let key = &self.containers[root].key;
+        match key.strip_prefix('\0') {
+            Some(email_id) => email_id.to_string(),
+            None => format!("<{}>", key),
+        }
+    }
+}
+
+/// Group messages into conversations, JWZ style.
+///
+/// Messages are linked through `References` and `In-Reply-To`; threads whose
+/// root is missing or unrelated are then joined by subject, but only when one
+/// side is a reply, so unrelated mails that happen to share a subject stay
+/// apart. Returns the thread id of every message, keyed by email id.
+pub fn thread_messages(messages: &[ThreadHeaders]) -> HashMap<String, String> {
+    let mut threader = Threader { messages, containers: Vec::new(), by_key: HashMap::new() };
+    for index in 0..messages.len() {
+        threader.add(index);
+    }
+
+    let roots: Vec<usize> = (0..threader.containers.len())
+        .filter(|index| threader.containers[*index].parent.is_none())
+        .filter(|index| !threader.subtree(*index).is_empty())
+        .collect();
+
+    // Pick, for each base subject, the root the others are merged into:
+    // an original message over a reply, then the oldest.
+    let mut by_subject: HashMap<String, (usize, bool, &str)> = HashMap::new();
+    let mut root_subjects = Vec::with_capacity(roots.len());
+    for root in &roots {
+        let first = threader
+            .subtree(*root)
+            .into_iter()
+            .min_by(|a, b| messages[*a].date.cmp(&messages[*b].date))
+            .unwrap();
+        // A root that was never received still heads a real conversation.
+        let (subject, is_reply) = base_subject(&messages[first].subject);
+        let is_reply = is_reply && !threader.containers[*root].messages.is_empty();
+        let date = messages[first].date.as_str();
+        if !subject.is_empty() {
+            let best = by_subject.entry(subject.clone()).or_insert((*root, is_reply, date));
+            if (is_reply, date) < (best.1, best.2) {
+                *best = (*root, is_reply, date);
+            }
+        }
+        root_subjects.push((subject, is_reply));
+    }
+
+    let mut threads = HashMap::new();
+    for (root, (subject, is_reply)) in roots.iter().zip(root_subjects) {
+        let target = match by_subject.get(&subject) {
+            Some((best, _, _)) if is_reply && !subject.is_empty() => *best,
+            _ => *root,
+        };
+        let thread_id = threader.thread_id(target);
+        for message in threader.subtree(*root) {
+            threads.insert(messages[message].id.clone(), thread_id.clone());
+        }
+    }
+    threads
+}
+
+/// Thread newly stored `emails` into the conversations of their account and
+/// save the thread ids that changed.
+///
+/// Only the conversations a new message may join are loaded: those sharing
+/// a message id with it, or its base subject.
+pub async fn update_threads(db: &Database, emails: &[Email]) -> database::Result<()> {
+    let mut by_account: HashMap<Option<&str>, Vec<&Email>> = HashMap::new();
+    for email in emails {
+        by_account.entry(email.account_id.as_deref()).or_default().push(email);
+    }
+
+    for (account_id, emails) in by_account {
+        let mut ids = Vec::new();
+        let mut message_ids = HashSet::new();
+        let mut subjects = HashSet::new();
+        for email in emails {
+            ids.push(email.id.clone());
+            message_ids.extend(
+                email
+                    .message_id
+                    .iter()
+                    .chain(&email.in_reply_to)
+                    .chain(email.references.iter().flatten())
+                    .cloned(),
+            );
+            let (subject, _) = base_subject(&email.subject);
+            if !subject.is_empty() {
+                subjects.insert(subject);
+            }
+        }
+        let headers = db
+            .get_related_thread_headers(
+                account_id,
+                ids,
+                message_ids.into_iter().collect(),
+                subjects.into_iter().collect(),
+            )
+            .await?;
+        save_threads(db, account_id, &headers).await?;
+    }
+    Ok(())
+}
+
+/// Thread `headers`, all of `account_id`, and save the thread ids that changed.
+pub async fn save_threads(db: &Database, account_id: Option<&str>, headers: &[ThreadHeaders]) -> database::Result<()> {
+    let threads = thread_messages(headers);
+    for message in headers {
+        if let Some(thread_id) = threads.get(&message.id) {
+            let thread_id = scoped_thread_id(account_id, thread_id);
+            if message.thread_id.as_ref() != Some(&thread_id) {
+                db.set_thread_id(&message.id, &thread_id).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Prefix a `<message-id>` thread id with its account, so that the same
+/// conversation in two accounts stays two threads. Thread ids taken from an
+/// email id already start with the account.
+fn scoped_thread_id(account_id: Option<&str>, thread_id: &str) -> String {
+    match account_id {
+        Some(account_id) if thread_id.starts_with('<') => format!("{}:{}", account_id, thread_id),
+        _ => thread_id.to_string(),
+    }
+}
+
+/// Build the conversation list from the messages of some threads.
+pub fn summarize(emails: Vec<Email>) -> Vec<Thread> {
+    let mut groups: HashMap<String, Vec<Email>> = HashMap::new();
+    for email in emails {
+        let thread_id = email.thread_id.clone().unwrap_or_else(|| email.id.clone());
+        groups.entry(thread_id).or_default().push(email);
+    }
+
+    let mut threads: Vec<Thread> = groups
+        .into_iter()
+        .map(|(id, mut emails)| {
+            emails.sort_by(|a, b| a.date.cmp(&b.date));
+
+            let mut seen = HashSet::new();
+            let mut participants: Vec<EmailAddress> = Vec::new();
+            for email in &emails {
+                let addresses = std::iter::once(&email.from)
+                    .chain(&email.to)
+                    .chain(email.cc.iter().flatten());
+                for address in addresses {
+                    if !address.email.is_empty() && seen.insert(address.email.to_lowercase()) {
+                        participants.push(address.clone());
+                    }
+                }
+            }
+
+            Thread {
+                id,
+                subject: emails[0].subject.clone(),
+                message_count: emails.len(),
+                unread_count: emails.iter().filter(|email| !email.read).count(),
+                starred: emails.iter().any(|email| email.starred),
+                participants,
+                latest_date: emails.last().map(|email| email.date.clone()).unwrap_or_default(),
+                email_ids: emails.into_iter().map(|email| email.id).collect(),
+            }
+        })
+        .collect();
+
+    threads.sort_by(|a, b| b.latest_date.cmp(&a.latest_date));
+    threads
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(id: &str, message_id: &str, references: &[&str], subject: &str, date: &str) -> ThreadHeaders {
+        ThreadHeaders {
+            id: id.to_string(),
+            account_id: None,
+            message_id: Some(format!("<{}>", message_id)),
+            in_reply_to: references.last().map(|r| format!("<{}>", r)),
+            references: Some(references.iter().map(|r| format!("<{}>", r)).collect()),
+            subject: subject.to_string(),
+            date: date.to_string(),
+            thread_id: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_message_ids() {
+        assert_eq!(
+            parse_message_ids("<a@x> \r\n <b@y>garbage<c@z>"),
+            vec!["<a@x>".to_string(), "<b@y>".to_string(), "<c@z>".to_string()]
+        );
+        assert!(parse_message_ids("no ids here").is_empty());
+    }
+
+    #[test]
+    fn test_base_subject() {
+        assert_eq!(base_subject("Re: Fwd: [dev] RE: Lunch"), ("lunch".to_string(), true));
+        assert_eq!(base_subject("Lunch"), ("lunch".to_string(), false));
+        assert_eq!(base_subject("Meeting: 10am"), ("meeting: 10am".to_string(), false));
+        assert_eq!(strip_reply_prefixes("RE: Fwd[2]: [dev] Lunch"), "[dev] Lunch");
+    }
+
+    #[test]
+    fn test_thread_messages() {
+        let messages = vec![
+            // A reply arriving before its parent, whose root is missing locally.
+            headers("2", "b@x", &["a@x"], "Re: Plans", "2024-01-02"),
+            headers("3", "c@x", &["a@x", "b@x"], "Re: Plans", "2024-01-03"),
+            // References dropped by a broken client: joined by subject.
+            headers("4", "d@x", &[], "Re: Plans", "2024-01-04"),
+            // Same subject, but not a reply: a separate conversation.
+            headers("5", "e@x", &[], "Plans", "2024-02-01"),
+            headers("6", "f@x", &[], "Hello", "2024-01-01"),
+        ];
+
+        let threads = thread_messages(&messages);
+        assert_eq!(threads["2"], "<a@x>");
+        assert_eq!(threads["3"], "<a@x>");
+        assert_eq!(threads["4"], "<a@x>");
+        assert_eq!(threads["5"], "<e@x>");
+        assert_eq!(threads["6"], "<f@x>");
+    }
+
+    #[test]
+    fn test_reference_loop() {
+        let messages = vec![
+            headers("1", "a@x", &["b@x"], "Loop", "2024-01-01"),
+            headers("2", "b@x", &["a@x"], "Re: Loop", "2024-01-02"),
+        ];
+
+        let threads = thread_messages(&messages);
+        assert_eq!(threads["1"], threads["2"]);
+    }
+}
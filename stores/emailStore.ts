@@ -1,6 +1,7 @@
 import { defineStore } from 'pinia'
 import { ref, computed } from 'vue'
 import { invoke } from '@tauri-apps/api/core'
+import { listen } from '@tauri-apps/api/event'
 
 export interface EmailAddress {
   name: string
@@ -12,6 +13,9 @@ export interface Attachment {
   filename: string
   size: number
   mimeType: string
+  contentId?: string
+  inline?: boolean
+  data?: number[]
 }
 
 export interface Email {
@@ -29,6 +33,93 @@ export interface Email {
   selected: boolean
   folder: string
   attachments?: Attachment[]
+  account_id?: string
+  inReplyTo?: string
+  references?: string[]
+  threadId?: string
+  bodyLoaded?: boolean
+  size?: number
+}
+
+export interface NewEmail {
+  account_id?: string
+  from: EmailAddress
+  to: EmailAddress[]
+  cc?: EmailAddress[]
+  bcc?: EmailAddress[]
+  subject: string
+  body: string
+  htmlBody?: string
+  attachments?: Attachment[]
+  inReplyTo?: string
+  references?: string[]
+}
+
+export interface Thread {
+  id: string
+  subject: string
+  messageCount: number
+  unreadCount: number
+  starred: boolean
+  participants: EmailAddress[]
+  latestDate: string
+  emailIds: string[]
+}
+
+export interface EmailListItem {
+  id: string
+  from: EmailAddress
+  to: EmailAddress[]
+  subject: string
+  preview: string
+  date: string
+  read: boolean
+  starred: boolean
+  folder: string
+  hasAttachments: boolean
+  account_id?: string
+  threadId?: string
+  size?: number
+}
+
+export interface EmailListQuery {
+  folder: string
+  limit?: number
+  cursor?: string
+  sort?: 'date' | 'sender' | 'subject' | 'size'
+  ascending?: boolean
+  unread?: boolean
+  starred?: boolean
+  hasAttachments?: boolean
+}
+
+export interface EmailPage {
+  items: EmailListItem[]
+  nextCursor?: string
+}
+
+export interface SearchHit {
+  email: Email
+  score: number
+  snippet?: string
+}
+
+export interface MailFolder {
+  accountId: string
+  path: string
+  name: string
+  delimiter?: string
+  parent?: string
+  specialUse?: string
+  selectable: boolean
+  subscribed: boolean
+}
+
+export interface NewMailEvent {
+  accountId: string
+  folder: string
+  count: number
+  ids: string[]
 }
 
 export interface EmailAccount {
@@ -40,24 +131,72 @@ export interface EmailAccount {
   smtpServer: string
   smtpPort: number
   username: string
-  password: string
-  useSsl: boolean
+  // Write-only: the backend keeps it in its secret store and never returns it.
+  password?: string
+  imapSecurity: Security
+  smtpSecurity: Security
   allowInvalidCerts: boolean
   allowInvalidSmtpCerts: boolean
+  // Pinned server certificates (SHA-256), trusted without a valid chain.
+  imapFingerprint?: string | null
+  smtpFingerprint?: string | null
   userId?: string | null
+  signature?: string
+  identities?: Identity[]
+  auth?: AuthMethod
 }
 
+export type Security = 'tls' | 'startTls' | 'none'
+
+// The usual security of a server listening on `port`: implicit TLS only on
+// IMAPS (993) and SMTPS (465).
+export function securityForPort(protocol: 'imap' | 'smtp', port: number): Security {
+  return port === (protocol === 'imap' ? 993 : 465) ? 'tls' : 'startTls'
+}
+
+export type AuthMethod = { type: 'password' } | ({ type: 'oauth2' } & OAuth2Config)
+
+export interface OAuth2Config {
+  authUrl: string
+  tokenUrl: string
+  clientId: string
+  clientSecret?: string
+  scopes: string[]
+  mechanism?: 'XOAUTH2' | 'OAUTHBEARER'
+}
+
+export interface Identity {
+  email: string
+  name: string
+  signature?: string
+}
+
+export interface Sender extends Identity {
+  accountId: string
+}
+
+const PAGE_SIZE = 50
+
 export const useEmailStore = defineStore('email', () => {
   const emails = ref<Email[]>([])
+  const nextCursor = ref<string | undefined>()
   const currentEmail = ref<Email | null>(null)
   const currentFolder = ref('inbox')
   const isLoading = ref(false)
   const error = ref<string | null>(null)
   const isSyncing = ref(false)
-  const syncStatus = ref<{ type: 'success' | 'error'; message: string } | null>(null)
+  const syncStatus = ref<{ type: 'success' | 'warning' | 'error'; message: string } | null>(null)
   const searchQuery = ref('')
+  const searchResults = ref<SearchHit[]>([])
   const accounts = ref<EmailAccount[]>([])
+  // Every address mail can be sent as: each account, then its aliases.
+  const senders = computed<Sender[]>(() => accounts.value.flatMap(account => [
+    { accountId: account.id, email: account.email, name: account.name, signature: account.signature },
+    ...(account.identities ?? []).map(identity => ({ ...identity, accountId: account.id }))
+  ]))
   const currentAccount = ref<EmailAccount | null>(null)
+  const folders = ref<MailFolder[]>([])
+  const threads = ref<Thread[]>([])
 
   let hasSeenAnyEmail = false
 
@@ -117,19 +256,54 @@ export const useEmailStore = defineStore('email', () => {
     emails.value.filter(e => e.selected)
   )
 
+  const hasMoreEmails = computed(() => nextCursor.value !== undefined)
+
   // Actions
+
+  // Load the message list of the current folder page by page, without bodies.
+  // A reload keeps as many messages as were shown, and the bodies already loaded.
+  async function loadEmails(more = false) {
+    if (more && !nextCursor.value) return
+    const shown = emails.value.filter(e => e.folder === currentFolder.value).length
+    const page = await invoke<EmailPage>('list_emails', {
+      query: {
+        folder: currentFolder.value,
+        cursor: more ? nextCursor.value : undefined,
+        limit: more ? PAGE_SIZE : Math.max(shown, PAGE_SIZE)
+      }
+    })
+    const known = new Map(emails.value.map(e => [e.id, e]))
+    const items: Email[] = page.items.map(item => {
+      const previous = known.get(item.id)
+      return {
+        body: item.preview,
+        bodyLoaded: false,
+        ...(previous?.bodyLoaded ? previous : {}),
+        ...item,
+        selected: previous?.selected ?? false
+      }
+    })
+    emails.value = more ? [...emails.value, ...items] : items
+    nextCursor.value = page.nextCursor
+    markSeenIfAny(emails.value)
+  }
+
+  async function loadMoreEmails() {
+    try {
+      await loadEmails(true)
+    } catch (e) {
+      error.value = String(e)
+    }
+  }
+
   async function fetchEmails(forceSync = false, skipAutoSync = false) {
     isLoading.value = true
     error.value = null
 
     try {
-      const result = await invoke<Email[]>('fetch_emails', {
-        folder: currentFolder.value
-      })
-      emails.value = result.map(e => ({ ...e, selected: false }))
-      markSeenIfAny(emails.value)
+      await loadEmails()
 
-      if (!skipAutoSync && (forceSync || result.length === 0)) {
+      if (!skipAutoSync && (forceSync || emails.value.length === 0)) {
         await syncEmails()
       }
     } catch (e) {
@@ -218,23 +392,23 @@ export const useEmailStore = defineStore('email', () => {
         accountId: account.id,
         folder: currentFolder.value
       })
-      if (synced && synced.length > 0) {
-        const mapped = synced.map(e => ({ ...e, selected: false }))
-        const newItems = mapped.filter(e => !existingIds.has(e.id))
-        if (newItems.length > 0 && existingIds.size > 0) {
-          playNotificationSound()
-        }
-        emails.value = mapped
-        markSeenIfAny(emails.value)
-        return { total: mapped.length, newCount: newItems.length }
+      const newItems = synced.filter(e => !existingIds.has(e.id))
+      if (newItems.length > 0 && existingIds.size > 0) {
+        playNotificationSound()
       }
-      return { total: 0, newCount: 0 }
+      await loadEmails()
+      return { total: synced.length, newCount: newItems.length }
     } catch (e) {
       error.value = String(e)
       throw e
     }
   }
 
+  // Raised by the backend when a pinned server certificate was replaced.
+  function certificateChanged(e: unknown) {
+    return String(e).includes('Certificate changed for')
+  }
+
   async function refreshEmails() {
     syncStatus.value = null
     isSyncing.value = true
@@ -266,9 +440,7 @@ export const useEmailStore = defineStore('email', () => {
         totalNew += newItems.length
       }
 
-      const result = await invoke<Email[]>('fetch_emails', { folder: currentFolder.value })
-      emails.value = result.map(e => ({ ...e, selected: false }))
-      markSeenIfAny(emails.value)
+      await loadEmails()
 
       syncStatus.value = {
         type: 'success',
@@ -276,10 +448,15 @@ export const useEmailStore = defineStore('email', () => {
       }
     } catch (e) {
       error.value = String(e)
-      syncStatus.value = {
-        type: 'error',
-        message: `Échec de la synchronisation : ${String(e)}`
-      }
+      syncStatus.value = certificateChanged(e)
+        ? {
+            type: 'warning',
+            message: `Le certificat d'un serveur a changé, la connexion est refusée. Testez le compte dans les paramètres pour vérifier et accepter le nouveau certificat.\n${String(e)}`
+          }
+        : {
+            type: 'error',
+            message: `Échec de la synchronisation : ${String(e)}`
+          }
     } finally {
       isSyncing.value = false
     }
@@ -290,17 +467,8 @@ export const useEmailStore = defineStore('email', () => {
     error.value = null
 
     try {
-      await invoke('send_email', { email })
-      const sentEmail: Email = {
-        ...email,
-        id: Date.now().toString(),
-        date: new Date().toISOString(),
-        read: true,
-        starred: false,
-        selected: false,
-        folder: 'sent'
-      }
-      emails.value.push(sentEmail)
+      const sent = await invoke<Email>('send_email', { email })
+      emails.value.push({ ...sent, selected: false })
     } catch (e) {
       error.value = String(e)
       throw e
@@ -309,25 +477,57 @@ export const useEmailStore = defineStore('email', () => {
     }
   }
 
-  async function saveDraft(email: Partial<Email>) {
-    const draft: Email = {
-      id: email.id || Date.now().toString(),
-      from: email.from || { name: '', email: '' },
-      to: email.to || [],
-      subject: email.subject || '',
-      body: email.body || '',
-      date: new Date().toISOString(),
-      read: true,
-      starred: false,
-      selected: false,
-      folder: 'drafts'
-    }
-
-    const existingIndex = emails.value.findIndex(e => e.id === draft.id)
+  function storeDraft(draft: Email, replacing?: string) {
+    const stored = { ...draft, selected: false }
+    const existingIndex = emails.value.findIndex(e => e.id === (replacing ?? draft.id))
     if (existingIndex >= 0) {
-      emails.value[existingIndex] = draft
+      emails.value[existingIndex] = stored
     } else {
-      emails.value.push(draft)
+      emails.value.push(stored)
+    }
+    return stored
+  }
+
+  // Drafts are stored by the backend and mirrored to the server Drafts folder.
+  async function saveDraft(draft: NewEmail, id?: string) {
+    const saved = id
+      ? await invoke<Email>('update_draft', { id, draft })
+      : await invoke<Email>('save_draft', { draft })
+    return storeDraft(saved, id)
+  }
+
+  async function discardDraft(id: string) {
+    await invoke('discard_draft', { id })
+    emails.value = emails.value.filter(e => e.id !== id)
+  }
+
+  async function sendDraft(id: string) {
+    isLoading.value = true
+    error.value = null
+
+    try {
+      const sent = await invoke<Email>('send_draft', { id })
+      emails.value = emails.value.filter(e => e.id !== id)
+      emails.value.push({ ...sent, selected: false })
+      return sent
+    } catch (e) {
+      error.value = String(e)
+      throw e
+    } finally {
+      isLoading.value = false
+    }
+  }
+
+  async function loadEmailBody(id: string, attachments = false) {
+    try {
+      const loaded = await invoke<Email>('load_email_body', { id, attachments })
+      const index = emails.value.findIndex(e => e.id === id)
+      if (index >= 0) {
+        emails.value[index] = { ...emails.value[index], ...loaded }
+      }
+      return loaded
+    } catch (e) {
+      console.error('Failed to load email body:', e)
     }
   }
 
@@ -408,8 +608,17 @@ export const useEmailStore = defineStore('email', () => {
     currentEmail.value = email
   }
 
-  function searchEmails(query: string) {
+  async function searchEmails(query: string, server = false) {
     searchQuery.value = query
+    if (!query.trim()) {
+      searchResults.value = []
+      return
+    }
+    try {
+      searchResults.value = await invoke<SearchHit[]>('search_emails', { query, server })
+    } catch (e) {
+      console.error('Search failed:', e)
+    }
   }
 
   function getEmailById(id: string): Email | undefined {
@@ -431,6 +640,7 @@ export const useEmailStore = defineStore('email', () => {
         currentAccount.value = matched
       }
       await refreshEmails()
+      return saved
     } catch (e) {
       console.error('Failed to save account:', e)
       throw e
@@ -446,12 +656,24 @@ export const useEmailStore = defineStore('email', () => {
       if (matched) {
         currentAccount.value = matched
       }
+      return saved
     } catch (e) {
       console.error('Failed to update account:', e)
       throw e
     }
   }
 
+  // Sign an OAuth2 account in through the browser.
+  async function authorizeAccount(id: string) {
+    try {
+      await invoke('oauth2_authorize', { accountId: id })
+      await refreshEmails()
+    } catch (e) {
+      console.error('Failed to authorize account:', e)
+      throw e
+    }
+  }
+
   async function removeAccount(id: string) {
     accounts.value = accounts.value.filter(a => a.id !== id)
 
@@ -467,6 +689,64 @@ export const useEmailStore = defineStore('email', () => {
     }
   }
 
+  async function loadThreads(folder: string) {
+    try {
+      threads.value = await invoke<Thread[]>('get_threads', { folder })
+    } catch (e) {
+      console.error('Failed to load threads:', e)
+    }
+  }
+
+  async function composeReply(emailId: string, mode: 'reply' | 'replyAll' = 'reply') {
+    return invoke<NewEmail>('compose_reply', { emailId, mode })
+  }
+
+  async function composeForward(emailId: string) {
+    return invoke<NewEmail>('compose_forward', { emailId })
+  }
+
+  async function listEmails(query: EmailListQuery) {
+    return invoke<EmailPage>('list_emails', { query })
+  }
+
+  async function getAttachment(emailId: string, attachmentId: string) {
+    return invoke<Attachment>('get_attachment', { emailId, attachmentId })
+  }
+
+  async function saveAttachment(emailId: string, attachmentId: string) {
+    return invoke<string>('save_attachment', { emailId, attachmentId })
+  }
+
+  async function loadFolders(refresh = false) {
+    const account = currentAccount.value || accounts.value[0]
+    if (!account) return
+    try {
+      folders.value = await invoke<MailFolder[]>('list_folders', { accountId: account.id, refresh })
+    } catch (e) {
+      console.error('Failed to load folders:', e)
+    }
+  }
+
+  async function manageFolder(command: string, args: Record<string, unknown>) {
+    const account = currentAccount.value || accounts.value[0]
+    if (!account) return
+    try {
+      folders.value = await invoke<MailFolder[]>(command, { accountId: account.id, ...args })
+    } catch (e) {
+      console.error(`Failed to ${command.replace('_', ' ')}:`, e)
+      throw e
+    }
+  }
+
+  const createFolder = (name: string, parent?: string) =>
+    manageFolder('create_folder', { name, parent: parent ?? null })
+  const renameFolder = (path: string, newName: string) =>
+    manageFolder('rename_folder', { path, newName })
+  const deleteFolder = (path: string) =>
+    manageFolder('delete_folder', { path })
+  const subscribeFolder = (path: string, subscribed: boolean) =>
+    manageFolder('subscribe_folder', { path, subscribed })
+
   function setCurrentAccount(account: EmailAccount | null) {
     currentAccount.value = account
   }
@@ -486,8 +766,25 @@ export const useEmailStore = defineStore('email', () => {
     }
   }
 
+  // Push notifications from the backend IDLE watchers
+  async function listenForNewMail() {
+    try {
+      await listen<NewMailEvent>('mail://new', async (event) => {
+        if (event.payload.count > 0 && hasSeenAnyEmail) {
+          playNotificationSound()
+        }
+        if (event.payload.folder === currentFolder.value && !isSyncing.value) {
+          await loadEmails()
+        }
+      })
+    } catch (e) {
+      console.error('Failed to listen for new mail:', e)
+    }
+  }
+
   // Initialize
   loadAccounts()
+  listenForNewMail()
 
   return {
     // State
@@ -499,8 +796,10 @@ export const useEmailStore = defineStore('email', () => {
     isSyncing,
     syncStatus,
     searchQuery,
+    searchResults,
     accounts,
     currentAccount,
+    folders,
 
     // Computed
     filteredEmails,
@@ -510,11 +809,16 @@ export const useEmailStore = defineStore('email', () => {
 
     // Actions
     fetchEmails,
+    loadMoreEmails,
+    hasMoreEmails,
     syncEmails,
     refreshEmails,
     sendEmail,
     saveDraft,
+    discardDraft,
+    sendDraft,
     markAsRead,
+    loadEmailBody,
     markAsUnread,
     toggleStar,
     toggleSelect,
@@ -528,8 +832,23 @@ export const useEmailStore = defineStore('email', () => {
     searchEmails,
     getEmailById,
     addAccount,
+    updateAccount,
+    authorizeAccount,
     removeAccount,
     setCurrentAccount,
-    loadAccounts
+    loadAccounts,
+    loadFolders,
+    threads,
+    loadThreads,
+    listEmails,
+    composeReply,
+    composeForward,
+    senders,
+    getAttachment,
+    saveAttachment,
+    createFolder,
+    renameFolder,
+    deleteFolder,
+    subscribeFolder
   }
 })